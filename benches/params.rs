@@ -0,0 +1,75 @@
+// Compares the throughput of queries with small `Copy` params against queries
+// with heap-allocated params. Run with `cargo bench --bench params`.
+
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use inqui::{QueryCache, Runtime};
+
+const QUERIES: u32 = 10_000;
+const ROUNDS: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Param {
+    Foo(u32),
+    Bar(u32),
+}
+
+struct Compute;
+
+fn bench<K: Hash + Eq + Clone + Debug>(name: &str, make_param: impl Fn(u32) -> K) {
+    let runtime = Runtime::<()>::new();
+    let params = (0..QUERIES).map(make_param).collect::<Vec<_>>();
+
+    let mut insert = Duration::ZERO;
+    let mut cached = Duration::ZERO;
+
+    for _ in 0..ROUNDS {
+        let queries = QueryCache::<K>::new();
+
+        let start = Instant::now();
+        for param in params.iter() {
+            black_box(
+                queries.insert_with::<Compute, _, _, _>(&runtime, param.clone(), |_, _| 1u32),
+            );
+        }
+        // Recomputation of known queries.
+        for param in params.iter() {
+            black_box(
+                queries.insert_with::<Compute, _, _, _>(&runtime, param.clone(), |_, _| 2u32),
+            );
+        }
+        insert += start.elapsed();
+
+        let start = Instant::now();
+        for param in params.iter() {
+            black_box(queries.cached::<Compute, u32, _>(param, &runtime));
+        }
+        cached += start.elapsed();
+    }
+
+    let per_op = |total: Duration, ops: u32| total.as_nanos() / (ops * ROUNDS) as u128;
+
+    println!(
+        "{:<8} insert: {:>5} ns/op, cached: {:>5} ns/op",
+        name,
+        per_op(insert, 2 * QUERIES),
+        per_op(cached, QUERIES)
+    );
+}
+
+fn main() {
+    bench("u32", |n| n);
+    bench("Param", |n| {
+        if n % 2 == 0 {
+            Param::Foo(n)
+        } else {
+            Param::Bar(n)
+        }
+    });
+    bench("String", |n| format!("src/file{}.rs", n));
+}
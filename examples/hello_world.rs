@@ -21,6 +21,7 @@ use std::sync::Arc;
 #[inqui::database]
 trait HelloWorld {
     fn my_string(&self, key: ()) -> String;
+    #[allow(dead_code)]
     fn optional_string(&self, key: ()) -> Option<String>;
 }
 
@@ -31,8 +32,10 @@ trait HelloWorld {
 // inputs. The implementation uses query context to access the inputs in the
 // storage and track the query's dependencies.
 //
-// Arguably, this implementation should also be auto-generated via a procedural
-// macro.
+// The `#[inqui::database]` attribute also implements the trait directly for
+// `QueryContext`, which is enough when no custom behavior is needed. Together
+// with the `inqui::query_system!` macro, the boilerplate in this example can be
+// generated (see the query_system example).
 
 struct HelloWorldImpl<'r> {
     ctx: &'r inqui::QueryContext<'r, HelloWorldStorage>,
@@ -66,7 +69,8 @@ impl HelloWorld for HelloWorldImpl<'_> {
 // the same for *all* queries that your system will support. This is a
 // limitation, but there is a range how flexible one can be, from using a single
 // fixed type over a "compound" type with many `From<T>` implementations to
-// perhaps a beast based on `TypedId`-based map.
+// perhaps a beast based on `TypedId`-based map. The `#[inqui::param]` attribute
+// generates the `From` implementations for such a compound enum.
 //
 // The query system is also responsible for managing the inputs depending on the
 // application. It can either provide methods for setting the inputs, have a
@@ -0,0 +1,37 @@
+// The hello_world example with the query system generated by the
+// `query_system!` macro instead of written by hand.
+
+#[inqui::database]
+trait HelloWorld {
+    fn my_string(&self, key: ()) -> String;
+}
+
+inqui::query_system! {
+    // The generated system holds the runtime for `HelloWorldStorage` and the
+    // query cache. Every `#[query]` function gets a method of the same name
+    // that returns the cached output.
+    #[database(HelloWorld)]
+    struct System;
+
+    // The first argument is the database, the rest are the query parameters.
+    // The query context implements the database trait, so the inputs read
+    // through it are tracked as the query's dependencies.
+    #[query]
+    fn length(db: &dyn HelloWorld, key: ()) -> usize {
+        db.my_string(key).len()
+    }
+
+    #[query]
+    fn repeated(db: &dyn HelloWorld, key: (), times: usize) -> String {
+        db.my_string(key).repeat(times)
+    }
+}
+
+fn main() {
+    let mut system = System::new();
+
+    system.set_input::<MyStringInput>((), "Hello world!".to_string());
+
+    println!("Now, the length is {}.", system.length(()));
+    println!("Repeated twice: {}", system.repeated((), 2));
+}
@@ -1,17 +1,46 @@
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
-use syn::{parse_macro_input, spanned::Spanned, Ident, ItemTrait, Type};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, parse_quote,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    Attribute, Expr, GenericParam, Ident, ItemTrait, LitStr, Path, Token, Type,
+};
 
+mod param;
+mod query_system;
+
+#[proc_macro]
+pub fn query_system(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as query_system::QuerySystem);
+
+    query_system::query_system(input).unwrap_or_else(|error| error.into_compile_error().into())
+}
+
+// Turns an enum into a query param for the queries of different param types,
+// with a `From` conversion for every variant.
 #[proc_macro_attribute]
-pub fn database(_: TokenStream, item: TokenStream) -> TokenStream {
-    let item = parse_macro_input!(item as ItemTrait);
+pub fn param(_: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as syn::ItemEnum);
 
-    let storage_name = format_ident!("{}Storage", item.ident);
+    param::param(item).unwrap_or_else(|error| error.into_compile_error().into())
+}
+
+#[proc_macro_attribute]
+pub fn database(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let options = parse_macro_input!(attr as DatabaseOptions);
+    let mut item = parse_macro_input!(item as ItemTrait);
+
+    let storage_name = options
+        .storage
+        .clone()
+        .unwrap_or_else(|| format_ident!("{}Storage", item.ident));
 
     let inputs = item
         .items
-        .iter()
+        .iter_mut()
         .filter_map(|item| match item {
             syn::TraitItem::Method(method) => Some(method),
             _ => None,
@@ -44,6 +73,7 @@ pub fn database(_: TokenStream, item: TokenStream) -> TokenStream {
                     (*pat_ty.ty).clone()
                 })
                 .collect::<Vec<_>>();
+            let optional = matches!(method.sig.output, syn::ReturnType::Type(_, ref output_ty) if unwrap_option_type(output_ty).is_some());
             let output = match method.sig.output {
                 syn::ReturnType::Default => {
                     return Err(syn::Error::new(
@@ -57,7 +87,17 @@ pub fn database(_: TokenStream, item: TokenStream) -> TokenStream {
                 unwrap_option_type(output_ty).unwrap_or_else(|| output_ty.clone()),
             };
 
-            Ok(Input { name, ty_name, args, output })
+            // The input options are consumed by the macro, the trait is emitted
+            // without them.
+            let options = take_input_options(&mut method.attrs)?;
+            let docs = method
+                .attrs
+                .iter()
+                .filter(|attr| attr.path.is_ident("doc"))
+                .cloned()
+                .collect();
+
+            Ok(Input { name, ty_name, args, output, optional, options, docs })
         })
         .collect::<Result<Vec<_>, _>>();
 
@@ -66,27 +106,161 @@ pub fn database(_: TokenStream, item: TokenStream) -> TokenStream {
         Err(error) => return error.into_compile_error().into(),
     };
 
+    // The generated types are visible as the trait, so that they can be named
+    // wherever the database is used.
+    let vis = item.vis.clone();
+
+    // Type parameters of the trait are carried by the inputs and the storage.
+    // Inputs are stored in the runtime, so they cannot borrow anything.
+    for param in item.generics.params.iter() {
+        if !matches!(param, GenericParam::Type(_)) {
+            return syn::Error::new(
+                param.span(),
+                "only type parameters are supported on databases",
+            )
+            .into_compile_error()
+            .into();
+        }
+    }
+
+    if options.mock && !item.generics.params.is_empty() {
+        return syn::Error::new(
+            item.generics.span(),
+            "mock is not supported on generic databases",
+        )
+        .into_compile_error()
+        .into();
+    }
+
+    let generics = item.generics.clone();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let type_params = generics
+        .type_params()
+        .map(|param| &param.ident)
+        .collect::<Vec<_>>();
+
+    // Groups are indexed after the inputs, so that their dependencies do not
+    // clash.
+    let mut groups = Vec::new();
+    for input in inputs.iter() {
+        if let Some(ref group) = input.options.group {
+            if !groups.contains(&group.value()) {
+                groups.push(group.value());
+            }
+        }
+    }
+
     let quoted_inputs = inputs.iter().enumerate().map(|(i, input)| {
         let Input {
             name,
             ty_name,
             output,
+            docs,
             ..
         } = input;
         let args_ty = input.args_ty();
+        let key_map_ty = input.key_map_ty();
+        let values_ty = input.values_ty();
+        let metadata_ty = match input.options.metadata {
+            Some(ref metadata) => quote!(#metadata),
+            None => quote!(()),
+        };
         let index = i as u16;
+        let default_value = input.options.default.as_ref().map(|default| {
+            quote! {
+                fn default_value() -> Option<Self::Value> {
+                    Some(#default)
+                }
+            }
+        });
+        let group = input.options.group.as_ref().map(|group| {
+            let position = groups
+                .iter()
+                .position(|other| *other == group.value())
+                .unwrap();
+            let index = (inputs.len() + position) as u16;
+            quote!(const GROUP: Option<u16> = Some(#index);)
+        });
+        let fingerprint = input.options.fingerprint.then(|| {
+            quote! {
+                fn fingerprint(value: &Self::Value) -> Option<u64> {
+                    use std::hash::{Hash, Hasher};
+
+                    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                    value.hash(&mut hasher);
+                    Some(hasher.finish())
+                }
+            }
+        });
+        let value_eq = input.options.eq.then(|| {
+            quote! {
+                fn value_eq(previous: &Self::Value, value: &Self::Value) -> bool {
+                    previous == value
+                }
+            }
+        });
+        let normalize_key = input.options.normalize.as_ref().map(|normalize| {
+            quote! {
+                fn normalize_key(key: &Self::Key) -> Option<Self::Key> {
+                    Some(#normalize(key))
+                }
+            }
+        });
+
+        let marker = if type_params.is_empty() {
+            quote! {
+                #(#docs)*
+                #[derive(Debug, Default)]
+                #vis struct #ty_name;
+            }
+        } else {
+            // Derives would require the parameters to implement the traits
+            // too.
+            quote! {
+                #(#docs)*
+                #vis struct #ty_name #generics (std::marker::PhantomData<fn() -> (#(#type_params,)*)>) #where_clause;
+
+                impl #impl_generics std::fmt::Debug for #ty_name #ty_generics #where_clause {
+                    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                        f.write_str(stringify!(#ty_name))
+                    }
+                }
+
+                impl #impl_generics Default for #ty_name #ty_generics #where_clause {
+                    fn default() -> Self {
+                        Self(std::marker::PhantomData)
+                    }
+                }
+            }
+        };
 
         quote! {
-            #[derive(Debug, Default)]
-            struct #ty_name;
+            #marker
 
-            impl inqui::Input for #ty_name {
+            impl #impl_generics inqui::Input for #ty_name #ty_generics #where_clause {
                 type Key = #args_ty;
                 type Value = #output;
-                type StorageGroup = #storage_name;
+                type StorageGroup = #storage_name #ty_generics;
+                type KeyMap = #key_map_ty;
+                type Values = #values_ty;
+                type Metadata = #metadata_ty;
 
                 const INDEX: u16 = #index;
 
+                #group
+
+                fn name() -> &'static str {
+                    stringify!(#name)
+                }
+
+                #normalize_key
+
+                #default_value
+
+                #value_eq
+
+                #fingerprint
+
                 fn storage(group: &Self::StorageGroup) -> &inqui::InputStorage<Self> {
                     &group.#name
                 }
@@ -95,18 +269,312 @@ pub fn database(_: TokenStream, item: TokenStream) -> TokenStream {
                     &mut group.#name
                 }
             }
+
         }
     });
 
-    let storage_body = inputs
+    let storage_body = inputs.iter().map(
+        |Input { name, ty_name, .. }| quote!(#name: inqui::InputStorage<#ty_name #ty_generics>),
+    );
+
+    let manifest = inputs.iter().enumerate().map(|(i, Input { name, .. })| {
+        let name = name.to_string();
+        let index = i as u16;
+        quote!((#name, #index))
+    });
+
+    let manifest_hash = inputs
         .iter()
-        .map(|Input { name, ty_name, .. }| quote!(#name: inqui::InputStorage<#ty_name>));
+        .enumerate()
+        .fold(FNV_OFFSET, |hash, (i, input)| {
+            let args_ty = input.args_ty();
+            let output = &input.output;
+            let mut entry = format!("{}:{}:{}:{}", input.name, i, args_ty, quote!(#output));
+            if let Some(ref group) = input.options.group {
+                entry.push_str(&format!(":{}", group.value()));
+            }
+            fnv_hash(hash, entry.as_bytes())
+        });
 
+    let input_names = inputs.iter().map(|input| &input.name).collect::<Vec<_>>();
+    let input_count = inputs.len();
+    let index_count = (inputs.len() + groups.len()) as u16;
+
+    let input_tys = inputs
+        .iter()
+        .map(|input| &input.ty_name)
+        .collect::<Vec<_>>();
+    let where_predicates = where_clause.map(|where_clause| &where_clause.predicates);
+
+    // The storage can be cloned or formatted only if all inputs can. The
+    // higher-ranked bounds defer the check to where it is used. Default is
+    // implemented by hand so that it is not required from the type parameters.
+    let serde_attrs = serde_attrs(&input_tys, &ty_generics);
+    let trait_name = &item.ident;
     let quoted_storage = quote! {
-        #[derive(Debug, Default)]
-        struct #storage_name {
+        #serde_attrs
+        #vis struct #storage_name #generics #where_clause {
             #(#storage_body,)*
         }
+
+        impl #impl_generics std::fmt::Debug for #storage_name #ty_generics
+        where
+            #(for<'a> inqui::InputStorage<#input_tys #ty_generics>: std::fmt::Debug,)*
+            #where_predicates
+        {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!(#storage_name))
+                    #(.field(stringify!(#input_names), &self.#input_names))*
+                    .finish()
+            }
+        }
+
+        impl #impl_generics Default for #storage_name #ty_generics #where_clause {
+            fn default() -> Self {
+                Self {
+                    #(#input_names: Default::default(),)*
+                }
+            }
+        }
+
+        impl #impl_generics Clone for #storage_name #ty_generics
+        where
+            #(for<'a> inqui::InputStorage<#input_tys #ty_generics>: Clone,)*
+            #where_predicates
+        {
+            fn clone(&self) -> Self {
+                Self {
+                    #(#input_names: self.#input_names.clone(),)*
+                }
+            }
+        }
+
+        impl #impl_generics inqui::input::DatabaseStorage for #storage_name #ty_generics #where_clause {
+            type Database = dyn #trait_name #ty_generics;
+        }
+
+        impl #impl_generics inqui::input::StorageGroup for #storage_name #ty_generics #where_clause {
+            const INPUT_MANIFEST: &'static [(&'static str, u16)] = &[#(#manifest),*];
+            const MANIFEST_HASH: u64 = #manifest_hash;
+            const INDEX_COUNT: u16 = #index_count;
+
+            fn approx_memory_bytes(&self) -> usize {
+                0 #(+ self.#input_names.approx_memory_bytes())*
+            }
+
+            fn compact(&mut self) -> inqui::input::KeyRemap {
+                let remaps: [inqui::input::KeyRemap; #input_count] =
+                    [#(self.#input_names.compact()),*];
+                remaps.into_iter().collect()
+            }
+
+            fn diff(&self, old: &Self) -> Vec<inqui::input::InputChange> {
+                let mut changes = Vec::new();
+                #(changes.extend(self.#input_names.diff(&old.#input_names));)*
+                changes
+            }
+
+            fn dependencies(&self) -> Vec<(inqui::input::InputIndex, inqui::input::KeyIndex)> {
+                let mut dependencies = Vec::new();
+                #(dependencies.extend(self.#input_names.dependencies());)*
+                dependencies
+            }
+        }
+    };
+
+    let context_methods = inputs.iter().map(|input| {
+        let Input {
+            name,
+            ty_name,
+            args,
+            output,
+            ..
+        } = input;
+        let arg_names = (0..args.len())
+            .map(|i| format_ident!("arg{}", i))
+            .collect::<Vec<_>>();
+        let key = match arg_names.len() {
+            0 => quote!(()),
+            1 => quote!(#(#arg_names)*),
+            _ => quote!((#(#arg_names),*)),
+        };
+
+        if input.optional {
+            quote! {
+                fn #name(&self, #(#arg_names: #args),*) -> Option<#output> {
+                    self.use_input::<#ty_name #ty_generics>(&#key)
+                }
+            }
+        } else {
+            let message = format!("input `{}` is not set", name);
+            quote! {
+                fn #name(&self, #(#arg_names: #args),*) -> #output {
+                    self.use_input::<#ty_name #ty_generics>(&#key).expect(#message)
+                }
+            }
+        }
+    });
+
+    // The implementations for the runtime types are additionally generic over
+    // the storage containing the database.
+    let mut context_generics = generics.clone();
+    context_generics
+        .params
+        .push(parse_quote!(I: inqui::input::Contains<#storage_name #ty_generics>));
+    let (context_impl_generics, _, _) = context_generics.split_for_impl();
+
+    let transaction_name = format_ident!("{}Transaction", trait_name);
+    let (transaction_decls, transaction_methods): (Vec<_>, Vec<_>) = inputs
+        .iter()
+        .map(|input| {
+            let Input {
+                name,
+                ty_name,
+                args,
+                output,
+                ..
+            } = input;
+            let set_name = format_ident!("set_{}", name);
+            let remove_name = format_ident!("remove_{}", name);
+            let arg_names = (0..args.len())
+                .map(|i| format_ident!("arg{}", i))
+                .collect::<Vec<_>>();
+            let key = match arg_names.len() {
+                0 => quote!(()),
+                1 => quote!(#(#arg_names)*),
+                _ => quote!((#(#arg_names),*)),
+            };
+
+            let decl = quote! {
+                fn #set_name(&mut self, #(#arg_names: #args,)* value: #output);
+                fn #remove_name(&mut self, #(#arg_names: #args),*);
+            };
+            let method = quote! {
+                fn #set_name(&mut self, #(#arg_names: #args,)* value: #output) {
+                    self.set::<#ty_name #ty_generics>(#key, value);
+                }
+
+                fn #remove_name(&mut self, #(#arg_names: #args),*) {
+                    self.remove::<#ty_name #ty_generics>(&#key);
+                }
+            };
+
+            (decl, method)
+        })
+        .unzip();
+
+    // Typed setters for updating inputs of different types in a single
+    // transaction.
+    let quoted_transaction = quote! {
+        #vis trait #transaction_name #generics #where_clause {
+            #(#transaction_decls)*
+        }
+
+        impl #context_impl_generics #transaction_name #ty_generics
+            for inqui::runtime::Transaction<'_, I>
+        #where_clause
+        {
+            #(#transaction_methods)*
+        }
+    };
+
+    // A database with inputs in plain hash maps, for testing query functions
+    // without the runtime.
+    let quoted_mock = options.mock.then(|| {
+        let mock_name = format_ident!("{}Mock", trait_name);
+
+        let fields = inputs.iter().map(|input| {
+            let name = &input.name;
+            let args_ty = input.args_ty();
+            let output = &input.output;
+            quote!(#name: std::collections::HashMap<#args_ty, #output>)
+        });
+
+        let (setters, getters): (Vec<_>, Vec<_>) = inputs
+            .iter()
+            .map(|input| {
+                let Input {
+                    name, args, output, ..
+                } = input;
+                let with_name = format_ident!("with_{}", name);
+                let arg_names = (0..args.len())
+                    .map(|i| format_ident!("arg{}", i))
+                    .collect::<Vec<_>>();
+                let key = match arg_names.len() {
+                    0 => quote!(()),
+                    1 => quote!(#(#arg_names)*),
+                    _ => quote!((#(#arg_names),*)),
+                };
+                let normalize = match input.options.normalize {
+                    Some(ref normalize) => quote!(let key = #normalize(&key);),
+                    None => quote!(),
+                };
+                let default = match input.options.default {
+                    Some(ref default) => quote!(.or_else(|| Some(#default))),
+                    None => quote!(),
+                };
+
+                let setter = quote! {
+                    pub fn #with_name(mut self, #(#arg_names: #args,)* value: #output) -> Self {
+                        let key = #key;
+                        #normalize
+                        self.#name.insert(key, value);
+                        self
+                    }
+                };
+
+                let value = quote! {
+                    let key = #key;
+                    #normalize
+                    let value = self.#name.get(&key).cloned()#default;
+                };
+                let getter = if input.optional {
+                    quote! {
+                        fn #name(&self, #(#arg_names: #args),*) -> Option<#output> {
+                            #value
+                            value
+                        }
+                    }
+                } else {
+                    let message = format!("input `{}` is not set", name);
+                    quote! {
+                        fn #name(&self, #(#arg_names: #args),*) -> #output {
+                            #value
+                            value.expect(#message)
+                        }
+                    }
+                };
+
+                (setter, getter)
+            })
+            .unzip();
+
+        quote! {
+            #[derive(Default)]
+            #vis struct #mock_name {
+                #(#fields,)*
+            }
+
+            impl #mock_name {
+                pub fn new() -> Self {
+                    Self::default()
+                }
+
+                #(#setters)*
+            }
+
+            impl #trait_name for #mock_name {
+                #(#getters)*
+            }
+        }
+    });
+
+    // Queries that do not need anything special can use the query context
+    // directly as the database.
+    let quoted_context = quote! {
+        impl #context_impl_generics #trait_name #ty_generics for inqui::QueryContext<'_, I> #where_clause {
+            #(#context_methods)*
+        }
     };
 
     TokenStream::from(quote! {
@@ -115,14 +583,86 @@ pub fn database(_: TokenStream, item: TokenStream) -> TokenStream {
         #(#quoted_inputs)*
 
         #quoted_storage
+
+        #quoted_context
+
+        #quoted_transaction
+
+        #quoted_mock
     })
 }
 
+// The storage can be persisted only if all inputs can, which is deferred to
+// where it is used by the higher-ranked bounds, like with cloning.
+#[cfg(feature = "serde")]
+fn serde_attrs(input_tys: &[&Ident], ty_generics: &syn::TypeGenerics) -> proc_macro2::TokenStream {
+    let serialize = input_tys
+        .iter()
+        .map(|ty| {
+            quote!(for<'a> inqui::InputStorage<#ty #ty_generics>: inqui::serde::Serialize)
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let deserialize = input_tys
+        .iter()
+        .map(|ty| {
+            quote!(for<'a> inqui::InputStorage<#ty #ty_generics>: inqui::serde::Deserialize<'de>)
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    quote! {
+        #[derive(inqui::serde::Serialize, inqui::serde::Deserialize)]
+        #[serde(crate = "inqui::serde", bound(serialize = #serialize, deserialize = #deserialize))]
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn serde_attrs(_: &[&Ident], _: &syn::TypeGenerics) -> proc_macro2::TokenStream {
+    quote!()
+}
+
+#[derive(Default)]
+struct DatabaseOptions {
+    mock: bool,
+    // Name of the generated storage, `{Trait}Storage` by default.
+    storage: Option<Ident>,
+}
+
+impl Parse for DatabaseOptions {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = DatabaseOptions::default();
+
+        while !input.is_empty() {
+            let ident = input.parse::<Ident>()?;
+            match ident.to_string().as_str() {
+                "mock" => options.mock = true,
+                "storage" => {
+                    input.parse::<Token![=]>()?;
+                    options.storage = Some(input.parse()?);
+                }
+                _ => return Err(syn::Error::new(ident.span(), "unknown database option")),
+            }
+
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(options)
+    }
+}
+
 struct Input {
     name: Ident,
     ty_name: Ident,
     args: Vec<Type>,
     output: Box<Type>,
+    optional: bool,
+    options: InputOptions,
+    docs: Vec<Attribute>,
 }
 
 impl Input {
@@ -140,6 +680,147 @@ impl Input {
             }
         }
     }
+
+    fn key_map_ty(&self) -> proc_macro2::TokenStream {
+        let args_ty = self.args_ty();
+
+        match self.options.storage {
+            StorageKind::Hash => quote!(inqui::input::HashKeyMap<#args_ty>),
+            StorageKind::BTree => quote!(inqui::input::BTreeKeyMap<#args_ty>),
+            StorageKind::Vec => quote!(inqui::input::VecKeyMap<#args_ty>),
+        }
+    }
+
+    fn values_ty(&self) -> proc_macro2::TokenStream {
+        let output = &self.output;
+
+        match self.options.storage {
+            StorageKind::Hash => quote!(inqui::input::HashValues<#output>),
+            StorageKind::BTree => quote!(inqui::input::BTreeValues<#output>),
+            StorageKind::Vec => quote!(inqui::input::VecValues<#output>),
+        }
+    }
+}
+
+// The key map and the value storage backing the input.
+#[derive(Default, Clone, Copy)]
+enum StorageKind {
+    #[default]
+    Hash,
+    BTree,
+    Vec,
+}
+
+#[derive(Default)]
+struct InputOptions {
+    storage: StorageKind,
+    eq: bool,
+    fingerprint: bool,
+    normalize: Option<Path>,
+    metadata: Option<Type>,
+    default: Option<Expr>,
+    group: Option<LitStr>,
+}
+
+enum InputOption {
+    Storage(StorageKind),
+    Eq,
+    Fingerprint,
+    Normalize(Path),
+    Metadata(Type),
+    Default(Expr),
+    Group(LitStr),
+}
+
+impl Parse for InputOption {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ident: Ident = input.parse()?;
+
+        match ident.to_string().as_str() {
+            // Kept as a shorthand for `storage = "btree"`.
+            "ordered" => Ok(InputOption::Storage(StorageKind::BTree)),
+            "storage" => {
+                input.parse::<Token![=]>()?;
+                let storage: LitStr = input.parse()?;
+                match storage.value().as_str() {
+                    "hash" => Ok(InputOption::Storage(StorageKind::Hash)),
+                    "btree" => Ok(InputOption::Storage(StorageKind::BTree)),
+                    "vec" => Ok(InputOption::Storage(StorageKind::Vec)),
+                    _ => Err(syn::Error::new(
+                        storage.span(),
+                        "expected \"hash\", \"btree\" or \"vec\" storage",
+                    )),
+                }
+            }
+            "eq" => Ok(InputOption::Eq),
+            "fingerprint" => Ok(InputOption::Fingerprint),
+            "normalize" => {
+                input.parse::<Token![=]>()?;
+                Ok(InputOption::Normalize(input.parse()?))
+            }
+            "metadata" => {
+                input.parse::<Token![=]>()?;
+                Ok(InputOption::Metadata(input.parse()?))
+            }
+            "default" => {
+                input.parse::<Token![=]>()?;
+                Ok(InputOption::Default(input.parse()?))
+            }
+            "group" => {
+                input.parse::<Token![=]>()?;
+                Ok(InputOption::Group(input.parse()?))
+            }
+            _ => Err(syn::Error::new(ident.span(), "unknown input option")),
+        }
+    }
+}
+
+fn take_input_options(attrs: &mut Vec<Attribute>) -> syn::Result<InputOptions> {
+    let mut options = InputOptions::default();
+    let mut error = None;
+
+    attrs.retain(|attr| {
+        if !attr.path.is_ident("input") {
+            return true;
+        }
+
+        match attr.parse_args_with(Punctuated::<InputOption, Token![,]>::parse_terminated) {
+            Ok(parsed) => {
+                for option in parsed {
+                    match option {
+                        InputOption::Storage(storage) => options.storage = storage,
+                        InputOption::Eq => options.eq = true,
+                        InputOption::Fingerprint => options.fingerprint = true,
+                        InputOption::Normalize(normalize) => options.normalize = Some(normalize),
+                        InputOption::Metadata(metadata) => options.metadata = Some(metadata),
+                        InputOption::Default(default) => options.default = Some(default),
+                        InputOption::Group(group) => options.group = Some(group),
+                    }
+                }
+            }
+            Err(err) => {
+                error.get_or_insert(err);
+            }
+        }
+
+        false
+    });
+
+    match error {
+        Some(error) => Err(error),
+        None => Ok(options),
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// The hash must be stable across compilations, hence FNV instead of the
+// standard hasher.
+fn fnv_hash(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
 }
 
 fn unwrap_option_type(ty: &Type) -> Option<Box<Type>> {
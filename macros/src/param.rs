@@ -0,0 +1,59 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{spanned::Spanned, Fields, ItemEnum};
+
+pub fn param(item: ItemEnum) -> syn::Result<TokenStream> {
+    let name = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+
+    let mut seen = Vec::new();
+    let mut from_impls = Vec::new();
+
+    for variant in item.variants.iter() {
+        let variant_name = &variant.ident;
+
+        let fields = match variant.fields {
+            Fields::Unnamed(ref fields) => &fields.unnamed,
+            // Unit variants and variants with named fields have no natural
+            // source type.
+            _ => continue,
+        };
+
+        let tys = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+        let (source, construct) = match tys.len() {
+            1 => (quote!(#(#tys)*), quote!(Self::#variant_name(value))),
+            len => {
+                let indices = (0..len).map(syn::Index::from);
+                (
+                    quote!((#(#tys),*)),
+                    quote!(Self::#variant_name(#(value.#indices),*)),
+                )
+            }
+        };
+
+        // Conversions from the same type would conflict.
+        let source_str = source.to_string();
+        if seen.contains(&source_str) {
+            return Err(syn::Error::new(
+                variant.fields.span(),
+                "param variants must have distinct field types",
+            ));
+        }
+        seen.push(source_str);
+
+        from_impls.push(quote! {
+            impl #impl_generics From<#source> for #name #ty_generics #where_clause {
+                fn from(value: #source) -> Self {
+                    #construct
+                }
+            }
+        });
+    }
+
+    Ok(TokenStream::from(quote! {
+        #[derive(Clone, PartialEq, Eq, Hash)]
+        #item
+
+        #(#from_impls)*
+    }))
+}
@@ -0,0 +1,266 @@
+use convert_case::{Case, Casing};
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    spanned::Spanned,
+    Attribute, FnArg, Ident, Item, ItemFn, ItemStruct, Path, Type,
+};
+
+pub fn query_system(input: QuerySystem) -> syn::Result<TokenStream> {
+    let QuerySystem {
+        system,
+        database,
+        queries,
+        items,
+    } = input;
+
+    let vis = &system.vis;
+    let system_name = &system.ident;
+    let system_attrs = &system.attrs;
+    let param_name = format_ident!("{}Param", system_name);
+    let storage_name = storage_name(&database)?;
+
+    let variants = queries.iter().map(|query| {
+        let variant = &query.variant;
+        let args_ty = query.args_ty();
+        quote!(#variant(#args_ty))
+    });
+
+    let methods = queries.iter().map(|query| {
+        let Query {
+            item,
+            variant,
+            args,
+            ..
+        } = query;
+        let name = &item.sig.ident;
+        let output = &query.output;
+        let attrs = &item.attrs;
+        let vis = &item.vis;
+
+        let arg_names = (0..args.len())
+            .map(|i| format_ident!("arg{}", i))
+            .collect::<Vec<_>>();
+        let packed = match arg_names.len() {
+            1 => quote!(#(#arg_names)*),
+            _ => quote!((#(#arg_names),*)),
+        };
+
+        quote! {
+            #(#attrs)*
+            #vis fn #name(&self, #(#arg_names: #args),*) -> std::sync::Arc<#output> {
+                struct Query;
+
+                let param = #param_name::#variant(#packed);
+
+                self.queries
+                    .cached::<Query, #output, _>(&param, &self.runtime)
+                    .unwrap_or_else(|| {
+                        let guard = self.runtime.lock_readonly();
+
+                        let output = self.queries.insert_with::<Query, _, _, _>(
+                            &self.runtime,
+                            param,
+                            |param, ctx| match param {
+                                #param_name::#variant(#packed) => #name(ctx, #(#arg_names.clone()),*),
+                                #[allow(unreachable_patterns)]
+                                _ => unreachable!(),
+                            },
+                        );
+
+                        drop(guard);
+
+                        output
+                    })
+            }
+        }
+    });
+
+    let infos = queries.iter().map(|query| {
+        let name = query.item.sig.ident.to_string();
+        let params = query.args.iter().map(|arg| quote!(#arg).to_string());
+        let output = &query.output;
+        let output = quote!(#output).to_string();
+
+        quote! {
+            inqui::query::QueryInfo {
+                name: #name,
+                params: &[#(#params),*],
+                output: #output,
+            }
+        }
+    });
+
+    let query_fns = queries.iter().map(|query| &query.item);
+
+    Ok(TokenStream::from(quote! {
+        #(#system_attrs)*
+        #[derive(Default, Clone)]
+        #vis struct #system_name {
+            runtime: inqui::Runtime<#storage_name>,
+            queries: std::sync::Arc<inqui::QueryCache<#param_name>>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        #vis enum #param_name {
+            #(#variants,)*
+        }
+
+        impl #system_name {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            // All queries of the system in the order of declaration.
+            pub fn query_registry() -> &'static [inqui::query::QueryInfo] {
+                &[#(#infos),*]
+            }
+
+            pub fn runtime(&self) -> &inqui::Runtime<#storage_name> {
+                &self.runtime
+            }
+
+            pub fn set_input<T>(&mut self, key: T::Key, value: T::Value) -> Option<T::Value>
+            where
+                T: inqui::input::InputOf<#storage_name>,
+            {
+                self.runtime.set_input::<T>(key, value)
+            }
+
+            pub fn remove_input<T>(&mut self, key: &T::Key) -> inqui::runtime::RemoveResult<T::Value>
+            where
+                T: inqui::input::InputOf<#storage_name>,
+            {
+                self.runtime.remove_input::<T>(key)
+            }
+
+            #(#methods)*
+        }
+
+        #(#query_fns)*
+
+        #(#items)*
+    }))
+}
+
+pub struct QuerySystem {
+    system: ItemStruct,
+    database: Path,
+    queries: Vec<Query>,
+    items: Vec<Item>,
+}
+
+impl Parse for QuerySystem {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut system: ItemStruct = input.parse()?;
+
+        let database = take_attr(&mut system.attrs, "database")
+            .ok_or_else(|| {
+                syn::Error::new(
+                    system.ident.span(),
+                    "query system must have #[database(Trait)] attribute",
+                )
+            })?
+            .parse_args::<Path>()?;
+
+        if !matches!(system.fields, syn::Fields::Unit) {
+            return Err(syn::Error::new(
+                system.fields.span(),
+                "query system must be declared as a unit struct",
+            ));
+        }
+
+        let mut queries = Vec::new();
+        let mut items = Vec::new();
+
+        while !input.is_empty() {
+            match input.parse()? {
+                Item::Fn(mut item) => {
+                    if take_attr(&mut item.attrs, "query").is_some() {
+                        queries.push(Query::new(item)?);
+                    } else {
+                        items.push(Item::Fn(item));
+                    }
+                }
+                item => items.push(item),
+            }
+        }
+
+        Ok(QuerySystem {
+            system,
+            database,
+            queries,
+            items,
+        })
+    }
+}
+
+struct Query {
+    item: ItemFn,
+    variant: Ident,
+    args: Vec<Type>,
+    output: Box<Type>,
+}
+
+impl Query {
+    fn new(item: ItemFn) -> syn::Result<Self> {
+        let variant = format_ident!("{}", item.sig.ident.to_string().to_case(Case::Pascal));
+
+        let mut inputs = item.sig.inputs.iter();
+
+        // The first argument is the database.
+        if !matches!(inputs.next(), Some(FnArg::Typed(_))) {
+            return Err(syn::Error::new(
+                item.sig.span(),
+                "query must take the database as the first argument",
+            ));
+        }
+
+        let args = inputs
+            .map(|input| match input {
+                FnArg::Typed(pat_ty) => Ok((*pat_ty.ty).clone()),
+                FnArg::Receiver(receiver) => Err(syn::Error::new(
+                    receiver.span(),
+                    "query must be a free function",
+                )),
+            })
+            .collect::<syn::Result<Vec<_>>>()?;
+
+        let output = match item.sig.output {
+            syn::ReturnType::Default => Box::new(syn::parse_quote!(())),
+            syn::ReturnType::Type(_, ref output) => output.clone(),
+        };
+
+        Ok(Query {
+            item,
+            variant,
+            args,
+            output,
+        })
+    }
+
+    fn args_ty(&self) -> proc_macro2::TokenStream {
+        let args = &self.args;
+
+        match args.len() {
+            1 => quote!(#(#args)*),
+            _ => quote!((#(#args),*)),
+        }
+    }
+}
+
+fn take_attr(attrs: &mut Vec<Attribute>, name: &str) -> Option<Attribute> {
+    let position = attrs.iter().position(|attr| attr.path.is_ident(name))?;
+    Some(attrs.remove(position))
+}
+
+fn storage_name(database: &Path) -> syn::Result<Path> {
+    let mut storage = database.clone();
+    let last = storage
+        .segments
+        .last_mut()
+        .ok_or_else(|| syn::Error::new(database.span(), "expected database trait"))?;
+    last.ident = format_ident!("{}Storage", last.ident);
+    Ok(storage)
+}
@@ -1,29 +1,915 @@
-use std::hash::Hash;
+use std::{
+    collections::{btree_map, hash_map, BTreeMap},
+    hash::{Hash, Hasher},
+    mem,
+    ops::RangeBounds,
+};
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHasher};
 
 pub trait Input {
     type Key: Hash + Eq;
     type Value: Clone;
-    type StorageGroup;
+    type StorageGroup: DatabaseStorage;
+    type KeyMap: KeyMap<Self::Key>;
+    type Values: Storage<KeyIndex, Self::Value>;
+    // Informational data stored alongside the value, which does not trigger
+    // invalidation when changed.
+    type Metadata;
 
     const INDEX: u16;
+    // Inputs of the same group share the dependency on every key, so a query
+    // that uses several of them records only one dependency, and setting any
+    // of them invalidates the queries using the others.
+    const GROUP: Option<u16> = None;
+
+    // The name of the input as declared in the database.
+    fn name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    // Canonicalizes the key before it is hashed and looked up, so that keys
+    // that are logically equal map to the same input. `None` means the key is
+    // already canonical.
+    fn normalize_key(_key: &Self::Key) -> Option<Self::Key> {
+        None
+    }
+
+    // The value used when the key is not set.
+    fn default_value() -> Option<Self::Value> {
+        None
+    }
+
+    // Whether the value set is equal to the previous one, in which case the
+    // queries using it stay valid. By default, setting a value is always a
+    // change, `#[input(eq)]` compares the values with `PartialEq`.
+    fn value_eq(_previous: &Self::Value, _value: &Self::Value) -> bool {
+        false
+    }
+
+    // A hash of the value compared before the values themselves when the value
+    // is set, so that setting a different large value does not need a full
+    // comparison. `None` means the values are always compared in full.
+    fn fingerprint(_value: &Self::Value) -> Option<u64> {
+        None
+    }
 
     fn storage(group: &Self::StorageGroup) -> &InputStorage<Self>;
     fn storage_mut(group: &mut Self::StorageGroup) -> &mut InputStorage<Self>;
 }
 
+// Ties the input to the storage the runtime is parametrized with, which is
+// either the storage group of its database or a composite containing it (see
+// `Contains`).
+pub trait InputOf<I>: Input {
+    // The index of the input in `I`, see `Contains::INPUT_OFFSET`.
+    fn index() -> InputIndex;
+    // The dependency shared by all inputs of the group on given key, `None`
+    // if the input is not in a group.
+    fn group(key: &Self::Key) -> Option<(InputIndex, KeyIndex)>;
+    fn storage_in(inputs: &I) -> &InputStorage<Self>;
+    fn storage_in_mut(inputs: &mut I) -> &mut InputStorage<Self>;
+}
+
+impl<T: Input, I: Contains<T::StorageGroup>> InputOf<I> for T {
+    fn index() -> InputIndex {
+        InputIndex(I::INPUT_OFFSET + T::INDEX)
+    }
+
+    fn group(key: &Self::Key) -> Option<(InputIndex, KeyIndex)> {
+        group_dependency::<T>(key)
+            .map(|(group, key_index)| (InputIndex(I::INPUT_OFFSET + group.0), key_index))
+    }
+
+    fn storage_in(inputs: &I) -> &InputStorage<Self> {
+        T::storage(inputs.part())
+    }
+
+    fn storage_in_mut(inputs: &mut I) -> &mut InputStorage<Self> {
+        T::storage_mut(inputs.part_mut())
+    }
+}
+
+// The storage generated by the `database` macro.
+pub trait DatabaseStorage {
+    // The trait object of the database, which names it in the error messages.
+    type Database: ?Sized;
+}
+
+// A storage composed of the storage groups of several databases, so that one
+// runtime (and one query context) serves the inputs of all of them. Every
+// storage group contains itself.
+//
+// Using an input with a storage that does not contain its database fails on
+// this trait, so the error message names the database of the input. The
+// database parameter serves only that purpose.
+#[diagnostic::on_unimplemented(
+    message = "input belongs to database `{D}`, but this context is for `{Self}`",
+    label = "input from a different database",
+    note = "inputs can only be used with the storage generated by the same `#[database]` trait or a composite storage containing it"
+)]
+pub trait Contains<S: DatabaseStorage, D: ?Sized = <S as DatabaseStorage>::Database> {
+    // Added to the indices of the inputs (and input groups) of the part, so
+    // that they do not clash with the inputs of the other parts. Derive it
+    // from the previous part with `offset_after` rather than picking it by
+    // hand, a clash silently mixes the dependencies of the two parts.
+    const INPUT_OFFSET: u16 = 0;
+
+    fn part(&self) -> &S;
+    fn part_mut(&mut self) -> &mut S;
+}
+
+impl<S: DatabaseStorage> Contains<S> for S {
+    fn part(&self) -> &S {
+        self
+    }
+
+    fn part_mut(&mut self) -> &mut S {
+        self
+    }
+}
+
+// The first input index after the part `S` of the composite storage `C`, i.e.,
+// the offset of the next part.
+pub const fn offset_after<C: Contains<S>, S: StorageGroup + DatabaseStorage>() -> u16 {
+    C::INPUT_OFFSET + S::INDEX_COUNT
+}
+
+// Describes the layout of a storage group generated by the `database` macro, so
+// that persisted input indices can be validated against the current layout.
+pub trait StorageGroup {
+    // Pairs of input (method) name and its index.
+    const INPUT_MANIFEST: &'static [(&'static str, u16)];
+    // Hash of the manifest including key and value types of the inputs.
+    const MANIFEST_HASH: u64;
+    // Number of the input indices used by the group, including the indices of
+    // the input groups, which follow the inputs.
+    const INDEX_COUNT: u16;
+
+    fn approx_memory_bytes(&self) -> usize;
+
+    // Compacts the storages of all inputs, see `InputStorage::compact`. The
+    // input indices in the results of this and the following methods are the
+    // ones of the group, a composite storage offsets those of its parts (see
+    // `KeyRemap::offset`).
+    fn compact(&mut self) -> KeyRemap;
+
+    // Changes of all inputs since the old storage, see `InputStorage::diff`.
+    fn diff(&self, old: &Self) -> Vec<InputChange>;
+
+    // Dependencies on all keys that are set, see `InputStorage::dependencies`.
+    fn dependencies(&self) -> Vec<(InputIndex, KeyIndex)>;
+
+    fn input_name(index: u16) -> Option<&'static str> {
+        Self::INPUT_MANIFEST
+            .iter()
+            .find(|(_, other)| *other == index)
+            .map(|(name, _)| *name)
+    }
+
+    fn check_manifest<S: AsRef<str>>(manifest: &[(S, u16)]) -> Result<(), ManifestMismatch> {
+        let mut mismatched = Self::INPUT_MANIFEST
+            .iter()
+            .filter(|(name, index)| {
+                !manifest
+                    .iter()
+                    .any(|(other, other_index)| other.as_ref() == *name && other_index == index)
+            })
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<_>>();
+
+        mismatched.extend(
+            manifest
+                .iter()
+                .filter(|(name, _)| {
+                    !Self::INPUT_MANIFEST
+                        .iter()
+                        .any(|(other, _)| *other == name.as_ref())
+                })
+                .map(|(name, _)| name.as_ref().to_string()),
+        );
+
+        if mismatched.is_empty() {
+            Ok(())
+        } else {
+            Err(ManifestMismatch { mismatched })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestMismatch {
+    // Names of inputs that are missing on either side or have different index.
+    pub mismatched: Vec<String>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct InputIndex(pub(crate) u16);
 
+impl InputIndex {
+    // The index in a composite storage, see `Contains::INPUT_OFFSET`.
+    pub fn offset(self, offset: u16) -> Self {
+        InputIndex(self.0 + offset)
+    }
+}
+
+// Slots of removed keys are reused, the generation distinguishes the new key
+// from the removed one so that dependencies on the latter are not confused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyIndex {
+    pub(crate) slot: u32,
+    pub(crate) generation: u32,
+}
+
+impl KeyIndex {
+    // Stands for all keys of an input that are not set. Queries that used a key
+    // that is not set depend on it, and it changes whenever a new key is set.
+    pub(crate) const MISSING: KeyIndex = KeyIndex {
+        slot: u32::MAX,
+        generation: u32::MAX,
+    };
+
+    // Keys of input groups are identified by their hash, collisions only cause
+    // unnecessary invalidations.
+    const GROUP_GENERATION: u32 = u32::MAX - 1;
+
+    // Whether the index does not refer to a key in the storage, so it may not
+    // have a revision.
+    pub(crate) fn is_storage_independent(self) -> bool {
+        self == KeyIndex::MISSING || self.generation == KeyIndex::GROUP_GENERATION
+    }
+}
+
+// The dependency shared by all inputs of the group on given key, `None` if the
+// input is not in a group.
+pub(crate) fn group_dependency<T: Input + ?Sized>(key: &T::Key) -> Option<(InputIndex, KeyIndex)> {
+    let group = T::GROUP?;
+    let normalized = T::normalize_key(key);
+    let mut hasher = FxHasher::default();
+    normalized.as_ref().unwrap_or(key).hash(&mut hasher);
+    let hash = hasher.finish();
+
+    Some((
+        InputIndex(group),
+        KeyIndex {
+            slot: (hash ^ (hash >> 32)) as u32,
+            generation: KeyIndex::GROUP_GENERATION,
+        },
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Change {
+    Unchanged,
+    Changed,
+    Inserted,
+}
+
+impl Change {
+    // Key indices whose revision must be updated. The group dependency, if
+    // any, replaces both.
+    pub(crate) fn changed_keys(
+        self,
+        input: InputIndex,
+        key_index: KeyIndex,
+        group: Option<(InputIndex, KeyIndex)>,
+    ) -> impl Iterator<Item = (InputIndex, KeyIndex)> {
+        let count = match (self, group) {
+            (Change::Unchanged, _) => 0,
+            (Change::Changed, _) | (Change::Inserted, Some(_)) => 1,
+            (Change::Inserted, None) => 2,
+        };
+
+        [
+            group.unwrap_or((input, key_index)),
+            (input, KeyIndex::MISSING),
+        ]
+        .into_iter()
+        .take(count)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InputChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InputChange {
+    pub input: InputIndex,
+    pub key: KeyIndex,
+    pub kind: InputChangeKind,
+}
+
+impl InputChange {
+    pub fn offset(self, offset: u16) -> Self {
+        Self {
+            input: self.input.offset(offset),
+            ..self
+        }
+    }
+}
+
+// Maps key indices from before a compaction to the ones after it. Keys that
+// were removed before the compaction are not mapped.
+#[derive(Debug, Default)]
+pub struct KeyRemap {
+    map: FxHashMap<(InputIndex, KeyIndex), KeyIndex>,
+}
+
+impl KeyRemap {
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Offsets the input indices of a part of a composite storage, see
+    // `Contains::INPUT_OFFSET`.
+    pub fn offset(self, offset: u16) -> Self {
+        Self {
+            map: self
+                .map
+                .into_iter()
+                .map(|((input, from), to)| ((input.offset(offset), from), to))
+                .collect(),
+        }
+    }
+
+    // `None` if the key was removed. The indices standing for keys that are
+    // not set and for keys of input groups stay the same.
+    pub(crate) fn get(
+        &self,
+        (input, key): (InputIndex, KeyIndex),
+    ) -> Option<(InputIndex, KeyIndex)> {
+        if key.is_storage_independent() {
+            Some((input, key))
+        } else {
+            self.map.get(&(input, key)).map(|key| (input, *key))
+        }
+    }
+}
+
+impl FromIterator<KeyRemap> for KeyRemap {
+    fn from_iter<T: IntoIterator<Item = KeyRemap>>(iter: T) -> Self {
+        Self {
+            map: iter.into_iter().flat_map(|remap| remap.map).collect(),
+        }
+    }
+}
+
+// An opaque token for something outside the input system (an environment
+// variable, a file modification time) that a query can depend on.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct KeyIndex(pub(crate) u32);
+pub struct ExternalId(pub u64);
+
+// Maps input keys to their key indices. The default is a hash map, ordered
+// inputs use a B-tree so that they can be queried by key ranges.
+pub trait KeyMap<K>: Default {
+    type Entry<'a>: KeyMapEntry
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<KeyIndex>;
+    fn entry(&mut self, key: K) -> Self::Entry<'_>;
+    fn remove(&mut self, key: &K) -> Option<KeyIndex>;
+    fn len(&self) -> usize;
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a;
+    fn indices_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut KeyIndex>
+    where
+        K: 'a;
+
+    fn get_or_insert_with<F: FnOnce() -> KeyIndex>(&mut self, key: K, f: F) -> KeyIndex {
+        self.entry(key).or_insert_with(f)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shrink_to_fit(&mut self) {}
+}
+
+// A key looked up in a key map, which is inserted only when needed.
+pub trait KeyMapEntry {
+    // `None` if the key is not in the map.
+    fn index(&self) -> Option<KeyIndex>;
+    fn or_insert_with<F: FnOnce() -> KeyIndex>(self, f: F) -> KeyIndex;
+}
+
+impl<K> KeyMapEntry for hash_map::Entry<'_, K, KeyIndex> {
+    fn index(&self) -> Option<KeyIndex> {
+        match self {
+            hash_map::Entry::Occupied(entry) => Some(*entry.get()),
+            hash_map::Entry::Vacant(_) => None,
+        }
+    }
+
+    fn or_insert_with<F: FnOnce() -> KeyIndex>(self, f: F) -> KeyIndex {
+        *hash_map::Entry::or_insert_with(self, f)
+    }
+}
+
+impl<K: Ord> KeyMapEntry for btree_map::Entry<'_, K, KeyIndex> {
+    fn index(&self) -> Option<KeyIndex> {
+        match self {
+            btree_map::Entry::Occupied(entry) => Some(*entry.get()),
+            btree_map::Entry::Vacant(_) => None,
+        }
+    }
+
+    fn or_insert_with<F: FnOnce() -> KeyIndex>(self, f: F) -> KeyIndex {
+        *btree_map::Entry::or_insert_with(self, f)
+    }
+}
+
+pub type HashKeyMap<K> = FxHashMap<K, KeyIndex>;
+pub type BTreeKeyMap<K> = BTreeMap<K, KeyIndex>;
+
+pub trait OrderedKeyMap<K>: KeyMap<K> {
+    fn range<'a, R: RangeBounds<K>>(&'a self, range: R) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a;
+}
+
+impl<K: Hash + Eq> KeyMap<K> for FxHashMap<K, KeyIndex> {
+    type Entry<'a>
+        = hash_map::Entry<'a, K, KeyIndex>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<KeyIndex> {
+        FxHashMap::get(self, key).copied()
+    }
+
+    fn entry(&mut self, key: K) -> Self::Entry<'_> {
+        FxHashMap::entry(self, key)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<KeyIndex> {
+        FxHashMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        FxHashMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a,
+    {
+        FxHashMap::iter(self).map(|(key, index)| (key, *index))
+    }
+
+    fn indices_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut KeyIndex>
+    where
+        K: 'a,
+    {
+        self.values_mut()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        FxHashMap::shrink_to_fit(self)
+    }
+}
+
+impl<K: Ord> KeyMap<K> for BTreeMap<K, KeyIndex> {
+    type Entry<'a>
+        = btree_map::Entry<'a, K, KeyIndex>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<KeyIndex> {
+        BTreeMap::get(self, key).copied()
+    }
+
+    fn entry(&mut self, key: K) -> Self::Entry<'_> {
+        BTreeMap::entry(self, key)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<KeyIndex> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a,
+    {
+        BTreeMap::iter(self).map(|(key, index)| (key, *index))
+    }
+
+    fn indices_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut KeyIndex>
+    where
+        K: 'a,
+    {
+        self.values_mut()
+    }
+}
+
+impl<K: Ord> OrderedKeyMap<K> for BTreeMap<K, KeyIndex> {
+    fn range<'a, R: RangeBounds<K>>(&'a self, range: R) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a,
+    {
+        BTreeMap::range(self, range).map(|(key, index)| (key, *index))
+    }
+}
+
+// Key map for small integer keys, which are used directly as the position in
+// a vector. Suited for dense keys: a key far beyond the number of keys in the
+// map is kept in a B-tree instead, so that the vector stays proportional to
+// the number of keys. All keys in the B-tree are past the end of the vector.
+#[derive(Debug, Clone)]
+pub struct VecKeyMap<K> {
+    slots: Vec<Option<(K, KeyIndex)>>,
+    sparse: BTreeMap<K, KeyIndex>,
+    len: usize,
+}
+
+// The position must be ordered the same as the keys.
+pub trait VecKey {
+    fn position(&self) -> usize;
+}
+
+macro_rules! impl_vec_key {
+    ($($ty:ty),*) => {
+        $(impl VecKey for $ty {
+            fn position(&self) -> usize {
+                *self as usize
+            }
+        })*
+    };
+}
+
+impl_vec_key!(u8, u16, u32, u64, usize);
+
+impl<K> Default for VecKeyMap<K> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            sparse: BTreeMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<K: VecKey + Ord> VecKeyMap<K> {
+    // The vector is grown to at most twice the number of keys.
+    const MIN_SLOTS: usize = 64;
+
+    fn is_dense(&self, position: usize) -> bool {
+        position < self.slots.len() || position < Self::MIN_SLOTS.max(2 * (self.len + 1))
+    }
+
+    // Grows the vector to contain the position and moves the keys from the
+    // B-tree that are now within it.
+    fn grow(&mut self, position: usize) {
+        if position < self.slots.len() {
+            return;
+        }
+
+        self.slots.resize_with(position + 1, || None);
+        while let Some(entry) = self.sparse.first_entry() {
+            let position = entry.key().position();
+            if position >= self.slots.len() {
+                break;
+            }
+            self.slots[position] = Some(entry.remove_entry());
+        }
+    }
+}
+
+// The vector is grown only when the key is inserted.
+pub enum VecKeyMapEntry<'a, K> {
+    Dense {
+        map: &'a mut VecKeyMap<K>,
+        key: K,
+    },
+    Sparse {
+        entry: btree_map::Entry<'a, K, KeyIndex>,
+        len: &'a mut usize,
+    },
+}
+
+impl<K: VecKey + Ord> KeyMapEntry for VecKeyMapEntry<'_, K> {
+    fn index(&self) -> Option<KeyIndex> {
+        match self {
+            VecKeyMapEntry::Dense { map, key } => map
+                .slots
+                .get(key.position())?
+                .as_ref()
+                .map(|(_, index)| *index),
+            VecKeyMapEntry::Sparse { entry, .. } => entry.index(),
+        }
+    }
+
+    fn or_insert_with<F: FnOnce() -> KeyIndex>(self, f: F) -> KeyIndex {
+        match self {
+            VecKeyMapEntry::Dense { map, key } => {
+                let position = key.position();
+                map.grow(position);
+
+                let slot = &mut map.slots[position];
+                if slot.is_none() {
+                    map.len += 1;
+                }
+
+                slot.get_or_insert_with(|| (key, f())).1
+            }
+            VecKeyMapEntry::Sparse { entry, len } => {
+                if let btree_map::Entry::Vacant(_) = entry {
+                    *len += 1;
+                }
+                KeyMapEntry::or_insert_with(entry, f)
+            }
+        }
+    }
+}
+
+impl<K: VecKey + Ord> KeyMap<K> for VecKeyMap<K> {
+    type Entry<'a>
+        = VecKeyMapEntry<'a, K>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<KeyIndex> {
+        match self.slots.get(key.position()) {
+            Some(slot) => slot.as_ref().map(|(_, index)| *index),
+            None => self.sparse.get(key).copied(),
+        }
+    }
+
+    fn entry(&mut self, key: K) -> Self::Entry<'_> {
+        let position = key.position();
+        if position >= self.slots.len()
+            && (!self.is_dense(position) || self.sparse.contains_key(&key))
+        {
+            return VecKeyMapEntry::Sparse {
+                entry: self.sparse.entry(key),
+                len: &mut self.len,
+            };
+        }
+
+        VecKeyMapEntry::Dense { map: self, key }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<KeyIndex> {
+        let index = match self.slots.get_mut(key.position()) {
+            Some(slot) => slot.take().map(|(_, index)| index),
+            None => self.sparse.remove(key),
+        }?;
+        self.len -= 1;
+        Some(index)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a,
+    {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.as_ref().map(|(key, index)| (key, *index)))
+            .chain(self.sparse.iter().map(|(key, index)| (key, *index)))
+    }
+
+    fn indices_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut KeyIndex>
+    where
+        K: 'a,
+    {
+        self.slots
+            .iter_mut()
+            .filter_map(|slot| slot.as_mut().map(|(_, index)| index))
+            .chain(self.sparse.values_mut())
+    }
+
+    fn shrink_to_fit(&mut self) {
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+        self.slots.shrink_to_fit();
+    }
+}
+
+impl<K: VecKey + Ord> OrderedKeyMap<K> for VecKeyMap<K> {
+    fn range<'a, R: RangeBounds<K>>(&'a self, range: R) -> impl Iterator<Item = (&'a K, KeyIndex)>
+    where
+        K: 'a,
+    {
+        self.iter().filter(move |(key, _)| range.contains(key))
+    }
+}
+
+// Maps the key indices to the values of an input. The default is a hash map,
+// the slots of the key indices are dense, so they can be used as positions in
+// a vector as well.
+pub trait Storage<K, V>: Default {
+    fn get(&self, key: &K) -> Option<&V>;
+    fn get_mut(&mut self, key: &K) -> Option<&mut V>;
+    // Returns the previous value.
+    fn set(&mut self, key: K, value: V) -> Option<V>;
+    fn remove(&mut self, key: &K) -> Option<V>;
+    fn len(&self) -> usize;
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The number of values the storage has room for.
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    fn with_capacity(_capacity: usize) -> Self {
+        Self::default()
+    }
+}
+
+pub type HashValues<V> = FxHashMap<KeyIndex, V>;
+pub type BTreeValues<V> = BTreeMap<KeyIndex, V>;
+
+impl<K: Hash + Eq, V> Storage<K, V> for FxHashMap<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        FxHashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        FxHashMap::get_mut(self, key)
+    }
+
+    fn set(&mut self, key: K, value: V) -> Option<V> {
+        FxHashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        FxHashMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        FxHashMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        FxHashMap::iter(self)
+    }
+
+    fn capacity(&self) -> usize {
+        FxHashMap::capacity(self)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        FxHashMap::with_capacity_and_hasher(capacity, Default::default())
+    }
+}
+
+impl<K: Ord, V> Storage<K, V> for BTreeMap<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        BTreeMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        BTreeMap::get_mut(self, key)
+    }
+
+    fn set(&mut self, key: K, value: V) -> Option<V> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        BTreeMap::iter(self)
+    }
+}
+
+// Values at the positions of the slots of their key indices.
+#[derive(Debug, Clone)]
+pub struct VecValues<V> {
+    slots: Vec<Option<(KeyIndex, V)>>,
+    len: usize,
+}
+
+impl<V> Default for VecValues<V> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<V> Storage<KeyIndex, V> for VecValues<V> {
+    fn get(&self, key: &KeyIndex) -> Option<&V> {
+        match self.slots.get(key.slot as usize)? {
+            Some((index, value)) if index == key => Some(value),
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, key: &KeyIndex) -> Option<&mut V> {
+        match self.slots.get_mut(key.slot as usize)? {
+            Some((index, value)) if index == key => Some(value),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, key: KeyIndex, value: V) -> Option<V> {
+        let position = key.slot as usize;
+        if position >= self.slots.len() {
+            self.slots.resize_with(position + 1, || None);
+        }
+
+        match self.slots[position].replace((key, value)) {
+            Some((index, previous)) if index == key => Some(previous),
+            Some(_) => None,
+            None => {
+                self.len += 1;
+                None
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &KeyIndex) -> Option<V> {
+        let slot = self.slots.get_mut(key.slot as usize)?;
+        if !matches!(slot, Some((index, _)) if index == key) {
+            return None;
+        }
+
+        self.len -= 1;
+        slot.take().map(|(_, value)| value)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a KeyIndex, &'a V)>
+    where
+        V: 'a,
+    {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.as_ref().map(|(index, value)| (index, value)))
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.capacity()
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: Vec::with_capacity(capacity),
+            len: 0,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct InputStorage<T: Input + ?Sized> {
-    index_map: FxHashMap<T::Key, KeyIndex>,
-    value_map: FxHashMap<KeyIndex, T::Value>,
-    key_index: u32,
+    index_map: T::KeyMap,
+    value_map: T::Values,
+    metadata_map: FxHashMap<KeyIndex, T::Metadata>,
+    // Incremented only when the value actually changes.
+    versions: FxHashMap<KeyIndex, u64>,
+    // Fingerprints of the values, if the input has them.
+    fingerprints: FxHashMap<KeyIndex, u64>,
+    free_slots: Vec<KeyIndex>,
+    next_slot: u32,
+    // Generation of keys in fresh slots, raised by the compaction so that the
+    // indices from before it never refer to a different key.
+    generation: u32,
 }
 
 impl<T: Input + ?Sized> InputStorage<T> {
@@ -32,26 +918,412 @@ impl<T: Input + ?Sized> InputStorage<T> {
     }
 
     pub fn get(&self, key: &T::Key) -> Option<(T::Value, KeyIndex)> {
-        self.index_map.get(key).map(|index| {
-            let value = self.value_map.get(index).unwrap().clone();
-            (value, *index)
-        })
+        let normalized = T::normalize_key(key);
+        let key = normalized.as_ref().unwrap_or(key);
+
+        self.index_map
+            .get(key)
+            .map(|index| {
+                let value = self.value_map.get(&index).unwrap().clone();
+                (value, index)
+            })
+            // The default value stands for any key that is not set.
+            .or_else(|| T::default_value().map(|value| (value, KeyIndex::MISSING)))
     }
 
     pub fn set(&mut self, key: T::Key, value: T::Value) -> KeyIndex {
-        let new_index = KeyIndex(self.key_index);
-        self.key_index += 1;
-        let index = *self.index_map.entry(key).or_insert(new_index);
-        self.value_map.insert(index, value);
-        index
+        self.set_changed(key, value).0
+    }
+
+    // Also returns whether the value differs from the previous one.
+    pub(crate) fn set_changed(&mut self, key: T::Key, value: T::Value) -> (KeyIndex, Change) {
+        let (index, change, _) = self.replace(key, value);
+        (index, change)
+    }
+
+    // Like `set_changed`, but also returns the previous value.
+    pub(crate) fn replace(
+        &mut self,
+        key: T::Key,
+        value: T::Value,
+    ) -> (KeyIndex, Change, Option<T::Value>) {
+        let key = T::normalize_key(&key).unwrap_or(key);
+        let free_slots = &mut self.free_slots;
+        let next_slot = &mut self.next_slot;
+        let generation = self.generation;
+
+        let index = self
+            .index_map
+            .get_or_insert_with(key, || allocate_slot(free_slots, next_slot, generation));
+
+        let fingerprint = T::fingerprint(&value);
+        let change = match self.value_map.get(&index) {
+            None => Change::Inserted,
+            Some(previous)
+                if !is_equal::<T>(self.fingerprints.get(&index), previous, &value, fingerprint) =>
+            {
+                Change::Changed
+            }
+            Some(_) => Change::Unchanged,
+        };
+
+        if change != Change::Unchanged {
+            *self.versions.entry(index).or_default() += 1;
+        }
+
+        if let Some(fingerprint) = fingerprint {
+            self.fingerprints.insert(index, fingerprint);
+        }
+
+        let previous = self.value_map.set(index, value);
+        (index, change, previous)
+    }
+
+    // Looks up the key once for reading and modifying its value.
+    pub fn entry(&mut self, key: T::Key) -> InputEntry<'_, T> {
+        let key = T::normalize_key(&key).unwrap_or(key);
+        let entry = self.index_map.entry(key);
+
+        InputEntry {
+            index: entry.index(),
+            entry,
+            value_map: &mut self.value_map,
+            versions: &mut self.versions,
+            fingerprints: &mut self.fingerprints,
+            free_slots: &mut self.free_slots,
+            next_slot: &mut self.next_slot,
+            generation: self.generation,
+            change: Change::Unchanged,
+        }
+    }
+
+    pub fn version(&self, key: &T::Key) -> Option<u64> {
+        let normalized = T::normalize_key(key);
+        let key = normalized.as_ref().unwrap_or(key);
+
+        self.index_map
+            .get(key)
+            .and_then(|index| self.versions.get(&index).copied())
     }
 
     pub fn remove(&mut self, key: &T::Key) -> Option<(T::Value, KeyIndex)> {
+        let normalized = T::normalize_key(key);
+        let key = normalized.as_ref().unwrap_or(key);
+
         self.index_map.remove(key).map(|index| {
             let value = self.value_map.remove(&index).unwrap();
+            self.metadata_map.remove(&index);
+            self.versions.remove(&index);
+            self.fingerprints.remove(&index);
+            self.free_slots.push(index);
             (value, index)
         })
     }
+
+    // The keys that are set with their values, in the order of the key map.
+    // The values are looked up by the key indices of the keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&T::Key, &T::Value)> {
+        self.index_map
+            .iter()
+            .map(|(key, index)| (key, self.value_map.get(&index).unwrap()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.index_map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index_map.is_empty()
+    }
+}
+
+// Values with different fingerprints are not compared in full.
+fn is_equal<T: Input + ?Sized>(
+    previous_fingerprint: Option<&u64>,
+    previous: &T::Value,
+    value: &T::Value,
+    fingerprint: Option<u64>,
+) -> bool {
+    match (previous_fingerprint, fingerprint) {
+        (Some(previous), Some(fingerprint)) if *previous != fingerprint => false,
+        _ => T::value_eq(previous, value),
+    }
+}
+
+// Reuses a free slot with the next generation, or takes a fresh one.
+fn allocate_slot(free_slots: &mut Vec<KeyIndex>, next_slot: &mut u32, generation: u32) -> KeyIndex {
+    match free_slots.pop() {
+        Some(free) => KeyIndex {
+            slot: free.slot,
+            generation: free.generation + 1,
+        },
+        None => {
+            let slot = *next_slot;
+            *next_slot += 1;
+            KeyIndex { slot, generation }
+        }
+    }
+}
+
+// Borrows the fields of the storage separately, so that the key map entry
+// found by the lookup is kept for the insertion.
+pub struct InputEntry<'a, T: Input + ?Sized + 'a> {
+    entry: <T::KeyMap as KeyMap<T::Key>>::Entry<'a>,
+    index: Option<KeyIndex>,
+    value_map: &'a mut T::Values,
+    versions: &'a mut FxHashMap<KeyIndex, u64>,
+    fingerprints: &'a mut FxHashMap<KeyIndex, u64>,
+    free_slots: &'a mut Vec<KeyIndex>,
+    next_slot: &'a mut u32,
+    generation: u32,
+    change: Change,
+}
+
+impl<T: Input + ?Sized> InputEntry<'_, T> {
+    // `None` if the key is not set.
+    pub fn key_index(&self) -> Option<KeyIndex> {
+        self.index
+    }
+
+    pub fn get(&self) -> Option<&T::Value> {
+        self.index.and_then(|index| self.value_map.get(&index))
+    }
+
+    // Modifies the value in place if the key is set. The version is
+    // incremented only if the modified value differs.
+    pub fn and_modify<F: FnOnce(&mut T::Value)>(mut self, f: F) -> Self {
+        if let Some(index) = self.index {
+            let value = self.value_map.get_mut(&index).unwrap();
+            let previous = value.clone();
+            f(value);
+
+            let fingerprint = T::fingerprint(value);
+            if !is_equal::<T>(self.fingerprints.get(&index), &previous, value, fingerprint) {
+                *self.versions.entry(index).or_default() += 1;
+                self.change = Change::Changed;
+            }
+
+            if let Some(fingerprint) = fingerprint {
+                self.fingerprints.insert(index, fingerprint);
+            }
+        }
+
+        self
+    }
+
+    pub fn or_insert(self, default: T::Value) -> KeyIndex {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> T::Value>(self, default: F) -> KeyIndex {
+        self.or_insert_with_changed(default).0
+    }
+
+    pub(crate) fn or_insert_with_changed<F: FnOnce() -> T::Value>(
+        self,
+        default: F,
+    ) -> (KeyIndex, Change) {
+        if let Some(index) = self.index {
+            return (index, self.change);
+        }
+
+        let (free_slots, next_slot, generation) =
+            (self.free_slots, self.next_slot, self.generation);
+        let index = self
+            .entry
+            .or_insert_with(|| allocate_slot(free_slots, next_slot, generation));
+
+        let value = default();
+        if let Some(fingerprint) = T::fingerprint(&value) {
+            self.fingerprints.insert(index, fingerprint);
+        }
+        *self.versions.entry(index).or_default() += 1;
+        self.value_map.set(index, value);
+
+        (index, Change::Inserted)
+    }
+
+    // The key index and the change made by `and_modify`, `None` if the key is
+    // not set.
+    pub(crate) fn modified(self) -> Option<(KeyIndex, Change)> {
+        self.index.map(|index| (index, self.change))
+    }
+}
+
+impl<T: Input + ?Sized> InputStorage<T> {
+    pub fn metadata(&self, key: &T::Key) -> Option<&T::Metadata> {
+        let normalized = T::normalize_key(key);
+        let key = normalized.as_ref().unwrap_or(key);
+
+        self.index_map
+            .get(key)
+            .and_then(|index| self.metadata_map.get(&index))
+    }
+
+    // The metadata can be set only for keys with a value. Returns whether the
+    // metadata was set.
+    pub fn set_metadata(&mut self, key: &T::Key, metadata: T::Metadata) -> bool {
+        let normalized = T::normalize_key(key);
+        let key = normalized.as_ref().unwrap_or(key);
+
+        match self.index_map.get(key) {
+            Some(index) => {
+                self.metadata_map.insert(index, metadata);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Renumbers the keys densely, dropping the slots of removed keys, and
+    // shrinks the maps. The dependencies recorded with the old key indices must
+    // be remapped, see `Runtime::compact`.
+    pub fn compact(&mut self) -> KeyRemap {
+        let input = InputIndex(T::INDEX);
+        let len = self.index_map.len();
+        let mut remap = KeyRemap::default();
+        let mut value_map = T::Values::with_capacity(len);
+        let mut metadata_map = FxHashMap::default();
+        let mut versions = FxHashMap::with_capacity_and_hasher(len, Default::default());
+        let mut fingerprints = FxHashMap::default();
+
+        let generation = self
+            .index_map
+            .iter()
+            .map(|(_, index)| index.generation)
+            .chain(self.free_slots.iter().map(|index| index.generation))
+            .fold(self.generation, u32::max)
+            + 1;
+
+        for (slot, index) in self.index_map.indices_mut().enumerate() {
+            let compacted = KeyIndex {
+                slot: slot as u32,
+                generation,
+            };
+
+            if let Some(value) = self.value_map.remove(index) {
+                value_map.set(compacted, value);
+            }
+            if let Some(metadata) = self.metadata_map.remove(index) {
+                metadata_map.insert(compacted, metadata);
+            }
+            if let Some(version) = self.versions.remove(index) {
+                versions.insert(compacted, version);
+            }
+            if let Some(fingerprint) = self.fingerprints.remove(index) {
+                fingerprints.insert(compacted, fingerprint);
+            }
+
+            remap.map.insert((input, *index), compacted);
+            *index = compacted;
+        }
+
+        metadata_map.shrink_to_fit();
+        fingerprints.shrink_to_fit();
+        self.index_map.shrink_to_fit();
+        self.value_map = value_map;
+        self.metadata_map = metadata_map;
+        self.versions = versions;
+        self.fingerprints = fingerprints;
+        self.free_slots = Vec::new();
+        self.next_slot = len as u32;
+        self.generation = generation;
+
+        remap
+    }
+
+    // Keys added, removed or with a different value compared to the old storage,
+    // which must be an earlier state of this one (e.g., from a snapshot). A key
+    // removed and set again is reported as removed and added. The key indices
+    // are not comparable across a compaction.
+    pub fn diff(&self, old: &Self) -> Vec<InputChange> {
+        let input = InputIndex(T::INDEX);
+        let change = |key, kind| InputChange { input, key, kind };
+
+        let mut changes = self
+            .value_map
+            .iter()
+            .filter_map(|(index, value)| match old.value_map.get(index) {
+                None => Some(change(*index, InputChangeKind::Added)),
+                // The version tells that the value was set, but it may have
+                // been set back to the old value since.
+                Some(old_value)
+                    if self.versions.get(index) != old.versions.get(index)
+                        && !T::value_eq(old_value, value) =>
+                {
+                    Some(change(*index, InputChangeKind::Changed))
+                }
+                Some(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        changes.extend(
+            old.value_map
+                .iter()
+                .filter(|(index, _)| self.value_map.get(index).is_none())
+                .map(|(index, _)| change(*index, InputChangeKind::Removed)),
+        );
+
+        changes.sort();
+        changes
+    }
+
+    // The dependencies a query records when it reads the keys that are set.
+    // Keys of inputs in a group are represented by the group dependency.
+    pub fn dependencies(&self) -> impl Iterator<Item = (InputIndex, KeyIndex)> + '_ {
+        self.index_map
+            .iter()
+            .map(|(key, index)| group_dependency::<T>(key).unwrap_or((InputIndex(T::INDEX), index)))
+    }
+
+    // Counts only the inline size of keys and values, not memory they own.
+    pub fn approx_memory_bytes(&self) -> usize {
+        let key_entry = mem::size_of::<T::Key>() + mem::size_of::<KeyIndex>();
+        let value_entry = mem::size_of::<KeyIndex>() + mem::size_of::<T::Value>();
+        let metadata_entry = mem::size_of::<KeyIndex>() + mem::size_of::<T::Metadata>();
+
+        self.index_map.len() * key_entry
+            + self.value_map.capacity() * value_entry
+            + self.metadata_map.capacity() * metadata_entry
+            + self.versions.capacity() * mem::size_of::<(KeyIndex, u64)>()
+            + self.fingerprints.capacity() * mem::size_of::<(KeyIndex, u64)>()
+            + self.free_slots.capacity() * mem::size_of::<KeyIndex>()
+    }
+}
+
+impl<T: Input + ?Sized> InputStorage<T>
+where
+    T::KeyMap: OrderedKeyMap<T::Key>,
+{
+    // The range bounds are not normalized.
+    pub fn range<R: RangeBounds<T::Key>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (&T::Key, &T::Value, KeyIndex)> {
+        self.index_map
+            .range(range)
+            .map(|(key, index)| (key, self.value_map.get(&index).unwrap(), index))
+    }
+}
+
+impl<T: Input + ?Sized> Clone for InputStorage<T>
+where
+    T::KeyMap: Clone,
+    T::Values: Clone,
+    T::Metadata: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            index_map: self.index_map.clone(),
+            value_map: self.value_map.clone(),
+            metadata_map: self.metadata_map.clone(),
+            versions: self.versions.clone(),
+            fingerprints: self.fingerprints.clone(),
+            free_slots: self.free_slots.clone(),
+            next_slot: self.next_slot,
+            generation: self.generation,
+        }
+    }
 }
 
 impl<T: Input + ?Sized> Default for InputStorage<T> {
@@ -59,7 +1331,99 @@ impl<T: Input + ?Sized> Default for InputStorage<T> {
         Self {
             index_map: Default::default(),
             value_map: Default::default(),
-            key_index: 0,
+            metadata_map: Default::default(),
+            versions: Default::default(),
+            fingerprints: Default::default(),
+            free_slots: Vec::new(),
+            next_slot: 0,
+            generation: 0,
+        }
+    }
+}
+
+// The storage is persisted with the key indices, so that they stay the same
+// after it is restored. The fingerprints are computed again.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredInput<K, V, M> {
+    entries: Vec<StoredEntry<K, V, M>>,
+    free_slots: Vec<KeyIndex>,
+    next_slot: u32,
+    generation: u32,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredEntry<K, V, M> {
+    key: K,
+    index: KeyIndex,
+    value: V,
+    version: u64,
+    metadata: Option<M>,
+}
+
+#[cfg(feature = "serde")]
+impl<T: Input + ?Sized> serde::Serialize for InputStorage<T>
+where
+    T::Key: serde::Serialize,
+    T::Value: serde::Serialize,
+    T::Metadata: serde::Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries = self
+            .index_map
+            .iter()
+            .map(|(key, index)| StoredEntry {
+                key,
+                index,
+                value: self.value_map.get(&index).unwrap(),
+                version: self.versions.get(&index).copied().unwrap_or_default(),
+                metadata: self.metadata_map.get(&index),
+            })
+            .collect();
+
+        StoredInput {
+            entries,
+            free_slots: self.free_slots.clone(),
+            next_slot: self.next_slot,
+            generation: self.generation,
         }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Input + ?Sized> serde::Deserialize<'de> for InputStorage<T>
+where
+    T::Key: serde::Deserialize<'de>,
+    T::Value: serde::Deserialize<'de>,
+    T::Metadata: serde::Deserialize<'de>,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stored = StoredInput::<T::Key, T::Value, T::Metadata>::deserialize(deserializer)?;
+        let mut storage = Self {
+            free_slots: stored.free_slots,
+            next_slot: stored.next_slot,
+            generation: stored.generation,
+            ..Self::default()
+        };
+
+        for entry in stored.entries {
+            storage
+                .index_map
+                .get_or_insert_with(entry.key, || entry.index);
+            if let Some(fingerprint) = T::fingerprint(&entry.value) {
+                storage.fingerprints.insert(entry.index, fingerprint);
+            }
+            storage.value_map.set(entry.index, entry.value);
+            if entry.version > 0 {
+                storage.versions.insert(entry.index, entry.version);
+            }
+            if let Some(metadata) = entry.metadata {
+                storage.metadata_map.insert(entry.index, metadata);
+            }
+        }
+
+        Ok(storage)
     }
 }
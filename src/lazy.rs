@@ -0,0 +1,46 @@
+use std::{fmt, sync::OnceLock};
+
+use parking_lot::Mutex;
+
+type Thunk<O> = Box<dyn FnOnce() -> O + Send>;
+
+// Output of a query that is materialized only when it is first used, see
+// `QueryCache::insert_lazy`.
+pub struct Lazy<O> {
+    cell: OnceLock<O>,
+    thunk: Mutex<Option<Thunk<O>>>,
+}
+
+impl<O> Lazy<O> {
+    pub fn new(thunk: impl FnOnce() -> O + Send + 'static) -> Self {
+        Self {
+            cell: OnceLock::new(),
+            thunk: Mutex::new(Some(Box::new(thunk))),
+        }
+    }
+
+    pub fn get(&self) -> &O {
+        self.cell.get_or_init(|| {
+            // The thunk is gone only if it panicked in an earlier call.
+            let thunk = self
+                .thunk
+                .lock()
+                .take()
+                .expect("lazy value poisoned: its thunk panicked");
+            thunk()
+        })
+    }
+
+    pub fn is_evaluated(&self) -> bool {
+        self.cell.get().is_some()
+    }
+}
+
+impl<O: fmt::Debug> fmt::Debug for Lazy<O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cell.get() {
+            Some(output) => f.debug_tuple("Lazy").field(output).finish(),
+            None => write!(f, "Lazy(<unevaluated>)"),
+        }
+    }
+}
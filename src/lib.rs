@@ -1,12 +1,17 @@
 pub(crate) mod hash;
 pub mod input;
+pub mod lazy;
 pub mod query;
 pub(crate) mod query_stack;
 pub mod revision;
 pub mod runtime;
 
 pub use input::{Input, InputStorage};
-pub use macros::database;
-pub use query::{QueryCache, QueryContext};
-pub use query_stack::Cycle;
+pub use macros::{database, param, query_system};
+pub use query::{Query, QueryCache, QueryContext};
+pub use query_stack::{Cycle, QueryError};
 pub use runtime::Runtime;
+
+// Used by the storages generated by `database`.
+#[cfg(feature = "serde")]
+pub use serde;
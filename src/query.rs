@@ -1,129 +1,2427 @@
 use core::fmt;
 use std::{
     any::{Any, TypeId},
-    hash::Hash,
+    cell::RefCell,
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap,
+    },
+    hash::{BuildHasher, Hash, Hasher},
+    marker::PhantomData,
+    mem,
+    ops::{Deref, RangeBounds},
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
     },
+    thread::{self, ThreadId},
+    time::Instant,
 };
 
-use rustc_hash::FxHashMap;
+use parking_lot::{Condvar, Mutex, RwLock};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 
 use crate::{
     hash::{FxDashMap, FxDashSet},
-    input::{Input, InputIndex, KeyIndex},
+    input::{ExternalId, InputIndex, InputOf, KeyIndex, KeyRemap, OrderedKeyMap, StorageGroup},
+    lazy::Lazy,
     revision::Revision,
-    runtime::Runtime,
-    Cycle,
+    runtime::{Runtime, StaleDependency, YieldBudget},
+    Cycle, QueryError,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct QueryId(pub(crate) u32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QueryId(pub(crate) u32);
+
+// Identifies a query by the name of its type and its parameter. Unlike the
+// query id, it stays the same across caches and runs of the same build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StableQueryHandle(pub u64);
+
+// Describes a query generated by the `query_system!` macro, for tools that
+// enumerate the queries of a system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryInfo {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    pub output: &'static str,
+}
+
+pub struct QueryCache<K> {
+    id_map: FxDashMap<QueryType, HashMap<K, QueryId, ParamHasher>>,
+    param_hasher: ParamHasher,
+    query_map: FxDashMap<QueryId, QueryData>,
+    // Queries that used given input key. May contain queries that were evicted
+    // or recomputed with different dependencies since, which are filtered out
+    // when the index is read.
+    reverse_deps: FxDashMap<(InputIndex, KeyIndex), FxHashSet<QueryId>>,
+    // Queries called by the queries being computed, recorded when the called
+    // query is found in the cache or inserted while the caller is on the stack.
+    // Shared with the context of the caller, so that the calls are rolled back
+    // with its other dependencies.
+    pending_query_deps: FxDashMap<QueryId, Arc<FxDashSet<QueryId>>>,
+    flights: Mutex<FxHashMap<QueryId, Arc<Flight>>>,
+    // The queries the threads wait for to be computed by other threads. Locked
+    // only while holding the lock of the flights.
+    waiting: Mutex<FxHashMap<ThreadId, QueryId>>,
+    // Queries whose outputs were recovered from a cycle, see
+    // `QueryCache::try_insert_with_recovery`.
+    recovered: FxDashSet<QueryId>,
+    derived_map: FxDashMap<(QueryId, TypeId), DerivedData>,
+    heap_size_hooks: FxDashMap<QueryType, HeapSizeHook>,
+    cycle_exempt: FxDashSet<QueryType>,
+    volatile: FxDashSet<QueryType>,
+    recompute_counts: FxDashMap<QueryType, u64>,
+    handle_map: FxDashMap<StableQueryHandle, QueryId>,
+    param_normalizers: FxDashMap<QueryType, ParamNormalizer<K>>,
+    output_eqs: FxDashMap<QueryType, OutputEq>,
+    cache_predicates: FxDashMap<QueryType, CachePredicate>,
+    cycle_policies: FxDashMap<QueryType, Box<dyn Any + Send + Sync>>,
+    // Fixpoint iterations in progress, keyed by the query at their head.
+    fixpoints: FxDashMap<QueryId, FixpointState>,
+    secondary: RwLock<Option<Box<dyn SecondaryStore>>>,
+    codecs: FxDashMap<QueryType, Codec>,
+    // Queries evicted from memory whose outputs can be restored from the
+    // secondary store.
+    spilled: FxDashMap<QueryId, SpilledData>,
+    recompute_fns: FxDashMap<QueryType, RecomputeFn<K>>,
+    invalidation_observers: Mutex<FxHashMap<u64, InvalidationObserver>>,
+    observer_id: AtomicU64,
+    // Labels of the queries from their last computation.
+    labels: FxDashMap<QueryId, Box<str>>,
+    eviction: EvictionPolicy,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    recomputations: AtomicU64,
+    evicted: AtomicU64,
+    invalidation_logger: RwLock<Option<InvalidationLogger<K>>>,
+    observer: RwLock<Option<Arc<dyn CacheObserver>>>,
+    // Set by `render_cycle_participants`, which can require a debuggable param.
+    render_participants: RwLock<Option<RenderParticipants<K>>>,
+    soft_limit: RwLock<Option<SoftLimitState>>,
+    inserts: AtomicU64,
+    #[cfg(feature = "test-hooks")]
+    test_hook: RwLock<Option<TestHook>>,
+    // Logical clock for tracking the least recently used outputs.
+    tick: AtomicU64,
+    query_id: AtomicU32,
+    // Derive the query ids from the query type and param instead of allocating
+    // them in order, see `QueryCacheBuilder::deterministic_ids`.
+    deterministic_ids: bool,
+    allocated_ids: FxDashSet<QueryId>,
+    limiter: Option<ComputeLimiter>,
+}
+
+type InvalidationLogger<K> = Box<dyn Fn(&K, QueryType, Revision, StaleDependency) + Send + Sync>;
+// The soft limit is checked only every this many inserts.
+const SOFT_LIMIT_INTERVAL: u64 = 64;
+
+struct SoftLimitState {
+    limit: SoftLimit,
+    callback: Box<dyn Fn(&SoftLimitExceeded) + Send + Sync>,
+    last_size: AtomicUsize,
+}
+
+#[cfg(feature = "test-hooks")]
+type TestHook = Box<dyn Fn(HookPoint, QueryId) + Send + Sync>;
+type OutputEq =
+    Box<dyn Fn(&(dyn Any + Send + Sync), &(dyn Any + Send + Sync)) -> bool + Send + Sync>;
+type RecomputeFn<K> = Arc<dyn Fn(&QueryCache<K>, &dyn Any, &K) + Send + Sync>;
+type CachePredicate = Box<dyn Fn(&(dyn Any + Send + Sync)) -> bool + Send + Sync>;
+type ParamNormalizer<K> = Box<dyn Fn(&K) -> K + Send + Sync>;
+type HeapSizeHook = Box<dyn Fn(&(dyn Any + Send + Sync)) -> usize + Send + Sync>;
+type Recover<'a, K, O> = Box<dyn FnOnce(&Cycle, &K) -> O + 'a>;
+type RenderParticipants<K> = fn(&QueryCache<K>, Cycle) -> Cycle;
+
+struct QueryData {
+    output: Arc<dyn Any + Send + Sync>,
+    output_size: usize,
+    output_type: TypeId,
+    ty: QueryType,
+    valid_at: Revision,
+    // The revision at which the output last changed.
+    changed_at: Revision,
+    dependencies: Vec<(InputIndex, KeyIndex)>,
+    external_dependencies: Vec<ExternalId>,
+    // Queries of the same cache called during the computation.
+    query_dependencies: Vec<QueryId>,
+    last_used: AtomicU64,
+}
+
+// The memoized outputs of a cache without its params, so that they can be
+// borrowed by the contexts of the queries regardless of the param type.
+#[derive(Clone, Copy)]
+struct Memos<'a> {
+    query_map: &'a FxDashMap<QueryId, QueryData>,
+    spilled: &'a FxDashMap<QueryId, SpilledData>,
+    recovered: &'a FxDashSet<QueryId>,
+}
+
+// The last memoized run of the query being recomputed. It is looked up only
+// when the query asks for it.
+#[derive(Clone, Copy)]
+struct PreviousRun<'r> {
+    query_id: QueryId,
+    // Tells the run apart from a recovered output inserted during the
+    // computation.
+    valid_at: Revision,
+    memos: Memos<'r>,
+}
+
+impl<'r> PreviousRun<'r> {
+    fn data(&self) -> Option<impl Deref<Target = QueryData> + 'r> {
+        self.memos
+            .query_map
+            .get(&self.query_id)
+            .filter(|data| data.valid_at == self.valid_at)
+    }
+}
+
+impl Memos<'_> {
+    // The last revision at which any of the queries, or the queries they
+    // called, changed. A query that was recomputed to an equal output (see
+    // `QueryCache::set_output_eq`) changed when the output last changed. For
+    // a stale query, it is unknown whether its output would change, so it
+    // changed when its dependency did. The same applies to evicted queries,
+    // which are considered changed in the current revision.
+    fn queries_changed_at<I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_ids: &[QueryId],
+        memo: &mut FxHashMap<QueryId, Revision>,
+    ) -> Revision {
+        let mut changed_at = Revision::default();
+
+        for query_id in query_ids {
+            if let Some(known) = memo.get(query_id) {
+                changed_at = changed_at.max(*known);
+                continue;
+            }
+
+            // Queries on a cycle do not count themselves.
+            memo.insert(*query_id, Revision::default());
+
+            let (inputs_rev, valid_at, output_changed_at, called) =
+                match self.query_map.get(query_id) {
+                    Some(data) => (
+                        self.inputs_rev(
+                            runtime,
+                            *query_id,
+                            &data.dependencies,
+                            &data.external_dependencies,
+                        ),
+                        data.valid_at,
+                        data.changed_at,
+                        data.query_dependencies.clone(),
+                    ),
+                    None => match self.spilled.get(query_id) {
+                        Some(data) => (
+                            self.inputs_rev(
+                                runtime,
+                                *query_id,
+                                &data.dependencies,
+                                &data.external_dependencies,
+                            ),
+                            data.valid_at,
+                            data.changed_at,
+                            data.query_dependencies.clone(),
+                        ),
+                        None => {
+                            memo.insert(*query_id, runtime.rev());
+                            return runtime.rev();
+                        }
+                    },
+                };
+
+            let dependencies_rev = inputs_rev.max(self.queries_changed_at(runtime, &called, memo));
+            let query_changed_at = if dependencies_rev > valid_at {
+                dependencies_rev
+            } else {
+                output_changed_at
+            };
+
+            memo.insert(*query_id, query_changed_at);
+            changed_at = changed_at.max(query_changed_at);
+        }
+
+        changed_at
+    }
+
+    // Whether a dependency of the cached query changed since it was computed,
+    // `None` if the query is not cached.
+    // Recovered outputs have no dependencies, they are invalidated by any
+    // change instead.
+    fn inputs_rev<I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_id: QueryId,
+        dependencies: &[(InputIndex, KeyIndex)],
+        external_dependencies: &[ExternalId],
+    ) -> Revision {
+        if self.recovered.contains(&query_id) {
+            runtime.rev()
+        } else {
+            runtime.last_rev_of(dependencies, external_dependencies)
+        }
+    }
+}
+
+struct InvalidationObserver {
+    handle: StableQueryHandle,
+    callback: Arc<dyn Fn(QueryId) + Send + Sync>,
+    // The observer is notified once per computation of the query.
+    notified_at: Option<Revision>,
+}
+
+struct SpilledData {
+    output_size: usize,
+    output_type: TypeId,
+    ty: QueryType,
+    valid_at: Revision,
+    changed_at: Revision,
+    dependencies: Vec<(InputIndex, KeyIndex)>,
+    external_dependencies: Vec<ExternalId>,
+    query_dependencies: Vec<QueryId>,
+}
+
+struct Codec {
+    encode: Encode,
+    decode: Decode,
+}
+
+type Encode = Box<dyn Fn(&(dyn Any + Send + Sync)) -> Option<Vec<u8>> + Send + Sync>;
+type Decode = Box<dyn Fn(&[u8]) -> Option<Arc<dyn Any + Send + Sync>> + Send + Sync>;
+
+// Bounds the number of queries computed at once, see
+// `QueryCacheBuilder::max_concurrency`.
+struct ComputeLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+thread_local! {
+    // Limiters whose permit is held by the current thread.
+    static HELD_PERMITS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+impl ComputeLimiter {
+    fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    // Nested queries are computed under the permit of the outermost one, taking
+    // another permit could deadlock when all are held by the outer queries.
+    fn acquire(&self) -> Option<ComputePermit<'_>> {
+        let address = self as *const Self as usize;
+
+        if HELD_PERMITS.with(|held| held.borrow().contains(&address)) {
+            return None;
+        }
+
+        let mut available = self.available.lock();
+        while *available == 0 {
+            self.released.wait(&mut available);
+        }
+        *available -= 1;
+
+        HELD_PERMITS.with(|held| held.borrow_mut().push(address));
+        Some(ComputePermit { limiter: self })
+    }
+}
+
+struct ComputePermit<'a> {
+    limiter: &'a ComputeLimiter,
+}
+
+impl Drop for ComputePermit<'_> {
+    fn drop(&mut self) {
+        let address = self.limiter as *const ComputeLimiter as usize;
+        HELD_PERMITS.with(|held| held.borrow_mut().retain(|other| *other != address));
+
+        *self.limiter.available.lock() += 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+// A computation of a query in progress, which other threads computing the same
+// query wait for instead of computing it again.
+struct Flight {
+    owner: ThreadId,
+    landed: Mutex<bool>,
+    landed_signal: Condvar,
+}
+
+impl Flight {
+    fn wait(&self) {
+        let mut landed = self.landed.lock();
+        while !*landed {
+            self.landed_signal.wait(&mut landed);
+        }
+    }
+}
+
+// Removes the flight when the computation finishes, fails or panics, and wakes
+// up the waiting threads.
+struct FlightGuard<'a> {
+    flights: &'a Mutex<FxHashMap<QueryId, Arc<Flight>>>,
+    query_id: QueryId,
+    flight: Arc<Flight>,
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        self.flights.lock().remove(&self.query_id);
+        *self.flight.landed.lock() = true;
+        self.flight.landed_signal.notify_all();
+    }
+}
+
+struct FixpointState {
+    approximation: Arc<dyn Any + Send + Sync>,
+    // Whether the current iteration used the approximation.
+    reentered: bool,
+    // Queries computed during the current iteration, which may be based on the
+    // approximation.
+    computed: Vec<QueryId>,
+}
+
+struct DerivedData {
+    // The weak reference keeps the allocation alive, so the pointer can't be
+    // reused by a different output.
+    source: Weak<dyn Any + Send + Sync>,
+    output: Arc<dyn Any + Send + Sync>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct QueryType {
+    type_id: TypeId,
+    // For debugging purposes.
+    name: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoftLimit {
+    Entries(usize),
+    // Approximate memory of the cache.
+    Bytes(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftLimitExceeded {
+    pub limit: SoftLimit,
+    // Current size in the units of the limit.
+    pub size: usize,
+    // Growth of the size since the last check.
+    pub growth: isize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPoint {
+    // Before waiting for a permit, see `QueryCacheBuilder::max_concurrency`.
+    BeforeAcquire,
+    BeforeCompute,
+    // After the query is computed, but before its output is inserted.
+    BeforeInsert,
+    // Before waiting for the computation of the query by another thread.
+    BeforeWait,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalidation {
+    pub query_name: &'static str,
+    pub param_debug: String,
+    pub valid_at: Revision,
+    pub dependency: StaleDependency,
+}
+
+// The cached output has a different type than requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DowncastError {
+    pub query_name: &'static str,
+    pub expected: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMiss {
+    // The query was not computed yet or its output was evicted.
+    Missing,
+    // The output was invalidated by a change of its dependencies.
+    Stale,
+}
+
+// A query registered as a type rather than a closure, see
+// `QueryCache::execute`. The type of the query identifies it in the cache.
+pub trait Query<I>: 'static {
+    type Param;
+    type Output: Send + Sync + 'static;
+
+    fn run(&self, param: &Self::Param, ctx: &QueryContext<'_, I>) -> Self::Output;
+}
+
+// A second tier of the cache (e.g., on disk) for outputs that are evicted from
+// memory. Only outputs of query types with a registered codec are stored.
+pub trait SecondaryStore: Send + Sync {
+    fn get(&self, id: QueryId) -> Option<Vec<u8>>;
+    fn put(&self, id: QueryId, bytes: Vec<u8>);
+}
+
+// Notified about the cache events, see `QueryCache::set_observer`. The methods
+// are called without holding any lock of the cache, so they may query it.
+pub trait CacheObserver: Send + Sync {
+    fn on_hit(&self, _id: QueryId) {}
+    // Queries that were never computed have no id yet, so their misses are not
+    // reported, only their inserts.
+    fn on_miss(&self, _id: QueryId) {}
+    fn on_insert(&self, _id: QueryId) {}
+    fn on_evict(&self, _id: QueryId) {}
+}
+
+// What happens when the query is re-entered while it is being computed.
+#[derive(Debug, Clone)]
+pub enum CyclePolicy<O> {
+    // The cycle is returned as an error.
+    Error,
+    // The re-entrant call returns the recovery value, which is not cached.
+    Recover(fn(&Cycle) -> O),
+    // The re-entrant call returns an approximation of the output, starting at
+    // `bottom`. The query is recomputed with the approximation joined with the
+    // last output until they are equal. Requires the query to be computed by
+    // `try_insert_fixpoint`, otherwise behaves like `Error`.
+    Fixpoint { bottom: O, join: fn(&O, &O) -> O },
+}
+
+type DependencyCallback = Box<dyn Fn(InputIndex, KeyIndex, &'static str) + Send + Sync>;
+type OutputFn<O, R> = Box<dyn Fn(&O) -> R + Send + Sync>;
+type TypedOutputEq<O> = Box<dyn Fn(&O, &O) -> bool + Send + Sync>;
+
+// The settings of a query type registered at once by `QueryCache::configure`,
+// instead of by the individual setters.
+pub struct QueryConfig<O> {
+    cycle_exempt: bool,
+    volatile: bool,
+    cycle_policy: Option<CyclePolicy<O>>,
+    output_eq: Option<TypedOutputEq<O>>,
+    cache_predicate: Option<OutputFn<O, bool>>,
+    heap_size: Option<OutputFn<O, usize>>,
+}
+
+impl<O> QueryConfig<O> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // See `QueryCache::set_cycle_exempt`.
+    pub fn cycle_exempt(mut self) -> Self {
+        self.cycle_exempt = true;
+        self
+    }
+
+    // See `QueryCache::set_volatile`.
+    pub fn volatile(mut self) -> Self {
+        self.volatile = true;
+        self
+    }
+
+    // See `QueryCache::set_cycle_policy`.
+    pub fn cycle_policy(mut self, policy: CyclePolicy<O>) -> Self {
+        self.cycle_policy = Some(policy);
+        self
+    }
+
+    // See `QueryCache::set_output_eq`.
+    pub fn output_eq(mut self, eq: impl Fn(&O, &O) -> bool + Send + Sync + 'static) -> Self {
+        self.output_eq = Some(Box::new(eq));
+        self
+    }
+
+    // See `QueryCache::set_cache_predicate`.
+    pub fn cache_predicate(
+        mut self,
+        predicate: impl Fn(&O) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.cache_predicate = Some(Box::new(predicate));
+        self
+    }
+
+    // See `QueryCache::set_heap_size`.
+    pub fn heap_size(mut self, heap_size: impl Fn(&O) -> usize + Send + Sync + 'static) -> Self {
+        self.heap_size = Some(Box::new(heap_size));
+        self
+    }
+}
+
+impl<O> Default for QueryConfig<O> {
+    fn default() -> Self {
+        Self {
+            cycle_exempt: false,
+            volatile: false,
+            cycle_policy: None,
+            output_eq: None,
+            cache_predicate: None,
+            heap_size: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // The cache is unbounded.
+    None,
+    // Keeps at most given number of outputs, evicting the least recently used.
+    Lru(usize),
+    // Keeps the approximate memory of the cache under given number of bytes,
+    // evicting the least recently used outputs.
+    Bytes(usize),
+    // Keeps the outputs only while they are referenced outside of the cache,
+    // evicting the others whenever a query is inserted.
+    Weak,
+}
+
+// Hashes the params of the queries when looking up their ids.
+#[derive(Debug, Clone, Default)]
+pub enum ParamHasher {
+    // Fast, but the params can be chosen so that their hashes clash.
+    #[default]
+    Fx,
+    // Randomly keyed, for params coming from untrusted sources.
+    Random(RandomState),
+}
+
+impl BuildHasher for ParamHasher {
+    type Hasher = ParamHasherState;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        match self {
+            ParamHasher::Fx => ParamHasherState::Fx(FxHasher::default()),
+            ParamHasher::Random(state) => ParamHasherState::Random(state.build_hasher()),
+        }
+    }
+}
+
+pub enum ParamHasherState {
+    Fx(FxHasher),
+    Random(DefaultHasher),
+}
+
+impl Hasher for ParamHasherState {
+    fn finish(&self) -> u64 {
+        match self {
+            ParamHasherState::Fx(hasher) => hasher.finish(),
+            ParamHasherState::Random(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            ParamHasherState::Fx(hasher) => hasher.write(bytes),
+            ParamHasherState::Random(hasher) => hasher.write(bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    // Lookups of a valid output, see `QueryCache::cached`.
+    pub hits: u64,
+    // Lookups of an output that is missing or stale.
+    pub misses: u64,
+    // Number of outputs held in memory.
+    pub entries: usize,
+    // Number of computations of the queries, see `QueryCache::try_insert_with`.
+    pub recomputations: u64,
+    // Number of outputs evicted by the eviction policy since the cache was
+    // created.
+    pub evicted: u64,
+}
+
+pub struct QueryCacheBuilder<K> {
+    eviction: EvictionPolicy,
+    hasher: ParamHasher,
+    deterministic_ids: bool,
+    max_concurrency: Option<usize>,
+    phantom: PhantomData<K>,
+}
+
+impl<K> QueryCacheBuilder<K> {
+    pub fn eviction(mut self, eviction: EvictionPolicy) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    pub fn hasher(mut self, hasher: ParamHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    // The query ids are derived from the query type and param, so the same
+    // query gets the same id regardless of the order of computation and across
+    // `QueryCache::clear`. The ids of queries whose hashes clash depend on the
+    // order in which they were first computed. `QueryCache::rekey` is not
+    // supported then.
+    pub fn deterministic_ids(mut self, deterministic: bool) -> Self {
+        self.deterministic_ids = deterministic;
+        self
+    }
+
+    // Limits how many queries are computed at once, the others wait until one
+    // of them finishes. This bounds the compute concurrency (e.g., of parallel
+    // requests), unlike the input lock, which only separates the queries from
+    // input changes. Nested queries do not count against the limit.
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
+    pub fn build(self) -> QueryCache<K> {
+        QueryCache {
+            eviction: self.eviction,
+            param_hasher: self.hasher,
+            deterministic_ids: self.deterministic_ids,
+            limiter: self.max_concurrency.map(ComputeLimiter::new),
+            ..Default::default()
+        }
+    }
+}
+
+impl<K> QueryCache<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A cache keeping at most given number of outputs, see
+    // `EvictionPolicy::Lru`.
+    pub fn with_capacity(max_entries: usize) -> Self {
+        Self::builder()
+            .eviction(EvictionPolicy::Lru(max_entries))
+            .build()
+    }
+
+    pub fn builder() -> QueryCacheBuilder<K> {
+        QueryCacheBuilder {
+            eviction: EvictionPolicy::None,
+            hasher: ParamHasher::Fx,
+            deterministic_ids: false,
+            max_concurrency: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<K> QueryCache<K> {
+    // Registers all settings of given query type. The settings that are not
+    // set in the config are left as they are.
+    pub fn configure<Q: 'static, O: Send + Sync + 'static>(&self, config: QueryConfig<O>) {
+        if config.cycle_exempt {
+            self.set_cycle_exempt::<Q>();
+        }
+        if config.volatile {
+            self.set_volatile::<Q>();
+        }
+        if let Some(policy) = config.cycle_policy {
+            self.set_cycle_policy::<Q, O>(policy);
+        }
+        if let Some(eq) = config.output_eq {
+            self.set_output_eq::<Q, O>(eq);
+        }
+        if let Some(predicate) = config.cache_predicate {
+            self.set_cache_predicate::<Q, O>(predicate);
+        }
+        if let Some(heap_size) = config.heap_size {
+            self.set_heap_size::<Q, O>(heap_size);
+        }
+    }
+
+    // Registers a function that computes the heap memory owned by the outputs
+    // of given query type. Without it, only the inline size of the output is
+    // counted.
+    pub fn set_heap_size<Q: 'static, O: 'static>(
+        &self,
+        heap_size: impl Fn(&O) -> usize + Send + Sync + 'static,
+    ) {
+        self.heap_size_hooks.insert(
+            QueryType::of::<Q>(),
+            Box::new(move |output| output.downcast_ref().map(&heap_size).unwrap_or_default()),
+        );
+    }
+
+    // Invokes the callback when the cache grows over the limit, instead of
+    // evicting anything. The limit is checked periodically, not on every
+    // insert.
+    pub fn set_soft_limit(
+        &self,
+        limit: SoftLimit,
+        callback: impl Fn(&SoftLimitExceeded) + Send + Sync + 'static,
+    ) {
+        *self.soft_limit.write() = Some(SoftLimitState {
+            limit,
+            callback: Box::new(callback),
+            last_size: AtomicUsize::new(0),
+        });
+    }
+
+    fn check_soft_limit(&self) {
+        if !self
+            .inserts
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(SOFT_LIMIT_INTERVAL)
+        {
+            return;
+        }
+
+        let soft_limit = self.soft_limit.read();
+        let Some(state) = soft_limit.as_ref() else {
+            return;
+        };
+
+        let (size, limit) = match state.limit {
+            SoftLimit::Entries(limit) => (self.query_map.len(), limit),
+            SoftLimit::Bytes(limit) => (self.approx_memory_bytes(), limit),
+        };
+
+        let last_size = state.last_size.swap(size, Ordering::Relaxed);
+
+        if size > limit {
+            (state.callback)(&SoftLimitExceeded {
+                limit: state.limit,
+                size,
+                growth: size as isize - last_size as isize,
+            });
+        }
+    }
+
+    // Reports every query found stale together with the dependency that
+    // invalidated it.
+    pub fn set_invalidation_logger(&self, logger: impl Fn(&Invalidation) + Send + Sync + 'static)
+    where
+        K: fmt::Debug,
+    {
+        *self.invalidation_logger.write() =
+            Some(Box::new(move |param, ty, valid_at, dependency| {
+                logger(&Invalidation {
+                    query_name: ty.name(),
+                    param_debug: format!("{:?}", param),
+                    valid_at,
+                    dependency,
+                })
+            }));
+    }
+
+    // Called at the given points of query computation, so that tests can
+    // deterministically control interleaving of parallel queries (e.g., by
+    // blocking on a barrier).
+    #[cfg(feature = "test-hooks")]
+    pub fn set_test_hook(&self, hook: impl Fn(HookPoint, QueryId) + Send + Sync + 'static) {
+        *self.test_hook.write() = Some(Box::new(hook));
+    }
+
+    pub fn set_observer(&self, observer: Arc<dyn CacheObserver>) {
+        *self.observer.write() = Some(observer);
+    }
+
+    // Cloned out, so that the observer is not called under the lock.
+    fn observer(&self) -> Option<Arc<dyn CacheObserver>> {
+        self.observer.read().clone()
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn run_test_hook(&self, point: HookPoint, query_id: QueryId) {
+        if let Some(hook) = self.test_hook.read().as_ref() {
+            hook(point, query_id);
+        }
+    }
+
+    #[cfg(not(feature = "test-hooks"))]
+    fn run_test_hook(&self, _: HookPoint, _: QueryId) {}
+
+    // Excludes the query type from cycle detection, so that it can legitimately
+    // re-enter itself (e.g., a REPL re-evaluating the top-level query). Beware
+    // that a real cycle through an exempt query is then not detected and
+    // overflows the stack, unless the depth is limited by
+    // `Runtime::set_max_query_depth`.
+    pub fn set_cycle_exempt<Q: 'static>(&self) {
+        self.cycle_exempt.insert(QueryType::of::<Q>());
+    }
+
+    // The queries of given type are never cache hits, so that they run on every
+    // call (e.g., for side effects). Unlike calling them outside of the cache,
+    // their dependencies are still recorded and they take part in the cycle
+    // detection. Their last outputs are returned by `cached_stale_ok` as stale.
+    pub fn set_volatile<Q: 'static>(&self) {
+        self.volatile.insert(QueryType::of::<Q>());
+    }
+
+    // Outputs are written to the store when they are inserted, and read back
+    // when they are looked up after being evicted from memory. The dependencies
+    // of evicted queries stay in memory, so that the validity of the stored
+    // outputs can be checked.
+    pub fn set_secondary_store(&self, store: impl SecondaryStore + 'static) {
+        *self.secondary.write() = Some(Box::new(store));
+    }
+
+    // Registers the serialization of the outputs of given query type for the
+    // secondary store. The decoding fails by returning `None`.
+    pub fn set_secondary_codec<Q: 'static, O: Send + Sync + 'static>(
+        &self,
+        encode: impl Fn(&O) -> Vec<u8> + Send + Sync + 'static,
+        decode: impl Fn(&[u8]) -> Option<O> + Send + Sync + 'static,
+    ) {
+        self.codecs.insert(
+            QueryType::of::<Q>(),
+            Codec {
+                encode: Box::new(move |output| output.downcast_ref().map(&encode)),
+                decode: Box::new(move |bytes| {
+                    decode(bytes).map(|output| Arc::new(output) as Arc<dyn Any + Send + Sync>)
+                }),
+            },
+        );
+    }
+
+    fn put_secondary(&self, query_id: QueryId, data: &QueryData) {
+        let secondary = self.secondary.read();
+
+        if let Some(store) = secondary.as_ref() {
+            self.spilled.remove(&query_id);
+
+            if let Some(codec) = self.codecs.get(&data.ty) {
+                if let Some(bytes) = (codec.encode)(data.output.as_ref()) {
+                    store.put(query_id, bytes);
+                }
+            }
+        }
+    }
+
+    fn spill(&self, query_id: QueryId, data: QueryData) {
+        if self.secondary.read().is_some() && self.codecs.contains_key(&data.ty) {
+            self.spilled.insert(
+                query_id,
+                SpilledData {
+                    output_size: data.output_size,
+                    output_type: data.output_type,
+                    ty: data.ty,
+                    valid_at: data.valid_at,
+                    changed_at: data.changed_at,
+                    dependencies: data.dependencies,
+                    external_dependencies: data.external_dependencies,
+                    query_dependencies: data.query_dependencies,
+                },
+            );
+        }
+    }
+
+    // Returns `None` if the output could not be restored into memory.
+    fn restore_spilled(&self, query_id: QueryId) -> Option<()> {
+        let (_, spilled) = self.spilled.remove(&query_id)?;
+        let bytes = self.secondary.read().as_ref()?.get(query_id)?;
+        let output = (self.codecs.get(&spilled.ty)?.decode)(&bytes)?;
+
+        self.query_map.insert(
+            query_id,
+            QueryData {
+                output,
+                output_size: spilled.output_size,
+                output_type: spilled.output_type,
+                ty: spilled.ty,
+                valid_at: spilled.valid_at,
+                changed_at: spilled.changed_at,
+                dependencies: spilled.dependencies,
+                external_dependencies: spilled.external_dependencies,
+                query_dependencies: spilled.query_dependencies,
+                last_used: AtomicU64::new(self.tick.fetch_add(1, Ordering::Relaxed)),
+            },
+        );
+
+        self.evict();
+        Some(())
+    }
+
+    // Decides what happens when a query of given type participates in a cycle
+    // by being re-entered. The default is `CyclePolicy::Error`.
+    pub fn set_cycle_policy<Q: 'static, O: Send + Sync + 'static>(&self, policy: CyclePolicy<O>) {
+        self.cycle_policies
+            .insert(QueryType::of::<Q>(), Box::new(policy));
+    }
+
+    // Registers a function that canonicalizes the params of given query type
+    // before they are used as cache keys, so that equivalent params share one
+    // cache entry. The function must be pure and idempotent.
+    pub fn set_param_normalizer<Q: 'static>(
+        &self,
+        normalize: impl Fn(&K) -> K + Send + Sync + 'static,
+    ) {
+        self.param_normalizers
+            .insert(QueryType::of::<Q>(), Box::new(normalize));
+    }
+
+    // Registers a function that decides whether a recomputed output of given
+    // query type is equal to the previous one. If it is, the previous output is
+    // kept and the query is considered unchanged (backdated), even if the
+    // outputs differ in parts irrelevant for caching.
+    pub fn set_output_eq<Q: 'static, O: 'static>(
+        &self,
+        eq: impl Fn(&O, &O) -> bool + Send + Sync + 'static,
+    ) {
+        self.output_eqs.insert(
+            QueryType::of::<Q>(),
+            Box::new(move |a, b| match (a.downcast_ref(), b.downcast_ref()) {
+                (Some(a), Some(b)) => eq(a, b),
+                _ => false,
+            }),
+        );
+    }
+
+    // Registers a function that decides whether an output of given query type
+    // is cached. Outputs for which it returns false (e.g., partial results) are
+    // returned to the caller, but the query is computed again next time.
+    pub fn set_cache_predicate<Q: 'static, O: 'static>(
+        &self,
+        predicate: impl Fn(&O) -> bool + Send + Sync + 'static,
+    ) {
+        self.cache_predicates.insert(
+            QueryType::of::<Q>(),
+            Box::new(move |output| output.downcast_ref().is_some_and(&predicate)),
+        );
+    }
+
+    fn normalize_param<Q: 'static>(&self, param: &K) -> Option<K> {
+        self.param_normalizers
+            .get(&QueryType::of::<Q>())
+            .map(|normalize| normalize(param))
+    }
+
+    // How many times the queries of each type were computed, sorted from the
+    // most computed ones.
+    pub fn recompute_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts = self
+            .recompute_counts
+            .iter()
+            .map(|kv| (kv.key().name(), *kv.value()))
+            .collect::<Vec<_>>();
+
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts
+    }
+
+    fn data_bytes(&self, data: &QueryData) -> usize {
+        let heap_size = self
+            .heap_size_hooks
+            .get(&data.ty)
+            .map(|hook| hook(data.output.as_ref()))
+            .unwrap_or_default();
+
+        mem::size_of::<QueryData>()
+            + data.output_size
+            + heap_size
+            + data.dependencies.capacity() * mem::size_of::<(InputIndex, KeyIndex)>()
+            + data.external_dependencies.capacity() * mem::size_of::<ExternalId>()
+            + data.query_dependencies.capacity() * mem::size_of::<QueryId>()
+    }
+
+    fn evict(&self) {
+        let (count, bytes) = match self.eviction {
+            EvictionPolicy::None => return,
+            EvictionPolicy::Weak => return self.evict_unreferenced(),
+            EvictionPolicy::Lru(capacity) => (capacity, usize::MAX),
+            EvictionPolicy::Bytes(limit) => (usize::MAX, limit),
+        };
+
+        let mut total_bytes = match self.eviction {
+            EvictionPolicy::Bytes(_) => self.approx_memory_bytes(),
+            _ => 0,
+        };
+
+        if self.query_map.len() <= count && total_bytes <= bytes {
+            return;
+        }
+
+        let mut entries = self
+            .query_map
+            .iter()
+            .map(|kv| (kv.value().last_used.load(Ordering::Relaxed), *kv.key()))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+
+        let mut remaining = entries.len();
+        let mut forgotten = FxHashMap::default();
+        let computing = self.computing();
+
+        for (_, id) in entries {
+            if remaining <= count && total_bytes <= bytes {
+                break;
+            }
+
+            if computing.contains(&id) {
+                continue;
+            }
+
+            if let Some(freed) = self.remove_output(id, &mut forgotten) {
+                total_bytes = total_bytes.saturating_sub(freed);
+                remaining -= 1;
+            }
+        }
+
+        self.forget_ids(forgotten);
+    }
+
+    fn evict_unreferenced(&self) {
+        let computing = self.computing();
+        let unreferenced = self
+            .query_map
+            .iter()
+            .filter(|kv| Arc::strong_count(&kv.value().output) == 1)
+            .filter(|kv| !computing.contains(kv.key()))
+            .map(|kv| *kv.key())
+            .collect::<Vec<_>>();
+
+        let mut forgotten = FxHashMap::default();
+        for id in unreferenced {
+            self.remove_output(id, &mut forgotten);
+        }
+
+        self.forget_ids(forgotten);
+    }
+
+    // The queries whose computation is in flight, and so are on the query
+    // stack of some thread. Their ids must not be dropped.
+    fn computing(&self) -> FxHashSet<QueryId> {
+        self.flights.lock().keys().copied().collect()
+    }
+
+    // Drops the output of the query and the values derived from it, spilling
+    // it if possible. The id is added to the forgotten ones unless the output
+    // was spilled. Returns the approximate number of bytes freed.
+    fn remove_output(
+        &self,
+        id: QueryId,
+        forgotten: &mut FxHashMap<QueryType, FxHashSet<QueryId>>,
+    ) -> Option<usize> {
+        let (_, data) = self.query_map.remove(&id)?;
+        let freed = mem::size_of::<QueryId>() + self.data_bytes(&data);
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+        self.derived_map
+            .retain(|(derived_id, _), _| *derived_id != id);
+
+        if let Some(observer) = self.observer() {
+            observer.on_evict(id);
+        }
+
+        let ty = data.ty;
+        self.spill(id, data);
+
+        // Spilled outputs are restored by the id.
+        if !self.spilled.contains_key(&id) {
+            forgotten.entry(ty).or_default().insert(id);
+        }
+
+        Some(freed)
+    }
+
+    // The ids of the evicted queries are dropped too, so that the id map does
+    // not grow with every query ever computed.
+    fn forget_ids(&self, forgotten: FxHashMap<QueryType, FxHashSet<QueryId>>) {
+        for (ty, ids) in forgotten {
+            if let Some(mut params) = self.id_map.get_mut(&ty) {
+                params.retain(|_, id| !ids.contains(id));
+            }
+            self.handle_map.retain(|_, id| !ids.contains(id));
+            for id in ids {
+                self.allocated_ids.remove(&id);
+            }
+        }
+    }
+
+    // Counters of the cache, see `CacheStats`.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.query_map.len(),
+            recomputations: self.recomputations.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    // Drops all cached queries including their ids, so the queries get new ids
+    // when computed again unless the ids are deterministic. Must not be called
+    // while the queries are being computed.
+    pub fn clear(&self) {
+        self.id_map.clear();
+        self.query_map.clear();
+        self.reverse_deps.clear();
+        self.pending_query_deps.clear();
+        self.derived_map.clear();
+        self.handle_map.clear();
+        self.spilled.clear();
+        self.labels.clear();
+        self.recovered.clear();
+        self.allocated_ids.clear();
+        self.query_id.store(0, Ordering::SeqCst);
+    }
+
+    // Drops all outputs computed before given revision, except those of the
+    // queries that are being computed. Like with the eviction policy, the ids
+    // are dropped too. Returns the number of evicted outputs.
+    pub fn evict_valid_before(&self, rev: Revision) -> usize {
+        let computing = self.computing();
+        let stale = self
+            .query_map
+            .iter()
+            .filter(|kv| kv.value().valid_at < rev && !computing.contains(kv.key()))
+            .map(|kv| *kv.key())
+            .collect::<Vec<_>>();
+
+        let mut forgotten = FxHashMap::default();
+        let evicted = stale
+            .into_iter()
+            .filter_map(|id| self.remove_output(id, &mut forgotten))
+            .count();
+
+        self.forget_ids(forgotten);
+
+        evicted
+    }
+
+    // Cached queries that depend on given input key, see
+    // `Runtime::input_key_index`.
+    pub fn queries_depending_on(
+        &self,
+        index: (InputIndex, KeyIndex),
+    ) -> Vec<(&'static str, QueryId)> {
+        let Some(mut ids) = self.reverse_deps.get_mut(&index) else {
+            return Vec::new();
+        };
+
+        let mut dependents = Vec::new();
+
+        ids.retain(|id| match self.query_map.get(id) {
+            Some(data) if data.dependencies.contains(&index) => {
+                dependents.push((data.ty.name(), *id));
+                true
+            }
+            _ => false,
+        });
+
+        dependents.sort_by_key(|(_, id)| *id);
+        dependents
+    }
+
+    // Keys that are set in the runtime but that no cached query depends on,
+    // e.g., to warn about configuration that is never used. The keys are
+    // unused only by the queries cached right now, a query computed later may
+    // still read them. Keys of inputs in a group are reported by the group
+    // dependency.
+    pub fn unused_inputs<I: StorageGroup>(
+        &self,
+        runtime: &Runtime<I>,
+    ) -> Vec<(InputIndex, KeyIndex)> {
+        let mut unused = runtime
+            .with_storage_group(|inputs| inputs.dependencies())
+            .into_iter()
+            .filter(|dependency| match self.reverse_deps.get(dependency) {
+                Some(ids) => !ids.iter().any(|id| {
+                    self.query_map
+                        .get(id)
+                        .is_some_and(|data| data.dependencies.contains(dependency))
+                }),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        unused.sort();
+        unused.dedup();
+        unused
+    }
+
+    // A best-effort estimate of the memory held by the cache.
+    pub fn approx_memory_bytes(&self) -> usize {
+        let id_entry = mem::size_of::<K>() + mem::size_of::<QueryId>();
+        let ids = self
+            .id_map
+            .iter()
+            .map(|kv| mem::size_of::<QueryType>() + kv.value().capacity() * id_entry)
+            .sum::<usize>();
+
+        let queries = self
+            .query_map
+            .iter()
+            .map(|kv| mem::size_of::<QueryId>() + self.data_bytes(kv.value()))
+            .sum::<usize>();
+
+        let derived = self.derived_map.len()
+            * (mem::size_of::<(QueryId, TypeId)>() + mem::size_of::<DerivedData>());
+
+        let reverse_deps = self
+            .reverse_deps
+            .iter()
+            .map(|kv| {
+                mem::size_of::<(InputIndex, KeyIndex)>()
+                    + kv.value().capacity() * mem::size_of::<QueryId>()
+            })
+            .sum::<usize>();
+
+        ids + queries + derived + reverse_deps
+    }
+
+    fn memos(&self) -> Memos<'_> {
+        Memos {
+            query_map: &self.query_map,
+            spilled: &self.spilled,
+            recovered: &self.recovered,
+        }
+    }
+
+    fn queries_changed_at<I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_ids: &[QueryId],
+        memo: &mut FxHashMap<QueryId, Revision>,
+    ) -> Revision {
+        self.memos().queries_changed_at(runtime, query_ids, memo)
+    }
+
+    fn inputs_rev<I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_id: QueryId,
+        dependencies: &[(InputIndex, KeyIndex)],
+        external_dependencies: &[ExternalId],
+    ) -> Revision {
+        self.memos()
+            .inputs_rev(runtime, query_id, dependencies, external_dependencies)
+    }
+
+    fn is_stale<I>(&self, runtime: &Runtime<I>, query_id: QueryId) -> Option<bool> {
+        let data = self.query_map.get(&query_id)?;
+        let inputs_rev = self.inputs_rev(
+            runtime,
+            query_id,
+            &data.dependencies,
+            &data.external_dependencies,
+        );
+        let valid_at = data.valid_at;
+        let query_dependencies = data.query_dependencies.clone();
+        drop(data);
+
+        let queries_rev =
+            self.queries_changed_at(runtime, &query_dependencies, &mut FxHashMap::default());
+        Some(inputs_rev.max(queries_rev) > valid_at)
+    }
+}
+
+impl<K: Hash + Eq + Clone> QueryCache<K> {
+    pub fn cached<Q: 'static, O: Send + Sync + 'static, I>(
+        &self,
+        param: &K,
+        runtime: &Runtime<I>,
+    ) -> Option<Arc<O>> {
+        self.cached_stale_ok::<Q, O, I>(param, runtime)
+            .and_then(|(output, stale)| (!stale).then_some(output))
+    }
+
+    // Like `cached`, but distinguishes why the output could not be returned, so
+    // that read-only code paths can assert that no computation is needed.
+    pub fn cached_strict<Q: 'static, O: Send + Sync + 'static, I>(
+        &self,
+        param: &K,
+        runtime: &Runtime<I>,
+    ) -> Result<Arc<O>, CacheMiss> {
+        match self.cached_stale_ok::<Q, O, I>(param, runtime) {
+            Some((output, false)) => Ok(output),
+            Some((_, true)) => Err(CacheMiss::Stale),
+            None => Err(CacheMiss::Missing),
+        }
+    }
+
+    // Returns the cached output even if it was invalidated, together with a
+    // flag whether it is stale. The caller can show the stale output while the
+    // query is recomputed.
+    pub fn cached_stale_ok<Q: 'static, O: Send + Sync + 'static, I>(
+        &self,
+        param: &K,
+        runtime: &Runtime<I>,
+    ) -> Option<(Arc<O>, bool)> {
+        // The same query type used with a different output type is treated as
+        // a cache miss instead of panicking on the downcast.
+        self.cached_checked::<Q, O, I>(param, runtime)
+            .unwrap_or(None)
+    }
+
+    // Like `cached`, but reports an output of a different type than `O` as an
+    // error instead of a cache miss, for caches storing heterogeneous outputs
+    // under one query type.
+    pub fn cached_downcast<Q: 'static, O: Send + Sync + 'static, I>(
+        &self,
+        param: &K,
+        runtime: &Runtime<I>,
+    ) -> Result<Option<Arc<O>>, DowncastError> {
+        self.cached_checked::<Q, O, I>(param, runtime)
+            .map(|cached| cached.and_then(|(output, stale)| (!stale).then_some(output)))
+    }
+
+    fn cached_checked<Q: 'static, O: Send + Sync + 'static, I>(
+        &self,
+        param: &K,
+        runtime: &Runtime<I>,
+    ) -> Result<Option<(Arc<O>, bool)>, DowncastError> {
+        let Some(id) = self.id::<Q>(param) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+
+        // The situation when id_map contains the query type and param, but
+        // query_map does not contain corresponding value, happens when we have
+        // started a query, but not finished it yet, and we are called again.
+        let data = match self.query_map.get(&id) {
+            Some(data) => data,
+            None => match self
+                .restore_spilled(id)
+                .and_then(|_| self.query_map.get(&id))
+            {
+                Some(data) => data,
+                None => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    if let Some(observer) = self.observer() {
+                        observer.on_miss(id);
+                    }
+                    return Ok(None);
+                }
+            },
+        };
+
+        if data.output_type != TypeId::of::<O>() {
+            return Err(DowncastError {
+                query_name: data.ty.name(),
+                expected: std::any::type_name::<O>(),
+            });
+        }
+
+        let ty = data.ty;
+        let valid_at = data.valid_at;
+        let inputs_rev =
+            self.inputs_rev(runtime, id, &data.dependencies, &data.external_dependencies);
+        let query_dependencies = data.query_dependencies.clone();
+
+        let output = Arc::downcast(data.output.clone()).unwrap();
+
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
+        data.last_used.store(tick, Ordering::Relaxed);
+
+        // The called queries are looked up in the same map.
+        drop(data);
+
+        let queries_rev =
+            self.queries_changed_at(runtime, &query_dependencies, &mut FxHashMap::default());
+        let stale = inputs_rev.max(queries_rev) > valid_at;
+
+        // Volatile queries are not invalidated, just never reused.
+        if !stale && self.volatile.contains(&ty) {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            if let Some(observer) = self.observer() {
+                observer.on_miss(id);
+            }
+            return Ok(Some((output, true)));
+        }
+
+        if stale {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            if let Some(observer) = self.observer() {
+                observer.on_miss(id);
+            }
+
+            if let Some(logger) = self.invalidation_logger.read().as_ref() {
+                if let Some(dependency) = self.stale_dependency(runtime, id) {
+                    logger(param, ty, valid_at, dependency);
+                }
+            }
+
+            self.notify_invalidated(|other, _| other == id);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            if let Some(observer) = self.observer() {
+                observer.on_hit(id);
+            }
+            #[cfg(feature = "tracing")]
+            tracing::trace!(query = ty.name(), query_id = id.0, "cache hit");
+            self.record_query_use(runtime, id);
+        }
+
+        Ok(Some((output, stale)))
+    }
+
+    // The valid output of a query computed by another thread.
+    fn landed_output<O: Send + Sync + 'static, I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_id: QueryId,
+    ) -> Option<Arc<O>> {
+        if self.is_stale(runtime, query_id)? {
+            return None;
+        }
+
+        let data = self.query_map.get(&query_id)?;
+        Arc::downcast(data.output.clone()).ok()
+    }
+
+    // Finds the dependency that changed after the query was computed.
+    fn stale_dependency<I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_id: QueryId,
+    ) -> Option<StaleDependency> {
+        let data = self.query_map.get(&query_id)?;
+
+        if self.recovered.contains(&query_id) {
+            return Some(StaleDependency::Recovered {
+                changed_at: runtime.rev(),
+            });
+        }
+
+        if let Some(dependency) = runtime.stale_dependency(
+            &data.dependencies,
+            &data.external_dependencies,
+            data.valid_at,
+        ) {
+            return Some(dependency);
+        }
+
+        let valid_at = data.valid_at;
+        let query_dependencies = data.query_dependencies.clone();
+        drop(data);
+
+        query_dependencies.into_iter().find_map(|query_id| {
+            let changed_at =
+                self.queries_changed_at(runtime, &[query_id], &mut FxHashMap::default());
+            (changed_at > valid_at).then_some(StaleDependency::Query {
+                query_id,
+                changed_at,
+            })
+        })
+    }
+
+    // Records the query as called by the query being computed, if any. The
+    // caller is assumed to be of the same cache.
+    fn record_query_use<I>(&self, runtime: &Runtime<I>, query_id: QueryId) {
+        if let Some(caller) = runtime
+            .query_stack()
+            .top()
+            .filter(|caller| *caller != query_id)
+        {
+            self.pending_query_deps
+                .entry(caller)
+                .or_default()
+                .insert(query_id);
+        }
+    }
+
+    // Derives a value from a cached query output. The derived value is cached
+    // under the marker type `M` and its type until the source output is
+    // recomputed, so the derivations that differ (even only in the captured
+    // values) need different markers.
+    pub fn map_cached<Q: 'static, M: 'static, O, U, I, F>(
+        &self,
+        param: &K,
+        runtime: &Runtime<I>,
+        f: F,
+    ) -> Option<Arc<U>>
+    where
+        O: Send + Sync + 'static,
+        U: Send + Sync + 'static,
+        F: FnOnce(&O) -> U,
+    {
+        let source = self.cached::<Q, O, I>(param, runtime)?;
+        let query_id = self.id::<Q>(param)?;
+        let key = (query_id, TypeId::of::<(M, U)>());
+
+        if let Some(derived) = self.derived_map.get(&key) {
+            if Weak::as_ptr(&derived.source) as *const () == Arc::as_ptr(&source) as *const () {
+                return Some(Arc::downcast(derived.output.clone()).unwrap());
+            }
+        }
+
+        let output = Arc::new(f(&source));
+        let source: Arc<dyn Any + Send + Sync> = source;
+
+        self.derived_map.insert(
+            key,
+            DerivedData {
+                source: Arc::downgrade(&source),
+                output: output.clone(),
+            },
+        );
+
+        Some(output)
+    }
+
+    // Returns the cached output of the query, or computes it while holding
+    // the inputs consistent.
+    pub fn execute<Q, I>(&self, query: &Q, runtime: &Runtime<I>, param: K) -> Arc<Q::Output>
+    where
+        Q: Query<I, Param = K>,
+        K: fmt::Debug,
+    {
+        self.cached::<Q, Q::Output, I>(&param, runtime)
+            .unwrap_or_else(|| {
+                let guard = runtime.lock_readonly();
+
+                let output = self
+                    .insert_with::<Q, _, _, _>(runtime, param, |param, ctx| query.run(param, ctx));
+
+                drop(guard);
+                output
+            })
+    }
+
+    pub fn insert_with<'r, Q: 'static, O: Send + Sync + 'static, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        f: F,
+    ) -> Arc<O>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> O,
+        K: fmt::Debug,
+    {
+        self.try_insert_with::<Q, _, Cycle, _, _>(runtime, param, |param, ctx| Ok(f(param, ctx)))
+            .unwrap_or_else(|cycle| panic!("{:?}", self.debug_cycle(cycle)))
+    }
+
+    // Splits the computation into a part that uses the inputs (and so records
+    // the dependencies) and an expensive part that only works with the values
+    // captured by the returned thunk. The thunk runs when the output is first
+    // used, which is useful when many queries are primed but few are read.
+    pub fn insert_lazy<'r, Q: 'static, O, I, F, T>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        f: F,
+    ) -> Arc<Lazy<O>>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> T,
+        T: FnOnce() -> O + Send + 'static,
+        O: Send + Sync + 'static,
+        K: fmt::Debug,
+    {
+        self.insert_with::<Q, Lazy<O>, I, _>(runtime, param, |param, ctx| Lazy::new(f(param, ctx)))
+    }
+
+    pub fn try_insert_with<'r, Q: 'static, O: Send + Sync + 'static, E, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        f: F,
+    ) -> Result<Arc<O>, E>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> Result<O, E>,
+        E: From<Cycle>,
+    {
+        self.try_insert_with_impl::<Q, O, E, I, F>(runtime, param, None, None, None, f)
+    }
+
+    // Like `try_insert_with`, but if the output is equal to the previous one,
+    // the previous output is kept and the query is considered unchanged since
+    // then (backdated). The queries that called it are then not invalidated
+    // when they are checked after it was recomputed, which only works when
+    // they were recorded as its dependents (see `QueryContext::use_query`).
+    pub fn try_insert_with_backdating<'r, Q: 'static, O, E, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        f: F,
+    ) -> Result<Arc<O>, E>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> Result<O, E>,
+        O: PartialEq + Send + Sync + 'static,
+        E: From<Cycle>,
+    {
+        self.try_insert_with_impl::<Q, O, E, I, F>(runtime, param, None, Some(O::eq), None, f)
+    }
+
+    // Attaches a human-readable label (e.g., the file path) to the query, which
+    // is shown in place of the param in cycle reports. Useful when the param is
+    // noisy or opaque.
+    pub fn try_insert_with_label<'r, Q: 'static, O: Send + Sync + 'static, E, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        label: &str,
+        f: F,
+    ) -> Result<Arc<O>, E>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> Result<O, E>,
+        E: From<Cycle>,
+    {
+        self.try_insert_with_impl::<Q, O, E, I, F>(runtime, param, Some(label), None, None, f)
+    }
+
+    // Like `try_insert_with`, but when the query is on a cycle, the output is
+    // recovered instead of failing. The recovered output is cached until any
+    // input changes. A computation of the query in progress (e.g., the one
+    // that re-entered it) replaces it when it finishes.
+    pub fn try_insert_with_recovery<'r, Q: 'static, O: Send + Sync + 'static, E, I, F, R>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        f: F,
+        recover: R,
+    ) -> Result<Arc<O>, E>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> Result<O, E>,
+        R: FnOnce(&Cycle, &K) -> O,
+        E: From<Cycle>,
+    {
+        self.try_insert_with_impl::<Q, O, E, I, F>(
+            runtime,
+            param,
+            None,
+            None,
+            Some(Box::new(recover)),
+            f,
+        )
+    }
+
+    fn try_insert_with_impl<'r, Q: 'static, O: Send + Sync + 'static, E, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        label: Option<&str>,
+        backdate: Option<fn(&O, &O) -> bool>,
+        recover: Option<Recover<'_, K, O>>,
+        f: F,
+    ) -> Result<Arc<O>, E>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> Result<O, E>,
+        E: From<Cycle>,
+    {
+        let param = self.normalize_param::<Q>(&param).unwrap_or(param);
+        let query_id = self.get_or_insert_id::<Q>(&param);
+
+        let exempt = self.cycle_exempt.contains(&QueryType::of::<Q>());
+        let max_depth = runtime.max_query_depth();
+        let guard = match runtime.query_stack().push(query_id, exempt, max_depth) {
+            Ok(guard) => guard,
+            Err(cycle) => {
+                return self.on_cycle::<Q, O, E, I>(runtime, query_id, &param, cycle, recover)
+            }
+        };
+
+        // Taken before the flight is registered, so that the owner of a flight
+        // never waits for a permit. Otherwise, a thread holding the permit
+        // could wait for the flight of a thread waiting for the permit.
+        self.run_test_hook(HookPoint::BeforeAcquire, query_id);
+        let permit = self.limiter.as_ref().and_then(ComputeLimiter::acquire);
+
+        // The output of the same query computed by another thread meanwhile is
+        // reused. If the computation failed, it is attempted again.
+        let _flight = loop {
+            let flight = {
+                let mut flights = self.flights.lock();
+                match flights.get(&query_id) {
+                    // Re-entered by a cycle exempt query.
+                    Some(flight) if flight.owner == thread::current().id() => break None,
+                    Some(flight) => {
+                        let flight = flight.clone();
+                        if let Some(cycle) = self.wait_cycle(&flights, query_id, flight.owner) {
+                            drop(flights);
+                            return self
+                                .on_cycle::<Q, O, E, I>(runtime, query_id, &param, cycle, recover);
+                        }
+                        self.waiting.lock().insert(thread::current().id(), query_id);
+                        flight
+                    }
+                    None => {
+                        let flight = Arc::new(Flight {
+                            owner: thread::current().id(),
+                            landed: Mutex::new(false),
+                            landed_signal: Condvar::new(),
+                        });
+                        flights.insert(query_id, flight.clone());
+                        break Some(FlightGuard {
+                            flights: &self.flights,
+                            query_id,
+                            flight,
+                        });
+                    }
+                }
+            };
+
+            self.run_test_hook(HookPoint::BeforeWait, query_id);
+            flight.wait();
+            self.waiting.lock().remove(&thread::current().id());
+
+            if let Some(output) = self.landed_output::<O, I>(runtime, query_id) {
+                drop(guard);
+                self.record_query_use(runtime, query_id);
+                return Ok(output);
+            }
+        };
+
+        *self
+            .recompute_counts
+            .entry(QueryType::of::<Q>())
+            .or_default() += 1;
+        self.recomputations.fetch_add(1, Ordering::Relaxed);
+
+        self.run_test_hook(HookPoint::BeforeCompute, query_id);
+
+        match label {
+            Some(label) => self.labels.insert(query_id, label.into()),
+            None => self.labels.remove(&query_id).map(|(_, label)| label),
+        };
+
+        // Replaces the one left over from a computation that failed.
+        let mut ctx = QueryContext::new(runtime);
+        self.pending_query_deps
+            .insert(query_id, ctx.query_dependencies.clone());
+        ctx.previous = self.query_map.get(&query_id).map(|data| PreviousRun {
+            query_id,
+            valid_at: data.valid_at,
+            memos: self.memos(),
+        });
+        // Entered while the nested queries are computed, so that their spans
+        // are nested too.
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "query",
+            query = QueryType::of::<Q>().name(),
+            query_id = query_id.0
+        )
+        .entered();
+        let mut output = Arc::new(f(&param, &ctx)?);
+        #[cfg(feature = "tracing")]
+        drop(span);
+        drop(permit);
+        let valid_at = runtime.rev();
+        let mut changed_at = valid_at;
+        self.pending_query_deps.remove(&query_id);
+        let (dependencies, external_dependencies, query_dependencies) = ctx.into_dependencies();
+
+        drop(guard);
+
+        self.record_query_use(runtime, query_id);
+
+        self.run_test_hook(HookPoint::BeforeInsert, query_id);
+
+        if let Some(predicate) = self.cache_predicates.get(&QueryType::of::<Q>()) {
+            if !predicate(output.as_ref()) {
+                // The previous output must not be returned as valid.
+                self.query_map.remove(&query_id);
+                return Ok(output);
+            }
+        }
+
+        if let Some(previous) = self.query_map.get(&query_id) {
+            let equal = match backdate {
+                Some(eq) => previous
+                    .output
+                    .downcast_ref::<O>()
+                    .is_some_and(|previous| eq(previous, &output)),
+                None => self
+                    .output_eqs
+                    .get(&QueryType::of::<Q>())
+                    .is_some_and(|eq| eq(previous.output.as_ref(), output.as_ref())),
+            };
+
+            if equal {
+                output = Arc::downcast(previous.output.clone()).unwrap();
+                changed_at = previous.changed_at;
+            }
+        }
+
+        for dependency in dependencies.iter() {
+            self.reverse_deps
+                .entry(*dependency)
+                .or_default()
+                .insert(query_id);
+        }
+
+        let data = QueryData {
+            output: output.clone(),
+            output_size: mem::size_of::<O>(),
+            output_type: TypeId::of::<O>(),
+            ty: QueryType::of::<Q>(),
+            valid_at,
+            changed_at,
+            dependencies,
+            external_dependencies,
+            query_dependencies,
+            last_used: AtomicU64::new(self.tick.fetch_add(1, Ordering::Relaxed)),
+        };
+
+        self.put_secondary(query_id, &data);
+        self.recovered.remove(&query_id);
+        self.query_map.insert(query_id, data);
+
+        if let Some(observer) = self.observer() {
+            observer.on_insert(query_id);
+        }
+
+        if !self.fixpoints.is_empty() {
+            self.fixpoints
+                .iter_mut()
+                .for_each(|mut state| state.computed.push(query_id));
+        }
+
+        self.evict();
+        self.check_soft_limit();
+
+        Ok(output)
+    }
+
+    // Computes the query with `CyclePolicy::Fixpoint` registered, iterating
+    // until its output is stable. The join must ascend a finite lattice,
+    // otherwise the iteration does not terminate. Without the fixpoint policy,
+    // it is the same as `try_insert_with`.
+    pub fn try_insert_fixpoint<'r, Q: 'static, O, E, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: K,
+        f: F,
+    ) -> Result<Arc<O>, E>
+    where
+        F: Fn(&K, &QueryContext<'r, I>) -> Result<O, E>,
+        O: Clone + PartialEq + Send + Sync + 'static,
+        E: From<Cycle>,
+    {
+        let param = self.normalize_param::<Q>(&param).unwrap_or(param);
+        let query_id = self.get_or_insert_id::<Q>(&param);
+
+        let fixpoint =
+            self.cycle_policies
+                .get(&QueryType::of::<Q>())
+                .and_then(|policy| match policy.downcast_ref::<CyclePolicy<O>>() {
+                    Some(CyclePolicy::Fixpoint { bottom, join }) => Some((bottom.clone(), *join)),
+                    _ => None,
+                });
+
+        let (bottom, join) = match fixpoint {
+            // A re-entrant call ends up with the approximation.
+            Some(fixpoint) if !self.fixpoints.contains_key(&query_id) => fixpoint,
+            _ => return self.try_insert_with::<Q, O, E, I, _>(runtime, param, f),
+        };
+
+        self.fixpoints.insert(
+            query_id,
+            FixpointState {
+                approximation: Arc::new(bottom),
+                reentered: false,
+                computed: Vec::new(),
+            },
+        );
+
+        let result = loop {
+            let output = match self.try_insert_with::<Q, O, E, I, _>(runtime, param.clone(), &f) {
+                Ok(output) => output,
+                Err(error) => break Err(error),
+            };
+
+            let mut state = self.fixpoints.get_mut(&query_id).unwrap();
+            let approximation = Arc::downcast::<O>(state.approximation.clone()).unwrap();
+
+            if !state.reentered || *output == *approximation {
+                break Ok(output);
+            }
+
+            state.approximation = Arc::new(join(&approximation, &output));
+            state.reentered = false;
+
+            // The queries computed with the old approximation are outdated.
+            for query_id in state.computed.drain(..) {
+                self.query_map.remove(&query_id);
+            }
+        };
+
+        self.fixpoints.remove(&query_id);
+        result
+    }
+
+    // Follows the threads waiting for each other starting at the owner of the
+    // query the current thread is about to wait for. If it leads back to the
+    // current thread, waiting would deadlock, so the queries on the way are
+    // reported as a cycle instead. Only the queries the threads wait for are
+    // in the cycle, not the whole stacks of the threads.
+    fn wait_cycle(
+        &self,
+        flights: &FxHashMap<QueryId, Arc<Flight>>,
+        query_id: QueryId,
+        owner: ThreadId,
+    ) -> Option<Cycle> {
+        let current = thread::current().id();
+        let waiting = self.waiting.lock();
+        let mut cycle = vec![query_id];
+        let mut owner = owner;
+
+        // Each thread waits for at most one query, so the walk ends.
+        while cycle.len() <= waiting.len() + 1 {
+            let awaited = *waiting.get(&owner)?;
+            cycle.push(awaited);
+            owner = flights.get(&awaited)?.owner;
+
+            if owner == current {
+                cycle.push(query_id);
+                return Some(Cycle::new(cycle));
+            }
+        }
+
+        None
+    }
+
+    fn on_cycle<Q: 'static, O: Send + Sync + 'static, E: From<Cycle>, I>(
+        &self,
+        runtime: &Runtime<I>,
+        query_id: QueryId,
+        param: &K,
+        cycle: Cycle,
+        recover: Option<Recover<'_, K, O>>,
+    ) -> Result<Arc<O>, E> {
+        let Some(recover) = recover.filter(|_| !cycle.is_depth_exceeded()) else {
+            return self.recover_cycle::<Q, O, E>(query_id, cycle);
+        };
+
+        let cycle = self.with_participants(cycle);
+        let output = Arc::new(recover(&cycle, param));
+        let rev = runtime.rev();
+
+        self.recovered.insert(query_id);
+        self.query_map.insert(
+            query_id,
+            QueryData {
+                output: output.clone(),
+                output_size: mem::size_of::<O>(),
+                output_type: TypeId::of::<O>(),
+                ty: QueryType::of::<Q>(),
+                valid_at: rev,
+                changed_at: rev,
+                dependencies: Vec::new(),
+                external_dependencies: Vec::new(),
+                query_dependencies: Vec::new(),
+                last_used: AtomicU64::new(self.tick.fetch_add(1, Ordering::Relaxed)),
+            },
+        );
+
+        if let Some(observer) = self.observer() {
+            observer.on_insert(query_id);
+        }
+
+        Ok(output)
+    }
+
+    fn recover_cycle<Q: 'static, O: Send + Sync + 'static, E: From<Cycle>>(
+        &self,
+        query_id: QueryId,
+        cycle: Cycle,
+    ) -> Result<Arc<O>, E> {
+        // Exceeding the depth is not a cycle the policies could recover from.
+        if cycle.is_depth_exceeded() {
+            return Err(self.with_participants(cycle).into());
+        }
+
+        let policy = self.cycle_policies.get(&QueryType::of::<Q>());
+
+        match policy
+            .as_ref()
+            .and_then(|policy| policy.downcast_ref::<CyclePolicy<O>>())
+        {
+            Some(CyclePolicy::Recover(recover)) => {
+                Ok(Arc::new(recover(&self.with_participants(cycle))))
+            }
+            Some(CyclePolicy::Fixpoint { .. }) => match self.fixpoints.get_mut(&query_id) {
+                Some(mut state) => {
+                    state.reentered = true;
+                    Ok(Arc::downcast(state.approximation.clone()).unwrap())
+                }
+                None => Err(self.with_participants(cycle).into()),
+            },
+            _ => Err(self.with_participants(cycle).into()),
+        }
+    }
+
+    // Rendered only when the cycle is reported, the fixpoint iteration
+    // re-enters the queries routinely.
+    fn with_participants(&self, cycle: Cycle) -> Cycle {
+        match *self.render_participants.read() {
+            Some(render) => render(self, cycle),
+            None => cycle,
+        }
+    }
+
+    fn get_or_insert_id<Q: 'static>(&self, param: &K) -> QueryId {
+        // Recomputation of a known query takes only a shared lock and does not
+        // clone the param.
+        let known = self
+            .id_map
+            .get(&QueryType::of::<Q>())
+            .and_then(|map| map.get(param).copied());
+
+        if let Some(query_id) = known {
+            return query_id;
+        }
+
+        *self
+            .id_map
+            .entry(QueryType::of::<Q>())
+            .or_insert_with(|| HashMap::with_hasher(self.param_hasher.clone()))
+            .entry(param.clone())
+            .or_insert_with(|| {
+                let handle = self.stable_handle::<Q>(param);
+                let query_id = if self.deterministic_ids {
+                    // Clashing hashes are resolved by probing the next ids.
+                    let mut query_id = QueryId((handle.0 ^ (handle.0 >> 32)) as u32);
+                    while !self.allocated_ids.insert(query_id) {
+                        query_id = QueryId(query_id.0.wrapping_add(1));
+                    }
+                    query_id
+                } else {
+                    QueryId(self.query_id.fetch_add(1, Ordering::SeqCst))
+                };
+                self.handle_map.insert(handle, query_id);
+                query_id
+            })
+    }
+
+    // The revision at which the query was last computed, regardless of whether
+    // it is still valid.
+    pub fn valid_at<Q: 'static>(&self, param: &K) -> Option<Revision> {
+        let id = self.id::<Q>(param)?;
+        self.query_map.get(&id).map(|data| data.valid_at)
+    }
+
+    // Computes the query as if the inputs were replaced by the overrides, without
+    // caching the result or touching the runtime. The overrides shadow only the
+    // inputs used directly in `f` (not input ranges), the queries called from
+    // `f` see the actual inputs. Fails like `try_insert_with` when the query is
+    // already on the stack or the stack is too deep.
+    pub fn compute_isolated<'r, Q: 'static, O, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: &K,
+        overrides: &'r Overrides<I>,
+        f: F,
+    ) -> Result<O, QueryError>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> O,
+    {
+        self.compute_uncached::<Q, O, I, F>(runtime, param, Some(overrides), f)
+    }
+
+    // Recomputes the query ignoring its cached output and compares the result
+    // with the cached output, e.g., for checking in tests that the incremental
+    // results match the clean ones. The queries called from `f` are served from
+    // the cache, so checking all cached queries checks every level. Queries
+    // without a valid cached output have nothing to drift from. Fails like
+    // `compute_isolated`.
+    pub fn check_no_drift<'r, Q: 'static, O, I, F>(
+        &'r self,
+        param: &K,
+        runtime: &'r Runtime<I>,
+        f: F,
+    ) -> Result<bool, QueryError>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> O,
+        O: PartialEq + Send + Sync + 'static,
+    {
+        let Some(cached) = self.cached::<Q, O, I>(param, runtime) else {
+            return Ok(true);
+        };
+
+        let output = self.compute_uncached::<Q, O, I, F>(runtime, param, None, f)?;
+        Ok(*cached == output)
+    }
+
+    fn compute_uncached<'r, Q: 'static, O, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        param: &K,
+        overrides: Option<&'r Overrides<I>>,
+        f: F,
+    ) -> Result<O, QueryError>
+    where
+        F: FnOnce(&K, &QueryContext<'r, I>) -> O,
+    {
+        let normalized = self.normalize_param::<Q>(param);
+        let query_id = self.get_or_insert_id::<Q>(normalized.as_ref().unwrap_or(param));
+        let exempt = self.cycle_exempt.contains(&QueryType::of::<Q>());
+        let guard = runtime
+            .query_stack()
+            .push(query_id, exempt, runtime.max_query_depth())
+            .map_err(|cycle| QueryError::from(self.with_participants(cycle)))?;
+
+        let mut ctx = QueryContext::new(runtime);
+        ctx.overrides = overrides;
+        let output = f(param, &ctx);
+
+        drop(guard);
+
+        Ok(output)
+    }
+
+    pub fn stable_handle<Q: 'static>(&self, param: &K) -> StableQueryHandle {
+        let normalized = self.normalize_param::<Q>(param);
+        let param = normalized.as_ref().unwrap_or(param);
+
+        let mut hasher = FxHasher::default();
+        QueryType::of::<Q>().name().hash(&mut hasher);
+        param.hash(&mut hasher);
+        StableQueryHandle(hasher.finish())
+    }
+
+    pub fn resolve(&self, handle: StableQueryHandle) -> Option<QueryId> {
+        self.handle_map.get(&handle).map(|id| *id)
+    }
+
+    // Calls the callback when the query becomes invalid, so that, e.g., a UI
+    // can re-render. For caches attached to the runtime (see
+    // `Runtime::attach_cache`), it is called when the inputs are set, otherwise
+    // when the stale query is looked up. It is called once per computation of
+    // the query and not at all before the query is computed. The observer is
+    // removed when the returned guard is dropped.
+    pub fn on_invalidate(
+        &self,
+        handle: StableQueryHandle,
+        callback: impl Fn(QueryId) + Send + Sync + 'static,
+    ) -> InvalidationSubscription<'_, K> {
+        let id = self.observer_id.fetch_add(1, Ordering::Relaxed);
+
+        self.invalidation_observers.lock().insert(
+            id,
+            InvalidationObserver {
+                handle,
+                callback: Arc::new(callback),
+                notified_at: None,
+            },
+        );
+
+        InvalidationSubscription { cache: self, id }
+    }
+
+    // Notifies the observers of the queries that are stale according to given
+    // predicate.
+    fn notify_invalidated(&self, stale: impl Fn(QueryId, &QueryData) -> bool) {
+        let mut notify = Vec::new();
+
+        for observer in self.invalidation_observers.lock().values_mut() {
+            let Some(id) = self.resolve(observer.handle) else {
+                continue;
+            };
+
+            let valid_at = match self.query_map.get(&id) {
+                Some(data) if stale(id, &data) => data.valid_at,
+                _ => continue,
+            };
+
+            if observer.notified_at != Some(valid_at) {
+                observer.notified_at = Some(valid_at);
+                notify.push((observer.callback.clone(), id));
+            }
+        }
+
+        for (callback, id) in notify {
+            callback(id);
+        }
+    }
+
+    // Registers how to recompute a query of given type from its param, which
+    // is needed for warming the cache by `warm_in_order`.
+    pub fn set_recompute<Q: 'static, I: 'static>(
+        &self,
+        recompute: impl Fn(&QueryCache<K>, &Runtime<I>, &K) + Send + Sync + 'static,
+    ) {
+        self.recompute_fns.insert(
+            QueryType::of::<Q>(),
+            Arc::new(move |cache, runtime, param| {
+                if let Some(runtime) = runtime.downcast_ref() {
+                    recompute(cache, runtime, param);
+                }
+            }),
+        );
+    }
+
+    // Recomputes the stale or evicted queries of the plan in its order. If the
+    // plan is ordered so that the queries come after their sub-queries (e.g.,
+    // topologically), the sub-queries are cache hits. Queries that were never
+    // computed or whose type has no recompute function are skipped. Returns the
+    // number of recomputed queries.
+    pub fn warm_in_order<I: 'static>(
+        &self,
+        runtime: &Runtime<I>,
+        plan: &[StableQueryHandle],
+    ) -> usize {
+        let ids = plan
+            .iter()
+            .filter_map(|handle| self.resolve(*handle))
+            .collect::<FxHashSet<_>>();
+
+        let mut params = FxHashMap::default();
+        for kv in self.id_map.iter() {
+            for (param, id) in kv.value().iter() {
+                if ids.contains(id) {
+                    params.insert(*id, (*kv.key(), param.clone()));
+                }
+            }
+        }
+
+        let mut recomputed = 0;
 
-pub struct QueryCache<K> {
-    id_map: FxDashMap<QueryType, FxHashMap<K, QueryId>>,
-    query_map: FxDashMap<QueryId, QueryData>,
-    query_id: AtomicU32,
-}
+        for id in plan.iter().filter_map(|handle| self.resolve(*handle)) {
+            let (ty, param) = match params.get(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
 
-struct QueryData {
-    output: Arc<dyn Any + Send + Sync>,
-    valid_at: Revision,
-    dependencies: Vec<(InputIndex, KeyIndex)>,
-}
+            let stale = self.is_stale(runtime, id).unwrap_or(true);
 
-#[derive(Debug, Clone, Copy)]
-struct QueryType {
-    type_id: TypeId,
-    // For debugging purposes.
-    name: &'static str,
-}
+            let recompute = self.recompute_fns.get(ty).map(|f| f.clone());
 
-impl<K> QueryCache<K> {
-    pub fn new() -> Self {
-        Self::default()
+            if let (true, Some(recompute)) = (stale, recompute) {
+                recompute(self, runtime, param);
+                recomputed += 1;
+            }
+        }
+
+        recomputed
     }
-}
 
-impl<K: Hash + Eq + Clone> QueryCache<K> {
-    pub fn cached<Q: 'static, O: Send + Sync + 'static, I>(
+    // All cached outputs of given query type with their params. The stale
+    // outputs are recomputed if `recompute` is set and the query type has a
+    // recompute function (see `set_recompute`), otherwise they are skipped.
+    pub fn iter_type<Q: 'static, O: Send + Sync + 'static, I: 'static>(
         &self,
-        param: &K,
         runtime: &Runtime<I>,
-    ) -> Option<Arc<O>> {
-        self.id_map
+        recompute: bool,
+    ) -> impl Iterator<Item = (K, Arc<O>)> {
+        let params = self
+            .id_map
             .get(&QueryType::of::<Q>())
-            .and_then(|map| map.value().get(param).copied())
-            .and_then(|id| {
-                // The situation when id_map contains the query type and param,
-                // but query_map does not contain corresponding value, happens
-                // when we have started a query, but not finished it yet, and we
-                // are called again.
-                let data = self.query_map.get(&id)?;
-                let last_rev = runtime.last_rev_of(&data.dependencies);
-
-                if last_rev <= data.valid_at {
-                    Some(Arc::downcast(data.output.clone()).unwrap())
-                } else {
-                    None
-                }
+            .map(|params| params.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let recompute = recompute
+            .then(|| {
+                self.recompute_fns
+                    .get(&QueryType::of::<Q>())
+                    .map(|f| f.clone())
+            })
+            .flatten();
+
+        params
+            .into_iter()
+            .filter_map(|param| {
+                let output = match self.cached::<Q, O, I>(&param, runtime) {
+                    Some(output) => Some(output),
+                    None => recompute.as_ref().and_then(|recompute| {
+                        recompute(self, runtime, &param);
+                        self.cached::<Q, O, I>(&param, runtime)
+                    }),
+                };
+
+                output.map(|output| (param, output))
             })
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 
-    pub fn insert_with<'r, Q: 'static, O: Send + Sync + 'static, I, F>(
-        &self,
-        runtime: &'r Runtime<I>,
-        param: K,
-        f: F,
-    ) -> Arc<O>
-    where
-        F: FnOnce(&K, &QueryContext<'r, I>) -> O,
-        K: fmt::Debug,
-    {
-        self.try_insert_with::<Q, _, Cycle, _, _>(runtime, param, |param, ctx| Ok(f(param, ctx)))
-            .unwrap_or_else(|cycle| panic!("{:?}", self.debug_cycle(cycle)))
+    // Moves the cached query from the old param to the new one without
+    // recomputing it (e.g., when a file is renamed). This is sound only if the
+    // output of the query does not depend on the part of the param that
+    // changed. Returns whether the query was found.
+    //
+    // Not supported with deterministic ids, where the id is derived from the
+    // param: the moved query would keep the id of the old param. The query is
+    // left in place and `false` is returned then.
+    pub fn rekey<Q: 'static>(&self, old: &K, new: K) -> bool {
+        if self.deterministic_ids {
+            return false;
+        }
+
+        let old = self
+            .normalize_param::<Q>(old)
+            .unwrap_or_else(|| old.clone());
+        let new = self.normalize_param::<Q>(&new).unwrap_or(new);
+
+        let Some(mut map) = self.id_map.get_mut(&QueryType::of::<Q>()) else {
+            return false;
+        };
+
+        let Some(query_id) = map.remove(&old) else {
+            return false;
+        };
+
+        if let Some(replaced) = map.insert(new.clone(), query_id) {
+            self.query_map.remove(&replaced);
+        }
+
+        drop(map);
+
+        self.handle_map.remove(&self.stable_handle::<Q>(&old));
+        self.handle_map
+            .insert(self.stable_handle::<Q>(&new), query_id);
+
+        true
     }
 
-    pub fn try_insert_with<'r, Q: 'static, O: Send + Sync + 'static, E, I, F>(
-        &self,
-        runtime: &'r Runtime<I>,
-        param: K,
-        f: F,
-    ) -> Result<Arc<O>, E>
-    where
-        F: FnOnce(&K, &QueryContext<'r, I>) -> Result<O, E>,
-        E: From<Cycle>,
-    {
-        let query_id = *self
-            .id_map
-            .entry(QueryType::of::<Q>())
-            .or_default()
-            .entry(param.clone())
-            .or_insert_with(|| QueryId(self.query_id.fetch_add(1, Ordering::SeqCst)));
+    // Drops the output of the query, so that it is recomputed the next time,
+    // e.g., when it depends on something that is not an input. The id is kept.
+    // The queries that called it are not invalidated, for that the dependency
+    // must be external (see `Runtime::invalidate_external`). A computation of
+    // the query that is in progress on another thread is not affected, and
+    // its output is inserted when it finishes. Returns whether an output was
+    // dropped.
+    pub fn invalidate<Q: 'static>(&self, param: &K) -> bool {
+        match self.id::<Q>(param) {
+            Some(query_id) => self.drop_output(query_id),
+            None => false,
+        }
+    }
 
-        let guard = runtime.query_stack().push(query_id)?;
+    // Like `invalidate`, but for all params of the query. Returns the number
+    // of dropped outputs.
+    pub fn invalidate_all<Q: 'static>(&self) -> usize {
+        let query_ids = match self.id_map.get(&QueryType::of::<Q>()) {
+            Some(map) => map.values().copied().collect::<Vec<_>>(),
+            None => return 0,
+        };
 
-        let ctx = QueryContext::new(runtime);
-        let output = Arc::new(f(&param, &ctx)?);
-        let valid_at = runtime.rev();
-        let dependencies = ctx.into_dependencies();
+        query_ids
+            .into_iter()
+            .filter(|query_id| self.drop_output(*query_id))
+            .count()
+    }
 
-        drop(guard);
+    fn drop_output(&self, query_id: QueryId) -> bool {
+        self.derived_map
+            .retain(|(derived_id, _), _| *derived_id != query_id);
+        let spilled = self.spilled.remove(&query_id).is_some();
+        self.query_map.remove(&query_id).is_some() || spilled
+    }
 
-        self.query_map.insert(
-            query_id,
-            QueryData {
-                output: output.clone(),
-                valid_at,
-                dependencies,
-            },
-        );
+    // Fixes the leading part of a composite param, so that repeated calls
+    // specify only the varying part.
+    pub fn bind<Q: 'static, P>(&self, prefix: P) -> BoundQuery<'_, Q, K, P> {
+        BoundQuery {
+            cache: self,
+            prefix,
+            phantom: PhantomData,
+        }
+    }
 
-        Ok(output)
+    // The revision at which the output of the query last changed, which is
+    // older than `valid_at` if the query was recomputed with an equal output.
+    pub fn changed_at<Q: 'static>(&self, param: &K) -> Option<Revision> {
+        let id = self.id::<Q>(param)?;
+        self.query_map.get(&id).map(|data| data.changed_at)
+    }
+
+    // The label of the last computation of the query.
+    pub fn label<Q: 'static>(&self, param: &K) -> Option<String> {
+        let id = self.id::<Q>(param)?;
+        self.labels.get(&id).map(|label| label.to_string())
     }
 
     pub fn id<Q: 'static>(&self, param: &K) -> Option<QueryId> {
+        let normalized = self.normalize_param::<Q>(param);
+        let param = normalized.as_ref().unwrap_or(param);
+
         self.id_map
             .get(&QueryType::of::<Q>())
             .and_then(|map| map.get(param).copied())
@@ -134,42 +2432,628 @@ impl<K: Hash + Eq + Clone> QueryCache<K> {
     }
 }
 
+impl<K: fmt::Debug> QueryCache<K> {
+    // Fills `Cycle::participants` of the cycles reported by the cache, which
+    // are left empty otherwise.
+    pub fn render_cycle_participants(&self) {
+        *self.render_participants.write() = Some(|cache, cycle| {
+            let debug = CycleDebug { cache, cycle };
+            let participants = debug.to_strings();
+            let mut cycle = debug.cycle;
+            cycle.set_participants(participants);
+            cycle
+        });
+    }
+
+    // Renders the cached queries and the inputs they depend on in the Graphviz
+    // DOT format. The nodes are sorted, so that the output is stable.
+    pub fn to_dot(&self) -> String {
+        self.render_dot(|input| format!("input {}", input.0), |_| None)
+    }
+
+    // Like `to_dot`, but with the input names and the stale queries colored.
+    pub fn to_dot_with_validity<I: StorageGroup>(&self, runtime: &Runtime<I>) -> String {
+        self.render_dot(
+            |input| match I::input_name(input.0) {
+                Some(name) => name.to_string(),
+                None => format!("group {}", input.0),
+            },
+            |id| self.is_stale(runtime, id),
+        )
+    }
+
+    fn render_dot(
+        &self,
+        input_name: impl Fn(InputIndex) -> String,
+        stale: impl Fn(QueryId) -> Option<bool>,
+    ) -> String {
+        let escape = |label: String| label.replace('\\', "\\\\").replace('"', "\\\"");
+
+        let mut queries = self
+            .id_map
+            .iter()
+            .flat_map(|kv| {
+                let ty = *kv.key();
+                kv.iter()
+                    .map(|(param, id)| (ty.name(), format!("{:?}", param), *id))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        queries.sort();
+
+        // Called queries are drawn only if they have data too.
+        let nodes = queries
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, _, id))| self.query_map.contains_key(id))
+            .map(|(i, (_, _, id))| (*id, i))
+            .collect::<FxHashMap<_, _>>();
+
+        let mut dot = String::from("digraph queries {\n");
+        let mut edges = Vec::new();
+        let mut inputs = Vec::new();
+
+        for (i, (name, param, id)) in queries.into_iter().enumerate() {
+            let stale = stale(id);
+
+            // Queries being computed or evicted have no data.
+            let Some(data) = self.query_map.get(&id) else {
+                continue;
+            };
+
+            let color = match stale {
+                Some(true) => ", style=filled, fillcolor=salmon",
+                Some(false) => ", style=filled, fillcolor=palegreen",
+                None => "",
+            };
+            let label = escape(format!("{}({})", name, param));
+            dot.push_str(&format!("    q{} [label=\"{}\"{}];\n", i, label, color));
+
+            let mut dependencies = data.dependencies.clone();
+            dependencies.sort();
+
+            for (input, key) in dependencies {
+                edges.push(format!(
+                    "    q{} -> i{}_{}_{};\n",
+                    i, input.0, key.slot, key.generation
+                ));
+                inputs.push((input, key));
+            }
+
+            let mut called = data
+                .query_dependencies
+                .iter()
+                .filter_map(|called| nodes.get(called).copied())
+                .collect::<Vec<_>>();
+            called.sort();
+
+            for j in called {
+                edges.push(format!("    q{} -> q{};\n", i, j));
+            }
+        }
+
+        inputs.sort();
+        inputs.dedup();
+
+        for (input, key) in inputs {
+            let label = if key == KeyIndex::MISSING {
+                format!("{} (missing)", input_name(input))
+            } else {
+                format!("{} #{}", input_name(input), key.slot)
+            };
+            dot.push_str(&format!(
+                "    i{}_{}_{} [label=\"{}\", shape=box];\n",
+                input.0,
+                key.slot,
+                key.generation,
+                escape(label)
+            ));
+        }
+
+        edges.into_iter().for_each(|edge| dot.push_str(&edge));
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// What the runtime changed, so that the attached caches notify the observers
+// of the queries depending on it.
+#[derive(Clone, Copy)]
+pub(crate) enum RuntimeChange<'a> {
+    Inputs(&'a [(InputIndex, KeyIndex)]),
+    External(ExternalId),
+}
+
+// Implemented by the caches attached to a runtime, so that the runtime can
+// remap their dependencies when it compacts the inputs and tell them which
+// inputs or external dependencies changed.
+pub(crate) trait AttachedCache: Send + Sync {
+    fn remap_dependencies(&self, remap: &KeyRemap);
+    fn runtime_changed(&self, change: RuntimeChange<'_>);
+    // Number of cached queries that depend on any of the keys.
+    fn dependents_of(&self, keys: &[(InputIndex, KeyIndex)]) -> usize;
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync> AttachedCache for QueryCache<K> {
+    fn runtime_changed(&self, change: RuntimeChange<'_>) {
+        match change {
+            RuntimeChange::Inputs(changed) => self.notify_invalidated(|_, data| {
+                data.dependencies
+                    .iter()
+                    .any(|dependency| changed.contains(dependency))
+            }),
+            RuntimeChange::External(external) => {
+                self.notify_invalidated(|_, data| data.external_dependencies.contains(&external))
+            }
+        }
+    }
+
+    fn dependents_of(&self, keys: &[(InputIndex, KeyIndex)]) -> usize {
+        let mut dependents = FxHashSet::default();
+
+        for key in keys {
+            if let Some(ids) = self.reverse_deps.get(key) {
+                // The reverse index may refer to queries recomputed since
+                // without the dependency.
+                dependents.extend(ids.iter().copied().filter(|id| {
+                    self.query_map
+                        .get(id)
+                        .is_some_and(|data| data.dependencies.contains(key))
+                }));
+            }
+        }
+
+        dependents.len()
+    }
+
+    fn remap_dependencies(&self, remap: &KeyRemap) {
+        let remap_all = |dependencies: &mut Vec<(InputIndex, KeyIndex)>| {
+            let remapped = dependencies
+                .iter()
+                .map(|dependency| remap.get(*dependency))
+                .collect::<Option<Vec<_>>>();
+
+            match remapped {
+                Some(remapped) => {
+                    *dependencies = remapped;
+                    true
+                }
+                // The query used a removed key, so it is stale anyway.
+                None => false,
+            }
+        };
+
+        let mut evicted = FxHashSet::default();
+
+        self.query_map.retain(|id, data| {
+            let keep = remap_all(&mut data.dependencies);
+            if !keep {
+                evicted.insert(*id);
+            }
+            keep
+        });
+        self.spilled
+            .retain(|_, data| remap_all(&mut data.dependencies));
+        self.derived_map.retain(|(id, _), _| !evicted.contains(id));
+
+        self.reverse_deps.clear();
+        for kv in self.query_map.iter() {
+            for dependency in kv.dependencies.iter() {
+                self.reverse_deps
+                    .entry(*dependency)
+                    .or_default()
+                    .insert(*kv.key());
+            }
+        }
+    }
+}
+
 impl<K> Default for QueryCache<K> {
     fn default() -> Self {
         Self {
             id_map: Default::default(),
+            param_hasher: ParamHasher::Fx,
             query_map: Default::default(),
+            reverse_deps: Default::default(),
+            pending_query_deps: Default::default(),
+            flights: Default::default(),
+            waiting: Default::default(),
+            recovered: Default::default(),
+            derived_map: Default::default(),
+            heap_size_hooks: Default::default(),
+            cycle_exempt: Default::default(),
+            volatile: Default::default(),
+            recompute_counts: Default::default(),
+            handle_map: Default::default(),
+            param_normalizers: Default::default(),
+            output_eqs: Default::default(),
+            cache_predicates: Default::default(),
+            cycle_policies: Default::default(),
+            fixpoints: Default::default(),
+            secondary: RwLock::new(None),
+            codecs: Default::default(),
+            spilled: Default::default(),
+            recompute_fns: Default::default(),
+            invalidation_observers: Default::default(),
+            observer_id: AtomicU64::new(0),
+            labels: Default::default(),
+            eviction: EvictionPolicy::None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            recomputations: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            invalidation_logger: Default::default(),
+            observer: Default::default(),
+            render_participants: Default::default(),
+            soft_limit: Default::default(),
+            inserts: Default::default(),
+            #[cfg(feature = "test-hooks")]
+            test_hook: Default::default(),
+            tick: Default::default(),
             query_id: Default::default(),
+            deterministic_ids: false,
+            allocated_ids: Default::default(),
+            limiter: None,
         }
     }
 }
 
 pub struct QueryContext<'r, I> {
     dependencies: FxDashSet<(InputIndex, KeyIndex)>,
+    external_dependencies: FxDashSet<ExternalId>,
+    query_dependencies: Arc<FxDashSet<QueryId>>,
+    // Inputs already read by the query, keyed by the input and the hash of the
+    // key, so that repeated reads do not lock the storage again.
+    read_inputs: FxDashMap<(InputIndex, u64), Box<dyn Any + Send + Sync>>,
     runtime: &'r Runtime<I>,
+    overrides: Option<&'r Overrides<I>>,
+    previous: Option<PreviousRun<'r>>,
+    on_dependency: RwLock<Option<DependencyCallback>>,
+    started: Instant,
+    ops: AtomicU64,
 }
 
 impl<'r, I> QueryContext<'r, I> {
     fn new(runtime: &'r Runtime<I>) -> Self {
         Self {
             dependencies: Default::default(),
+            external_dependencies: Default::default(),
+            query_dependencies: Default::default(),
+            read_inputs: Default::default(),
             runtime,
+            overrides: None,
+            previous: None,
+            on_dependency: RwLock::new(None),
+            started: Instant::now(),
+            ops: AtomicU64::new(0),
+        }
+    }
+
+    // Registers a callback called each time a new input dependency is
+    // recorded, with the name of the input read, so that the progress of a
+    // long query can be observed before it finishes.
+    pub fn on_dependency(
+        &self,
+        callback: impl Fn(InputIndex, KeyIndex, &'static str) + Send + Sync + 'static,
+    ) {
+        *self.on_dependency.write() = Some(Box::new(callback));
+    }
+
+    fn record_dependency(&self, dependency: (InputIndex, KeyIndex), name: &'static str) {
+        if self.dependencies.insert(dependency) {
+            if let Some(callback) = self.on_dependency.read().as_ref() {
+                callback(dependency.0, dependency.1, name);
+            }
         }
     }
 
     pub fn use_input<T>(&self, key: &T::Key) -> Option<T::Value>
     where
-        T: Input<StorageGroup = I>,
+        T: InputOf<I>,
+        T::Key: Clone + Send + Sync + 'static,
+        T::Value: Send + Sync + 'static,
     {
-        let (value, key_index) = self
+        if let Some((value, _)) = self
+            .overrides
+            .and_then(|overrides| T::storage_in(&overrides.inputs).get(key))
+        {
+            return Some(value);
+        }
+
+        let mut hasher = FxHasher::default();
+        key.hash(&mut hasher);
+        let read_key = (T::index(), hasher.finish());
+
+        // The dependency was already recorded by the first read.
+        if let Some(read) = self.read_inputs.get(&read_key) {
+            if let Some((read_key, value)) = read.downcast_ref::<(T::Key, Option<T::Value>)>() {
+                if read_key == key {
+                    return value.clone();
+                }
+            }
+        }
+
+        let (value, key_index) = match self
             .runtime
-            .with_storage::<T, _, _>(|storage| storage.get(key))?;
-        self.dependencies.insert((InputIndex(T::INDEX), key_index));
-        Some(value)
+            .with_storage::<T, _, _>(|storage| storage.get(key))
+        {
+            Some((value, key_index)) => (Some(value), key_index),
+            // The query must be invalidated when the key is set.
+            None => (None, KeyIndex::MISSING),
+        };
+        let dependency = T::group(key).unwrap_or((T::index(), key_index));
+        self.record_dependency(dependency, T::name());
+
+        // Keys with clashing hashes replace each other.
+        self.read_inputs
+            .insert(read_key, Box::new((key.clone(), value.clone())));
+
+        value
+    }
+
+    // Whether the query exhausted the yield budget of the runtime. Long-running
+    // queries can check it periodically and, when it is exhausted, return a
+    // resumable state (e.g., `Pending(progress)`) instead of the final result,
+    // and continue from the state in the next scheduler tick.
+    pub fn should_yield(&self) -> bool {
+        match self.runtime.yield_budget() {
+            Some(YieldBudget::Time(budget)) => self.started.elapsed() >= budget,
+            Some(YieldBudget::Ops(budget)) => self.ops.fetch_add(1, Ordering::Relaxed) >= budget,
+            None => false,
+        }
+    }
+
+    // Unlike the value, using the metadata does not create a dependency.
+    pub fn input_metadata<T>(&self, key: &T::Key) -> Option<T::Metadata>
+    where
+        T: InputOf<I>,
+        T::Metadata: Clone,
+    {
+        self.runtime.get_input_metadata::<T>(key)
+    }
+
+    // Every key in the range is recorded as a dependency. So is any key missing
+    // from the input, as in `use_input`, so that the keys inserted into the
+    // range later invalidate the query (along with those inserted elsewhere).
+    pub fn use_input_range<T, R>(&self, range: R) -> Vec<(T::Key, T::Value)>
+    where
+        T: InputOf<I>,
+        T::Key: Clone,
+        T::KeyMap: OrderedKeyMap<T::Key>,
+        R: RangeBounds<T::Key>,
+    {
+        let values = self.runtime.with_storage::<T, _, _>(|storage| {
+            storage
+                .range(range)
+                .map(|(key, value, key_index)| {
+                    let dependency = T::group(key).unwrap_or((T::index(), key_index));
+                    self.record_dependency(dependency, T::name());
+                    (key.clone(), value.clone())
+                })
+                .collect()
+        });
+
+        self.record_dependency((T::index(), KeyIndex::MISSING), T::name());
+
+        values
+    }
+
+    // The query is invalidated whenever `Runtime::invalidate_external` is
+    // called with the same token.
+    pub fn add_external_dependency(&self, external: ExternalId) {
+        self.external_dependencies.insert(external);
+    }
+
+    // The query is invalidated whenever the query of the same cache (or any
+    // query it called) changes. The queries found in the cache or computed
+    // during the computation are recorded automatically, this is for outputs
+    // obtained otherwise. Queries of other caches are not tracked.
+    pub fn use_query(&self, query_id: QueryId) {
+        self.query_dependencies.insert(query_id);
+    }
+
+    // Marks the dependencies recorded so far, so that the ones recorded later
+    // (e.g., on a speculative branch) can be discarded by
+    // `rollback_dependencies`.
+    pub fn checkpoint_dependencies(&self) -> DependencyCheckpoint {
+        DependencyCheckpoint {
+            dependencies: self
+                .dependencies
+                .iter()
+                .map(|dependency| *dependency)
+                .collect(),
+            external_dependencies: self
+                .external_dependencies
+                .iter()
+                .map(|external| *external)
+                .collect(),
+            query_dependencies: self
+                .query_dependencies
+                .iter()
+                .map(|query_id| *query_id)
+                .collect(),
+        }
+    }
+
+    // Discards the dependencies recorded since the checkpoint. The queries
+    // called since stay cached.
+    pub fn rollback_dependencies(&self, checkpoint: DependencyCheckpoint) {
+        self.dependencies
+            .retain(|dependency| checkpoint.dependencies.contains(dependency));
+        self.external_dependencies
+            .retain(|external| checkpoint.external_dependencies.contains(external));
+        self.query_dependencies
+            .retain(|query_id| checkpoint.query_dependencies.contains(query_id));
+        // Reading the discarded inputs again must record them again.
+        self.read_inputs.clear();
+    }
+
+    // Whether none of the inputs used by the last memoized run of the query
+    // changed since, for early cutoff in the query body (e.g., returning the
+    // `previous_output`). If so, the dependencies of the last run are recorded
+    // for this one. False if the query was not computed before.
+    pub fn unchanged_since_last_run(&self) -> bool {
+        let Some(previous) = self.previous else {
+            return false;
+        };
+        let memos = previous.memos;
+        let Some(data) = previous.data() else {
+            return false;
+        };
+
+        let inputs_rev = memos.inputs_rev(
+            self.runtime,
+            previous.query_id,
+            &data.dependencies,
+            &data.external_dependencies,
+        );
+        let dependencies = data.dependencies.clone();
+        let external_dependencies = data.external_dependencies.clone();
+        let query_dependencies = data.query_dependencies.clone();
+        drop(data);
+
+        let queries_rev =
+            memos.queries_changed_at(self.runtime, &query_dependencies, &mut FxHashMap::default());
+        if inputs_rev.max(queries_rev) > previous.valid_at {
+            return false;
+        }
+
+        for dependency in dependencies {
+            self.dependencies.insert(dependency);
+        }
+        for external in external_dependencies {
+            self.external_dependencies.insert(external);
+        }
+        for query_id in query_dependencies {
+            self.query_dependencies.insert(query_id);
+        }
+        true
+    }
+
+    // The output of the last memoized run of the query, if it has type `O`.
+    pub fn previous_output<O: Send + Sync + 'static>(&self) -> Option<Arc<O>> {
+        let output = self.previous?.data()?.output.clone();
+        Arc::downcast(output).ok()
+    }
+
+    fn into_dependencies(self) -> (Vec<(InputIndex, KeyIndex)>, Vec<ExternalId>, Vec<QueryId>) {
+        (
+            self.dependencies.into_iter().collect(),
+            self.external_dependencies.into_iter().collect(),
+            self.query_dependencies
+                .iter()
+                .map(|query_id| *query_id)
+                .collect(),
+        )
+    }
+}
+
+pub struct DependencyCheckpoint {
+    dependencies: FxHashSet<(InputIndex, KeyIndex)>,
+    external_dependencies: FxHashSet<ExternalId>,
+    query_dependencies: FxHashSet<QueryId>,
+}
+
+// Composes a param from its fixed prefix and the rest.
+pub trait ComposeParam<P, R> {
+    fn compose(prefix: P, rest: R) -> Self;
+}
+
+impl<A, B> ComposeParam<A, B> for (A, B) {
+    fn compose(prefix: A, rest: B) -> Self {
+        (prefix, rest)
+    }
+}
+
+impl<A, B, C> ComposeParam<A, (B, C)> for (A, B, C) {
+    fn compose(prefix: A, (b, c): (B, C)) -> Self {
+        (prefix, b, c)
+    }
+}
+
+impl<A, B, C> ComposeParam<(A, B), C> for (A, B, C) {
+    fn compose((a, b): (A, B), rest: C) -> Self {
+        (a, b, rest)
+    }
+}
+
+pub struct BoundQuery<'a, Q, K, P> {
+    cache: &'a QueryCache<K>,
+    prefix: P,
+    phantom: PhantomData<Q>,
+}
+
+impl<Q: 'static, K: Hash + Eq + Clone, P: Clone> BoundQuery<'_, Q, K, P> {
+    pub fn param<R>(&self, rest: R) -> K
+    where
+        K: ComposeParam<P, R>,
+    {
+        K::compose(self.prefix.clone(), rest)
+    }
+
+    pub fn cached<R, O: Send + Sync + 'static, I>(
+        &self,
+        rest: R,
+        runtime: &Runtime<I>,
+    ) -> Option<Arc<O>>
+    where
+        K: ComposeParam<P, R>,
+    {
+        self.cache.cached::<Q, O, I>(&self.param(rest), runtime)
+    }
+
+    pub fn insert_with<'r, R, O: Send + Sync + 'static, I, F>(
+        &'r self,
+        runtime: &'r Runtime<I>,
+        rest: R,
+        f: F,
+    ) -> Arc<O>
+    where
+        K: ComposeParam<P, R> + fmt::Debug,
+        F: FnOnce(&K, &QueryContext<'r, I>) -> O,
+    {
+        self.cache
+            .insert_with::<Q, O, I, F>(runtime, self.param(rest), f)
+    }
+}
+
+// Input values that shadow the actual inputs in `QueryCache::compute_isolated`.
+pub struct Overrides<I> {
+    inputs: I,
+}
+
+impl<I: Default> Overrides<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<I> Overrides<I> {
+    pub fn set<T>(&mut self, key: T::Key, value: T::Value) -> &mut Self
+    where
+        T: InputOf<I>,
+    {
+        T::storage_in_mut(&mut self.inputs).set(key, value);
+        self
+    }
+}
+
+impl<I: Default> Default for Overrides<I> {
+    fn default() -> Self {
+        Self {
+            inputs: Default::default(),
+        }
     }
+}
 
-    fn into_dependencies(self) -> Vec<(InputIndex, KeyIndex)> {
-        self.dependencies.into_iter().collect()
+pub struct InvalidationSubscription<'a, K> {
+    cache: &'a QueryCache<K>,
+    id: u64,
+}
+
+impl<K> Drop for InvalidationSubscription<'_, K> {
+    fn drop(&mut self) {
+        self.cache.invalidation_observers.lock().remove(&self.id);
     }
 }
 
@@ -178,8 +3062,17 @@ pub struct CycleDebug<'a, K> {
     cycle: Cycle,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleNode {
+    pub query_name: &'static str,
+    pub param_debug: String,
+    // The label the query was computed with, if any.
+    pub label: Option<String>,
+    pub query_id: QueryId,
+}
+
 impl<K: fmt::Debug> CycleDebug<'_, K> {
-    pub fn to_strings(&self) -> Vec<String> {
+    pub fn nodes(&self) -> Vec<CycleNode> {
         self.cycle
             .cycle()
             .iter()
@@ -188,7 +3081,12 @@ impl<K: fmt::Debug> CycleDebug<'_, K> {
                     let ty = *kv.key();
                     let iter = kv.iter().filter_map(move |(param, id)| {
                         if id == query_id {
-                            Some(format!("{}({:?})", ty.name(), param))
+                            Some(CycleNode {
+                                query_name: ty.name(),
+                                param_debug: format!("{:?}", param),
+                                label: self.cache.labels.get(id).map(|label| label.to_string()),
+                                query_id: *id,
+                            })
                         } else {
                             None
                         }
@@ -199,11 +3097,26 @@ impl<K: fmt::Debug> CycleDebug<'_, K> {
                 all
             })
     }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.nodes()
+            .into_iter()
+            .map(|node| {
+                let param = node.label.as_ref().unwrap_or(&node.param_debug);
+                format!("{}({})", node.query_name, param)
+            })
+            .collect()
+    }
 }
 
+// Exceeding the maximum depth is reported with the whole stack, which is not
+// necessarily a cycle.
 impl<K: fmt::Debug> fmt::Debug for CycleDebug<'_, K> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Cycle {{ cycle: [ ")?;
+        match self.cycle.max_depth() {
+            Some(max_depth) => write!(f, "maximum query depth {} exceeded, stack: [ ", max_depth)?,
+            None => write!(f, "Cycle {{ cycle: [ ")?,
+        }
 
         let mut iter = self.to_strings().into_iter();
 
@@ -215,7 +3128,11 @@ impl<K: fmt::Debug> fmt::Debug for CycleDebug<'_, K> {
             write!(f, ", {}", entry)?;
         }
 
-        write!(f, " ] }}")
+        if self.cycle.is_depth_exceeded() {
+            write!(f, " ]")
+        } else {
+            write!(f, " ] }}")
+        }
     }
 }
 
@@ -227,7 +3144,7 @@ impl QueryType {
         }
     }
 
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &'static str {
         self.name
     }
 }
@@ -241,7 +3158,7 @@ impl PartialEq for QueryType {
 impl Eq for QueryType {}
 
 impl Hash for QueryType {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: Hasher>(&self, state: &mut H) {
         self.type_id.hash(state);
     }
 }
@@ -1,31 +1,153 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, sync::Arc};
 
 use crate::query::QueryId;
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub(crate) struct QueryStack {
     active: RefCell<Vec<QueryId>>,
+    // Queries saved by `Runtime::save_query_stack`, which are still being
+    // computed, but hidden from the cycle detection.
+    suspended: RefCell<Vec<QueryId>>,
+    // Snapshot of the stack of the parent computation, see
+    // `Runtime::with_inherited_stack`.
+    inherited: Arc<[QueryId]>,
+    observer: Option<StackObserver>,
+}
+
+pub(crate) type StackObserver = Arc<dyn Fn(StackEvent) + Send + Sync>;
+
+// Reported to the observer set by `Runtime::set_stack_observer`. The depth is
+// the number of queries below on the stack, including the inherited ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEvent {
+    Enter { query_id: QueryId, depth: usize },
+    Exit { query_id: QueryId, depth: usize },
 }
 
 impl QueryStack {
-    pub fn push(&self, query_id: QueryId) -> Result<ActiveQueryGuard<'_>, Cycle> {
+    pub fn inherit(&self) -> Self {
+        let inherited = self
+            .inherited
+            .iter()
+            .chain(self.active.borrow().iter())
+            .copied()
+            .collect();
+
+        Self {
+            active: Default::default(),
+            suspended: Default::default(),
+            inherited,
+            observer: self.observer.clone(),
+        }
+    }
+
+    // An empty stack with the same observer.
+    pub fn detached(&self) -> Self {
+        Self {
+            observer: self.observer.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_observer(&mut self, observer: Option<StackObserver>) {
+        self.observer = observer;
+    }
+
+    // Suspends the active queries, returning their number.
+    pub fn suspend(&self) -> usize {
+        let active = self.active.take();
+        let count = active.len();
+        self.suspended.borrow_mut().extend(active);
+        count
+    }
+
+    pub fn resume(&self, count: usize) {
+        let mut current = self.active.borrow_mut();
+        assert!(
+            current.is_empty(),
+            "query stack restored while queries started after saving it are active"
+        );
+        let mut suspended = self.suspended.borrow_mut();
+        let at = suspended.len() - count;
+        *current = suspended.split_off(at);
+    }
+
+    // The query being computed, including the suspended and inherited ones.
+    pub fn top(&self) -> Option<QueryId> {
+        self.active
+            .borrow()
+            .last()
+            .or(self.suspended.borrow().last())
+            .or(self.inherited.last())
+            .copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inherited.is_empty()
+            && self.suspended.borrow().is_empty()
+            && self.active.borrow().is_empty()
+    }
+
+    // The number of queries below the active ones, which count to the depth.
+    fn base_depth(&self) -> usize {
+        self.inherited.len() + self.suspended.borrow().len()
+    }
+
+    // Exempt queries are never reported as a repetition. Exceeding the maximum
+    // depth is reported as a cycle consisting of the whole stack.
+    pub fn push(
+        &self,
+        query_id: QueryId,
+        exempt: bool,
+        max_depth: usize,
+    ) -> Result<ActiveQueryGuard<'_>, Cycle> {
         let mut active = self.active.borrow_mut();
+        let stack = || self.inherited.iter().chain(active.iter()).copied();
+        let base_depth = self.base_depth();
+
+        if base_depth + active.len() >= max_depth {
+            let suspended = self.suspended.borrow();
+            let mut cycle = self
+                .inherited
+                .iter()
+                .chain(suspended.iter())
+                .chain(active.iter())
+                .copied()
+                .collect::<Vec<_>>();
+            cycle.push(query_id);
 
-        if let Some(cycle_start) = active
+            let mut cycle = Cycle::new(cycle);
+            cycle.max_depth = Some(max_depth);
+            return Err(cycle);
+        }
+
+        let cycle_start = active
             .iter()
-            .copied()
-            .enumerate()
-            .rev()
-            .find_map(|(i, on_stack)| (on_stack == query_id).then(|| i))
-        {
-            let mut cycle = active[cycle_start..].to_vec();
+            .rposition(|on_stack| *on_stack == query_id)
+            .map(|i| self.inherited.len() + i)
+            .or_else(|| {
+                self.inherited
+                    .iter()
+                    .rposition(|on_stack| *on_stack == query_id)
+            });
+
+        if let Some(cycle_start) = cycle_start.filter(|_| !exempt) {
+            let mut cycle = stack().skip(cycle_start).collect::<Vec<_>>();
             cycle.push(query_id);
 
-            return Err(Cycle { cycle });
+            return Err(Cycle::new(cycle));
         }
 
         active.push(query_id);
         let pop_at = active.len();
+        let depth = base_depth + pop_at - 1;
+
+        // The observer may use the stack too.
+        drop(active);
+
+        if let Some(observer) = self.observer.as_ref() {
+            observer(StackEvent::Enter { query_id, depth });
+        }
 
         Ok(ActiveQueryGuard {
             query_stack: self,
@@ -43,17 +165,76 @@ impl Drop for ActiveQueryGuard<'_> {
     fn drop(&mut self) {
         let mut active = self.query_stack.active.borrow_mut();
         assert_eq!(active.len(), self.pop_at);
-        active.pop();
+        let query_id = active.pop().unwrap();
+        drop(active);
+
+        if let Some(observer) = self.query_stack.observer.as_ref() {
+            let depth = self.query_stack.base_depth() + self.pop_at - 1;
+            observer(StackEvent::Exit { query_id, depth });
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct Cycle {
     cycle: Vec<QueryId>,
+    // Rendered when the cycle is reported by the cache, so that it can be shown
+    // without the cache.
+    participants: Vec<String>,
+    // The stack got deeper than allowed, which is not necessarily a cycle.
+    max_depth: Option<usize>,
 }
 
 impl Cycle {
+    pub(crate) fn new(cycle: Vec<QueryId>) -> Self {
+        Self {
+            cycle,
+            participants: Vec::new(),
+            max_depth: None,
+        }
+    }
+
+    // The whole stack is reported as the cycle then, see
+    // `Runtime::set_max_query_depth`.
+    pub fn is_depth_exceeded(&self) -> bool {
+        self.max_depth.is_some()
+    }
+
+    // The maximum depth that was exceeded, if any.
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
     pub fn cycle(&self) -> &[QueryId] {
         self.cycle.as_slice()
     }
+
+    // The queries on the cycle in the `name(param)` format of
+    // `CycleDebug::to_strings`. Empty unless the cache that reported the cycle
+    // opted in with `QueryCache::render_cycle_participants`.
+    pub fn participants(&self) -> &[String] {
+        self.participants.as_slice()
+    }
+
+    pub(crate) fn set_participants(&mut self, participants: Vec<String>) {
+        self.participants = participants;
+    }
+}
+
+// An error type for the queries that distinguishes exceeding the maximum depth
+// from a real cycle.
+#[derive(Debug)]
+pub enum QueryError {
+    Cycle(Cycle),
+    DepthExceeded(Cycle),
+}
+
+impl From<Cycle> for QueryError {
+    fn from(cycle: Cycle) -> Self {
+        if cycle.is_depth_exceeded() {
+            QueryError::DepthExceeded(cycle)
+        } else {
+            QueryError::Cycle(cycle)
+        }
+    }
 }
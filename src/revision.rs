@@ -1,9 +1,15 @@
-use std::num::NonZeroU64;
+// With the `revision-u32` feature, revisions take half the memory in the maps
+// of input revisions, but the runtime panics after 2^32 - 1 input changes.
+#[cfg(feature = "revision-u32")]
+type Raw = std::num::NonZeroU32;
+#[cfg(not(feature = "revision-u32"))]
+type Raw = std::num::NonZeroU64;
 
 const START: u64 = 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Revision(NonZeroU64);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Revision(Raw);
 
 impl Revision {
     pub fn new() -> Self {
@@ -11,15 +17,19 @@ impl Revision {
     }
 
     pub fn increment(&mut self) {
-        *self = Self::from(self.0.get() + 1)
+        *self = Self::from(self.as_raw() + 1)
     }
 
+    // The conversions are needed only with the `revision-u32` feature.
+    #[allow(clippy::useless_conversion)]
     pub fn as_raw(&self) -> u64 {
-        self.0.get()
+        self.0.get().into()
     }
 
+    #[allow(clippy::useless_conversion)]
     fn from(raw: u64) -> Self {
-        Self(NonZeroU64::new(raw).unwrap())
+        let raw = raw.try_into().expect("revision overflow");
+        Self(Raw::new(raw).unwrap())
     }
 }
 
@@ -1,100 +1,827 @@
-use std::sync::Arc;
+use std::{
+    hash::Hash,
+    marker::PhantomData,
+    mem,
+    ops::RangeBounds,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+#[cfg(feature = "revision-stats")]
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
 
 use parking_lot::RwLock;
 use rustc_hash::FxHashMap;
 
 use crate::{
-    input::{Input, InputIndex, InputStorage, KeyIndex},
+    input::{
+        Change, ExternalId, InputChange, InputIndex, InputOf, InputStorage, KeyIndex,
+        OrderedKeyMap, StorageGroup,
+    },
+    query::{AttachedCache, QueryCache, QueryId, RuntimeChange},
     query_stack::QueryStack,
     revision::Revision,
 };
 
+pub use crate::query_stack::StackEvent;
+
 #[derive(Default)]
 pub struct Runtime<I> {
     shared: Arc<RwLock<SharedState<I>>>,
     query_stack: QueryStack,
     query_lock: Arc<RwLock<()>>,
+    yield_budget: Option<YieldBudget>,
+    missing_dependency_policy: MissingDependencyPolicy,
+    lock_fairness: LockFairness,
+}
+
+// How much work a query can do before `QueryContext::should_yield` asks it to
+// yield control back to the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldBudget {
+    Time(Duration),
+    // Number of `should_yield` calls.
+    Ops(u64),
+}
+
+// What to do when a query depends on a key index that has no revision, which
+// happens only if the dependencies got out of sync with the storage (e.g., a
+// cache not attached to the runtime during compaction).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingDependencyPolicy {
+    // The dependency is treated as changed in the current revision.
+    #[default]
+    Invalidate,
+    // Panics, for catching the dangling dependencies as bugs.
+    Error,
+}
+
+// Checks the manifest hash before deserializing the inputs, which could
+// otherwise succeed with the inputs at wrong indices.
+#[cfg(feature = "serde")]
+struct PersistedInputs<I>(PhantomData<I>);
+
+#[cfg(feature = "serde")]
+impl<'de, I: StorageGroup + serde::Deserialize<'de>> serde::de::Visitor<'de>
+    for PersistedInputs<I>
+{
+    type Value = (Revision, I);
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("manifest hash, revision and inputs")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        use serde::de::Error;
+
+        let hash = seq
+            .next_element::<u64>()?
+            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        if hash != I::MANIFEST_HASH {
+            return Err(A::Error::custom(format!(
+                "persisted manifest hash {:x} does not match {:x}",
+                hash,
+                I::MANIFEST_HASH
+            )));
+        }
+
+        let rev = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+        let inputs = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(2, &self))?;
+
+        Ok((rev, inputs))
+    }
+}
+
+// Whether the queries or the input updates take precedence when waiting for
+// the lock separating them, see `Runtime::lock_readonly`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockFairness {
+    // Once an input update is waiting, new queries wait until it is done, so
+    // the updates are not starved by a continuous stream of queries.
+    #[default]
+    WriterPreferring,
+    // The queries never wait for a waiting input update, which can then wait
+    // indefinitely under sustained query load.
+    ReaderPreferring,
+}
+
+pub struct RuntimeBuilder<I> {
+    lock_fairness: LockFairness,
+    phantom: PhantomData<I>,
+}
+
+impl<I: Default> RuntimeBuilder<I> {
+    pub fn lock_fairness(mut self, fairness: LockFairness) -> Self {
+        self.lock_fairness = fairness;
+        self
+    }
+
+    pub fn build(self) -> Runtime<I> {
+        Runtime {
+            lock_fairness: self.lock_fairness,
+            ..Default::default()
+        }
+    }
 }
 
 impl<I: Default> Runtime<I> {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn builder() -> RuntimeBuilder<I> {
+        RuntimeBuilder {
+            lock_fairness: LockFairness::default(),
+            phantom: PhantomData,
+        }
+    }
 }
 
 impl<I> Runtime<I> {
     pub fn get_input<T>(&self, key: &T::Key) -> Option<T::Value>
     where
-        T: Input<StorageGroup = I>,
+        T: InputOf<I>,
     {
         self.with_storage::<T, _, _>(|storage| storage.get(key).map(|(value, _)| value))
     }
 
-    pub fn set_input<T>(&mut self, key: T::Key, value: T::Value)
+    pub fn range_input<T, R>(&self, range: R) -> impl Iterator<Item = (T::Key, T::Value)>
+    where
+        T: InputOf<I>,
+        T::Key: Clone,
+        T::KeyMap: OrderedKeyMap<T::Key>,
+        R: RangeBounds<T::Key>,
+    {
+        self.with_storage::<T, _, _>(|storage| {
+            storage
+                .range(range)
+                .map(|(key, value, _)| (key.clone(), value.clone()))
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+    }
+
+    // Returns the previous value of the key, if it was set.
+    pub fn set_input<T>(&mut self, key: T::Key, value: T::Value) -> Option<T::Value>
+    where
+        T: InputOf<I>,
+    {
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+
+        let (changed, previous) = set_locked::<I, T>(&mut shared, key, value);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            input = T::name(),
+            changed = !changed.is_empty(),
+            rev = shared.rev.as_raw(),
+            "input set"
+        );
+
+        let caches = shared.caches.clone();
+        drop(shared);
+        drop(guard);
+
+        notify_caches(caches, RuntimeChange::Inputs(&changed));
+        previous
+    }
+
+    // Like `set_input`, but returns whether the value differs from the
+    // previous one. Unlike `set_input`, setting an equal value does not start
+    // a new revision either.
+    pub fn set_input_if_changed<T>(&mut self, key: T::Key, value: T::Value) -> bool
+    where
+        T: InputOf<I>,
+    {
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+
+        let group = T::group(&key);
+        let (key_index, change) = T::storage_in_mut(&mut shared.inputs).set_changed(key, value);
+
+        if change == Change::Unchanged {
+            return false;
+        }
+
+        let changed = record_change::<I, T>(&mut shared, key_index, change, group);
+
+        let caches = shared.caches.clone();
+        drop(shared);
+        drop(guard);
+
+        notify_caches(caches, RuntimeChange::Inputs(&changed));
+        true
+    }
+
+    // Modifies the value in place, with a single lookup of the key. Returns
+    // whether the key is set. Like with `set_input`, modifying the value to an
+    // equal one does not invalidate the dependent queries.
+    pub fn update_input<T, F>(&mut self, key: T::Key, f: F) -> bool
+    where
+        T: InputOf<I>,
+        F: FnOnce(&mut T::Value),
+    {
+        self.modify_input::<T, _, _>(key, f, None::<fn() -> T::Value>)
+    }
+
+    // Like `update_input`, but sets the default if the key is not set. Returns
+    // whether the key was set before.
+    pub fn set_input_with<T, D, F>(&mut self, key: T::Key, default: D, f: F) -> bool
+    where
+        T: InputOf<I>,
+        D: FnOnce() -> T::Value,
+        F: FnOnce(&mut T::Value),
+    {
+        self.modify_input::<T, _, _>(key, f, Some(default))
+    }
+
+    fn modify_input<T, F, D>(&mut self, key: T::Key, f: F, default: Option<D>) -> bool
+    where
+        T: InputOf<I>,
+        F: FnOnce(&mut T::Value),
+        D: FnOnce() -> T::Value,
+    {
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+
+        let group = T::group(&key);
+        let entry = T::storage_in_mut(&mut shared.inputs)
+            .entry(key)
+            .and_modify(f);
+        let existed = entry.key_index().is_some();
+
+        let modified = match default {
+            Some(default) => Some(entry.or_insert_with_changed(default)),
+            None => entry.modified(),
+        };
+
+        let Some((key_index, change)) = modified else {
+            return false;
+        };
+
+        let changed = record_change::<I, T>(&mut shared, key_index, change, group);
+
+        let caches = shared.caches.clone();
+        drop(shared);
+        drop(guard);
+
+        notify_caches(caches, RuntimeChange::Inputs(&changed));
+        existed
+    }
+
+    // Sets the value only if the current value equals the expected one, which
+    // is checked under the same lock. Returns whether the value was set. The
+    // values are compared with `PartialEq` even if the input does not compare
+    // them when set (see `Input::value_eq`).
+    pub fn compare_and_set_input<T>(
+        &mut self,
+        key: T::Key,
+        expected: &T::Value,
+        new: T::Value,
+    ) -> bool
+    where
+        T: InputOf<I>,
+        T::Value: PartialEq,
+    {
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+
+        let matches = T::storage_in(&shared.inputs)
+            .get(&key)
+            .is_some_and(|(value, _)| value == *expected);
+
+        if !matches {
+            return false;
+        }
+
+        let (changed, _) = set_locked::<I, T>(&mut shared, key, new);
+
+        let caches = shared.caches.clone();
+        drop(shared);
+        drop(guard);
+
+        notify_caches(caches, RuntimeChange::Inputs(&changed));
+        true
+    }
+
+    // Removes the key and invalidates the queries that depend on it. Only the
+    // queries in the attached caches are counted as invalidated.
+    pub fn remove_input<T>(&mut self, key: &T::Key) -> RemoveResult<T::Value>
     where
-        T: Input<StorageGroup = I>,
+        T: InputOf<I>,
     {
         let guard = self.query_lock.write();
         let mut shared = self.shared.write();
 
-        let key_index = T::storage_mut(&mut shared.inputs).set(key, value);
+        let Some((previous, key_index)) = T::storage_in_mut(&mut shared.inputs).remove(key) else {
+            return RemoveResult {
+                previous: None,
+                dependents_invalidated: 0,
+            };
+        };
 
-        shared.rev.increment();
+        shared.increment_rev();
         let rev = shared.rev;
 
-        shared
-            .input_revs
-            .insert((InputIndex(T::INDEX), key_index), rev);
+        let dependency = T::group(key).unwrap_or((T::index(), key_index));
+        shared.input_revs.insert(dependency, rev);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(input = T::name(), rev = rev.as_raw(), "input removed");
 
+        let caches = shared.caches.clone();
+        drop(shared);
         drop(guard);
+
+        let dependents_invalidated = caches
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|cache| cache.dependents_of(&[dependency]))
+            .sum();
+
+        notify_caches(caches, RuntimeChange::Inputs(&[dependency]));
+
+        RemoveResult {
+            previous: Some(previous),
+            dependents_invalidated,
+        }
+    }
+
+    // The index the queries using the key depend on, which is shared by the
+    // inputs of a group.
+    pub fn input_key_index<T>(&self, key: &T::Key) -> Option<(InputIndex, KeyIndex)>
+    where
+        T: InputOf<I>,
+    {
+        self.with_storage::<T, _, _>(|storage| storage.get(key))
+            .map(|(_, key_index)| T::group(key).unwrap_or((T::index(), key_index)))
+    }
+
+    // The version of the input value, which is incremented only when the value
+    // actually changes, unlike the revision.
+    pub fn input_version<T>(&self, key: &T::Key) -> Option<u64>
+    where
+        T: InputOf<I>,
+    {
+        self.with_storage::<T, _, _>(|storage| storage.version(key))
+    }
+
+    // Setting metadata does not create a new revision.
+    pub fn set_input_metadata<T>(&mut self, key: &T::Key, metadata: T::Metadata) -> bool
+    where
+        T: InputOf<I>,
+    {
+        let mut shared = self.shared.write();
+        T::storage_in_mut(&mut shared.inputs).set_metadata(key, metadata)
     }
 
-    pub fn remove_input<T>(&mut self, key: &T::Key)
+    pub fn get_input_metadata<T>(&self, key: &T::Key) -> Option<T::Metadata>
     where
-        T: Input<StorageGroup = I>,
+        T: InputOf<I>,
+        T::Metadata: Clone,
+    {
+        self.with_storage::<T, _, _>(|storage| storage.metadata(key).cloned())
+    }
+
+    // Applies all changes made in the transaction under a single lock
+    // acquisition. All changed inputs share a single new revision. The changes
+    // made before a panic in `f` are committed too, so that the queries using
+    // the changed inputs are not considered valid.
+    pub fn transaction<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Transaction<'_, I>) -> R,
     {
         let guard = self.query_lock.write();
         let mut shared = self.shared.write();
 
-        if let Some((_, key_index)) = T::storage_mut(&mut shared.inputs).remove(key) {
-            shared.rev.increment();
+        let mut tx = Transaction {
+            inputs: &mut shared.inputs,
+            changed: Vec::new(),
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut tx)));
+        let changed = tx.changed;
+
+        if !changed.is_empty() {
+            shared.increment_rev();
             let rev = shared.rev;
 
             shared
                 .input_revs
-                .insert((InputIndex(T::INDEX), key_index), rev);
+                .extend(changed.iter().map(|index| (*index, rev)));
         }
 
+        let caches = shared.caches.clone();
+        drop(shared);
+        drop(guard);
+
+        notify_caches(caches, RuntimeChange::Inputs(&changed));
+
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    pub fn invalidate_external(&mut self, external: ExternalId) {
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+
+        shared.increment_rev();
+        let rev = shared.rev;
+
+        shared.external_revs.insert(external, rev);
+
+        let caches = shared.caches.clone();
+        drop(shared);
         drop(guard);
+
+        notify_caches(caches, RuntimeChange::External(external));
+    }
+
+    pub fn approx_memory_bytes(&self) -> usize
+    where
+        I: StorageGroup,
+    {
+        let shared = self.shared.read();
+        let input_rev = mem::size_of::<(InputIndex, KeyIndex)>() + mem::size_of::<Revision>();
+        let external_rev = mem::size_of::<ExternalId>() + mem::size_of::<Revision>();
+
+        shared.inputs.approx_memory_bytes()
+            + shared.input_revs.capacity() * input_rev
+            + shared.external_revs.capacity() * external_rev
+    }
+
+    // Registers the cache so that its dependencies are remapped by `compact`
+    // and its invalidation observers are notified when the inputs are set or
+    // the external dependencies invalidated. The cache is held weakly.
+    pub fn attach_cache<K>(&self, cache: &Arc<QueryCache<K>>)
+    where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+    {
+        let cache: Arc<dyn AttachedCache> = cache.clone();
+        self.shared.write().caches.push(Arc::downgrade(&cache));
+    }
+
+    // Compacts the storage of all inputs (see `InputStorage::compact`) and
+    // remaps the dependencies recorded by the runtime and by the attached
+    // caches, evicting the queries that depend on removed keys. Caches that are
+    // not attached must be cleared, because their dependencies would refer to
+    // the wrong keys. Meant to be called when idle, as it blocks all queries.
+    pub fn compact(&mut self)
+    where
+        I: StorageGroup,
+    {
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+        let shared = &mut *shared;
+
+        let remap = shared.inputs.compact();
+
+        shared.input_revs = shared
+            .input_revs
+            .drain()
+            .filter_map(|(dependency, rev)| Some((remap.get(dependency)?, rev)))
+            .collect();
+        shared.external_revs.shrink_to_fit();
+
+        shared.caches.retain(|cache| match cache.upgrade() {
+            Some(cache) => {
+                cache.remap_dependencies(&remap);
+                true
+            }
+            None => false,
+        });
+
+        drop(guard);
+    }
+
+    // Clones the inputs at the current revision into a read-only view, which
+    // does not block updates of the runtime, e.g. for sending the inputs to a
+    // worker. Cloning the snapshot is cheap.
+    pub fn input_snapshot(&self) -> InputSnapshot<I>
+    where
+        I: Clone,
+    {
+        let shared = self.shared.read();
+
+        InputSnapshot {
+            rev: shared.rev,
+            inputs: Arc::new(shared.inputs.clone()),
+        }
+    }
+
+    // Persists the inputs with the current revision, e.g., to skip ingesting
+    // them again on the next run. The cached queries are not persisted. The
+    // hash of the manifest is persisted too, see `load_inputs`.
+    #[cfg(feature = "serde")]
+    pub fn dump_inputs<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        I: StorageGroup + serde::Serialize,
+    {
+        use serde::Serialize;
+
+        let shared = self.shared.read();
+        (I::MANIFEST_HASH, shared.rev, &shared.inputs).serialize(serializer)
+    }
+
+    // Replaces the inputs with the persisted ones. The revision continues from
+    // the persisted one, unless the runtime is already past it. All inputs are
+    // considered changed, so the queries cached before are invalidated. Fails
+    // if the inputs were persisted with a different manifest (e.g., by an older
+    // build with other inputs), whose indices would not match.
+    #[cfg(feature = "serde")]
+    pub fn load_inputs<'de, D: serde::Deserializer<'de>>(
+        &mut self,
+        deserializer: D,
+    ) -> Result<(), D::Error>
+    where
+        I: StorageGroup + serde::Deserialize<'de>,
+    {
+        let (rev, inputs) = deserializer.deserialize_tuple(3, PersistedInputs::<I>(PhantomData))?;
+
+        let guard = self.query_lock.write();
+        let mut shared = self.shared.write();
+
+        if rev > shared.rev {
+            shared.rev = rev;
+        } else {
+            shared.rev.increment();
+        }
+        let rev = shared.rev;
+        // The revisions before the load do not count into the stats.
+        #[cfg(feature = "revision-stats")]
+        shared.clock.reset(rev);
+
+        let changed = shared
+            .input_revs
+            .keys()
+            .copied()
+            .chain(inputs.dependencies())
+            .chain(
+                I::INPUT_MANIFEST
+                    .iter()
+                    .map(|(_, index)| (InputIndex(*index), KeyIndex::MISSING)),
+            )
+            .collect::<Vec<_>>();
+
+        shared.inputs = inputs;
+        shared
+            .input_revs
+            .extend(changed.iter().map(|index| (*index, rev)));
+
+        let caches = shared.caches.clone();
+        drop(shared);
+        drop(guard);
+
+        notify_caches(caches, RuntimeChange::Inputs(&changed));
+        Ok(())
+    }
+
+    // Input changes since the snapshot was taken, e.g., for sending only the
+    // changes to a replica.
+    pub fn diff(&self, old: &InputSnapshot<I>) -> Vec<InputChange>
+    where
+        I: StorageGroup,
+    {
+        self.with_storage_group(|inputs| inputs.diff(&old.inputs))
+    }
+
+    pub fn current_revision(&self) -> Revision {
+        self.rev()
+    }
+
+    // How many revisions passed and how fast, e.g., for caching more
+    // aggressively when the inputs change rarely.
+    #[cfg(feature = "revision-stats")]
+    pub fn revision_stats(&self) -> RevisionStats {
+        let shared = self.shared.read();
+        let clock = &shared.clock;
+        let elapsed = clock.started_at.elapsed();
+        let now = elapsed.as_nanos() as u64;
+
+        let at = clock.sample_at.load(Ordering::Acquire);
+        let since_sample = Duration::from_nanos(now.saturating_sub(at));
+        let sampled = clock.rate.load(Ordering::Acquire) != RevisionClock::NO_RATE;
+        if at != RevisionClock::NOT_STARTED
+            && ((!sampled && !since_sample.is_zero()) || since_sample >= RATE_SAMPLE_INTERVAL)
+            // Only one of the concurrent readers takes the sample.
+            && clock
+                .sample_at
+                .compare_exchange(at, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            let previous_rev = clock.sample_rev.swap(shared.rev.as_raw(), Ordering::AcqRel);
+            let revisions = (shared.rev.as_raw() - previous_rev) as f64;
+            let rate = revisions / since_sample.as_secs_f64();
+            let previous = clock.rate.load(Ordering::Acquire);
+            // Exponential moving average giving the recent sample half weight.
+            let rate = match previous {
+                RevisionClock::NO_RATE => rate,
+                previous => (f64::from_bits(previous) + rate) / 2.0,
+            };
+            clock.rate.store(rate.to_bits(), Ordering::Release);
+        }
+
+        let rate = clock.rate.load(Ordering::Acquire);
+        RevisionStats {
+            current: shared.rev,
+            initial: clock.initial,
+            elapsed,
+            change_rate: (rate != RevisionClock::NO_RATE).then(|| f64::from_bits(rate)),
+        }
+    }
+
+    // Gives read access to the whole storage group, e.g. for custom
+    // serialization. Reading does not record any dependency.
+    pub fn with_storage_group<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&I) -> R,
+    {
+        f(&self.shared.read().inputs)
     }
 
     pub(crate) fn with_storage<T, F, R>(&self, f: F) -> R
     where
-        T: Input<StorageGroup = I>,
+        T: InputOf<I>,
         F: FnOnce(&InputStorage<T>) -> R,
     {
-        f(T::storage(&self.shared.read().inputs))
+        f(T::storage_in(&self.shared.read().inputs))
     }
 
     pub(crate) fn rev(&self) -> Revision {
         self.shared.read().rev
     }
 
-    pub(crate) fn last_rev_of(&self, dependencies: &[(InputIndex, KeyIndex)]) -> Revision {
+    pub(crate) fn last_rev_of(
+        &self,
+        dependencies: &[(InputIndex, KeyIndex)],
+        external: &[ExternalId],
+    ) -> Revision {
         let shared = self.shared.read();
-        dependencies
+        // Missing keys of inputs to which no key was set yet have not changed.
+        let inputs = dependencies
             .iter()
-            .map(|index| shared.input_revs[index])
-            .max()
-            .unwrap_or_default()
+            .filter_map(|index| self.input_rev(&shared, index));
+        // External dependency that was never invalidated has not changed.
+        let external = external
+            .iter()
+            .filter_map(|external| shared.external_revs.get(external).copied());
+
+        inputs.chain(external).max().unwrap_or_default()
+    }
+
+    // Finds the dependency that changed after given revision.
+    pub(crate) fn stale_dependency(
+        &self,
+        dependencies: &[(InputIndex, KeyIndex)],
+        external: &[ExternalId],
+        valid_at: Revision,
+    ) -> Option<StaleDependency> {
+        let shared = self.shared.read();
+
+        let input = dependencies.iter().find_map(|index| {
+            let changed_at = self.input_rev(&shared, index)?;
+            (changed_at > valid_at).then_some(StaleDependency::Input {
+                input: index.0 .0,
+                key: index.1,
+                changed_at,
+            })
+        });
+
+        input.or_else(|| {
+            external.iter().find_map(|external| {
+                let changed_at = shared.external_revs.get(external).copied()?;
+                (changed_at > valid_at).then_some(StaleDependency::External {
+                    external: *external,
+                    changed_at,
+                })
+            })
+        })
+    }
+
+    fn input_rev(
+        &self,
+        shared: &SharedState<I>,
+        index: &(InputIndex, KeyIndex),
+    ) -> Option<Revision> {
+        match shared.input_revs.get(index) {
+            Some(rev) => Some(*rev),
+            None if index.1.is_storage_independent() => None,
+            None => match self.missing_dependency_policy {
+                MissingDependencyPolicy::Invalidate => Some(shared.rev),
+                MissingDependencyPolicy::Error => {
+                    panic!("dependency on {:?} has no revision", index)
+                }
+            },
+        }
+    }
+
+    pub fn set_missing_dependency_policy(&mut self, policy: MissingDependencyPolicy) {
+        self.missing_dependency_policy = policy;
+    }
+
+    pub fn set_yield_budget(&mut self, budget: Option<YieldBudget>) {
+        self.yield_budget = budget;
+    }
+
+    // Limits how deeply the queries can call each other in all caches, so that
+    // a long chain of queries fails instead of overflowing the native stack.
+    // Exceeding it is reported as a cycle of the whole stack, which converts to
+    // `QueryError::DepthExceeded`. Clones of the runtime share the limit.
+    pub fn set_max_query_depth(&mut self, max_depth: usize) {
+        self.shared.write().max_query_depth = Some(max_depth);
+    }
+
+    // Called whenever a query is pushed to or popped from the query stack, so
+    // that a profiler can reconstruct the tree of query computations. Clones of
+    // the runtime share the observer.
+    pub fn set_stack_observer(&mut self, observer: impl Fn(StackEvent) + Send + Sync + 'static) {
+        self.query_stack.set_observer(Some(Arc::new(observer)));
+    }
+
+    pub(crate) fn yield_budget(&self) -> Option<YieldBudget> {
+        self.yield_budget
+    }
+
+    pub(crate) fn max_query_depth(&self) -> usize {
+        self.shared.read().max_query_depth.unwrap_or(usize::MAX)
     }
 
     pub(crate) fn query_stack(&self) -> &QueryStack {
         &self.query_stack
     }
 
+    // Creates a clone for a sub-computation spawned by the currently computed
+    // query (e.g., on another thread), which detects cycles through the queries
+    // on the current stack. The stack is captured when the clone is created,
+    // so the sub-computation must finish before the spawning query does.
+    // Otherwise, it reports cycles with queries that are no longer computed.
+    pub fn with_inherited_stack(&self) -> Self {
+        Self {
+            query_stack: self.query_stack.inherit(),
+            ..self.clone()
+        }
+    }
+
+    // Hides the query stack of this runtime from the cycle detection so that a
+    // foreign call re-entering the queries (e.g., from an interpreter with its
+    // own call stack) does not report false cycles. The saved queries are still
+    // computed, so they record the queries called in the meantime as their
+    // dependencies. The snapshot must be restored before any query that was
+    // active when saving finishes, and only after all queries started in
+    // between finish. Cycles through the saved queries are not detected in the
+    // meantime.
+    pub fn save_query_stack(&self) -> QueryStackSnapshot {
+        QueryStackSnapshot {
+            suspended: self.query_stack.suspend(),
+        }
+    }
+
+    pub fn restore_query_stack(&self, snapshot: QueryStackSnapshot) {
+        self.query_stack.resume(snapshot.suspended);
+    }
+
+    // By default, once a writer (an input update) is waiting, new readers
+    // block until it is done, see `LockFairness`. The lock taken within a query
+    // (nested or inherited) never waits for the writers, because the writer
+    // waits for the outer lock to be released, which would be a deadlock.
     pub fn lock_readonly(&self) -> ReadOnlyGuard<'_> {
-        ReadOnlyGuard(self.query_lock.read())
+        let guard = if self.waits_for_writers() {
+            self.query_lock.read()
+        } else {
+            self.query_lock.read_recursive()
+        };
+
+        ReadOnlyGuard { _guard: guard }
+    }
+
+    // Like `lock_readonly`, but returns `None` instead of blocking.
+    pub fn try_lock_readonly(&self) -> Option<ReadOnlyGuard<'_>> {
+        let guard = if self.waits_for_writers() {
+            self.query_lock.try_read()
+        } else {
+            self.query_lock.try_read_recursive()
+        };
+
+        guard.map(|guard| ReadOnlyGuard { _guard: guard })
+    }
+
+    fn waits_for_writers(&self) -> bool {
+        self.lock_fairness == LockFairness::WriterPreferring && self.query_stack.is_empty()
+    }
+
+    // A consistent view of the inputs for running a batch of queries. No input
+    // can be set or removed while the snapshot is alive, not even through a
+    // clone of the runtime.
+    pub fn snapshot(&self) -> Snapshot<'_, I> {
+        Snapshot {
+            runtime: self,
+            _guard: self.lock_readonly(),
+        }
     }
 }
 
@@ -103,8 +830,11 @@ impl<I> Clone for Runtime<I> {
         Self {
             shared: self.shared.clone(),
             // Query stack is local to every thread.
-            query_stack: Default::default(),
+            query_stack: self.query_stack.detached(),
             query_lock: self.query_lock.clone(),
+            yield_budget: self.yield_budget,
+            missing_dependency_policy: self.missing_dependency_policy,
+            lock_fairness: self.lock_fairness,
         }
     }
 }
@@ -114,6 +844,311 @@ struct SharedState<I> {
     rev: Revision,
     inputs: I,
     input_revs: FxHashMap<(InputIndex, KeyIndex), Revision>,
+    external_revs: FxHashMap<ExternalId, Revision>,
+    caches: Vec<Weak<dyn AttachedCache>>,
+    #[cfg(feature = "revision-stats")]
+    clock: RevisionClock,
+    max_query_depth: Option<usize>,
+}
+
+impl<I> SharedState<I> {
+    fn increment_rev(&mut self) {
+        #[cfg(feature = "revision-stats")]
+        self.clock.start(self.rev);
+        self.rev.increment();
+    }
 }
 
-pub struct ReadOnlyGuard<'a>(parking_lot::RwLockReadGuard<'a, ()>);
+// The last sample is kept in atomics, so that reading the stats does not
+// serialize the readers of the runtime.
+#[cfg(feature = "revision-stats")]
+struct RevisionClock {
+    started_at: Instant,
+    initial: Revision,
+    // Nanoseconds since `started_at`, `NOT_STARTED` until the first revision.
+    sample_at: AtomicU64,
+    sample_rev: AtomicU64,
+    // Bits of the `f64` rate, `NO_RATE` until first sampled.
+    rate: AtomicU64,
+}
+
+#[cfg(feature = "revision-stats")]
+impl RevisionClock {
+    // A NaN, which is never a sampled rate.
+    const NO_RATE: u64 = u64::MAX;
+    const NOT_STARTED: u64 = u64::MAX;
+
+    // The rate is measured from the first revision, not from the creation of
+    // the runtime.
+    fn start(&mut self, rev: Revision) {
+        if *self.sample_at.get_mut() == Self::NOT_STARTED {
+            *self.sample_at.get_mut() = self.started_at.elapsed().as_nanos() as u64;
+            *self.sample_rev.get_mut() = rev.as_raw();
+        }
+    }
+
+    fn reset(&mut self, rev: Revision) {
+        self.initial = rev;
+        *self.sample_at.get_mut() = Self::NOT_STARTED;
+        *self.sample_rev.get_mut() = rev.as_raw();
+        *self.rate.get_mut() = Self::NO_RATE;
+    }
+}
+
+#[cfg(feature = "revision-stats")]
+impl Default for RevisionClock {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            initial: Revision::new(),
+            sample_at: AtomicU64::new(Self::NOT_STARTED),
+            sample_rev: AtomicU64::new(Revision::new().as_raw()),
+            rate: AtomicU64::new(Self::NO_RATE),
+        }
+    }
+}
+
+// The change rate is sampled at most this often.
+#[cfg(feature = "revision-stats")]
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(feature = "revision-stats")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RevisionStats {
+    pub current: Revision,
+    // The revision when the runtime was created or the inputs were loaded.
+    pub initial: Revision,
+    pub elapsed: Duration,
+    // Moving average of revisions per second, `None` until first sampled.
+    pub change_rate: Option<f64>,
+}
+
+#[cfg(feature = "revision-stats")]
+impl RevisionStats {
+    // Number of revisions since the runtime was created or the inputs were
+    // loaded.
+    pub fn revisions(&self) -> u64 {
+        self.current.as_raw() - self.initial.as_raw()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoveResult<V> {
+    // The removed value, `None` if the key was not set.
+    pub previous: Option<V>,
+    // Number of cached queries in the attached caches that depended on the
+    // key.
+    pub dependents_invalidated: usize,
+}
+
+#[derive(Debug)]
+pub struct QueryStackSnapshot {
+    // Number of the queries suspended by saving the stack.
+    suspended: usize,
+}
+
+// A dependency that invalidated a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleDependency {
+    Input {
+        // Index of the input, see `StorageGroup::input_name`.
+        input: u16,
+        key: KeyIndex,
+        changed_at: Revision,
+    },
+    External {
+        external: ExternalId,
+        changed_at: Revision,
+    },
+    // A query called by the invalidated one, see `QueryContext::use_query`.
+    Query {
+        query_id: QueryId,
+        changed_at: Revision,
+    },
+    // The output was recovered from a cycle, so it is invalidated by any
+    // change, see `QueryCache::try_insert_with_recovery`.
+    Recovered {
+        changed_at: Revision,
+    },
+}
+
+pub struct Transaction<'a, I> {
+    inputs: &'a mut I,
+    changed: Vec<(InputIndex, KeyIndex)>,
+}
+
+impl<I> Transaction<'_, I> {
+    pub fn set<T>(&mut self, key: T::Key, value: T::Value)
+    where
+        T: InputOf<I>,
+    {
+        let group = T::group(&key);
+        let (key_index, change) = T::storage_in_mut(self.inputs).set_changed(key, value);
+        self.changed
+            .extend(change.changed_keys(T::index(), key_index, group));
+    }
+
+    pub fn remove<T>(&mut self, key: &T::Key)
+    where
+        T: InputOf<I>,
+    {
+        if let Some((_, key_index)) = T::storage_in_mut(self.inputs).remove(key) {
+            self.changed
+                .push(T::group(key).unwrap_or((T::index(), key_index)));
+        }
+    }
+}
+
+// Called after the locks are released, so that the caches can run user
+// callbacks.
+fn set_locked<I, T>(
+    shared: &mut SharedState<I>,
+    key: T::Key,
+    value: T::Value,
+) -> (Vec<(InputIndex, KeyIndex)>, Option<T::Value>)
+where
+    T: InputOf<I>,
+{
+    let group = T::group(&key);
+    let (key_index, change, previous) = T::storage_in_mut(&mut shared.inputs).replace(key, value);
+
+    (
+        record_change::<I, T>(shared, key_index, change, group),
+        previous,
+    )
+}
+
+fn record_change<I, T>(
+    shared: &mut SharedState<I>,
+    key_index: KeyIndex,
+    change: Change,
+    group: Option<(InputIndex, KeyIndex)>,
+) -> Vec<(InputIndex, KeyIndex)>
+where
+    T: InputOf<I>,
+{
+    shared.increment_rev();
+    let rev = shared.rev;
+
+    // Setting an equal value does not invalidate the dependent queries.
+    let changed = change
+        .changed_keys(T::index(), key_index, group)
+        .collect::<Vec<_>>();
+
+    for index in changed.iter() {
+        shared.input_revs.insert(*index, rev);
+    }
+
+    changed
+}
+
+fn notify_caches(caches: Vec<Weak<dyn AttachedCache>>, change: RuntimeChange<'_>) {
+    if let RuntimeChange::Inputs([]) = change {
+        return;
+    }
+
+    for cache in caches.iter().filter_map(Weak::upgrade) {
+        cache.runtime_changed(change);
+    }
+}
+
+#[derive(Debug)]
+pub struct InputSnapshot<I> {
+    rev: Revision,
+    inputs: Arc<I>,
+}
+
+impl<I> InputSnapshot<I> {
+    pub fn revision(&self) -> Revision {
+        self.rev
+    }
+
+    pub fn get<T>(&self, key: &T::Key) -> Option<T::Value>
+    where
+        T: InputOf<I>,
+    {
+        T::storage_in(&self.inputs).get(key).map(|(value, _)| value)
+    }
+
+    pub fn range<T, R>(&self, range: R) -> impl Iterator<Item = (T::Key, T::Value)>
+    where
+        T: InputOf<I>,
+        T::Key: Clone,
+        T::KeyMap: OrderedKeyMap<T::Key>,
+        R: RangeBounds<T::Key>,
+    {
+        T::storage_in(&self.inputs)
+            .range(range)
+            .map(|(key, value, _)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    pub fn with_storage_group<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&I) -> R,
+    {
+        f(&self.inputs)
+    }
+
+    // Input changes between the old snapshot and this one.
+    pub fn diff(&self, old: &InputSnapshot<I>) -> Vec<InputChange>
+    where
+        I: StorageGroup,
+    {
+        self.inputs.diff(&old.inputs)
+    }
+}
+
+impl<I> Clone for InputSnapshot<I> {
+    fn clone(&self) -> Self {
+        Self {
+            rev: self.rev,
+            inputs: self.inputs.clone(),
+        }
+    }
+}
+
+// Unlike `InputSnapshot`, it does not clone the inputs, but blocks their
+// updates instead.
+pub struct Snapshot<'a, I> {
+    runtime: &'a Runtime<I>,
+    _guard: ReadOnlyGuard<'a>,
+}
+
+impl<'a, I> Snapshot<'a, I> {
+    // For running the queries, which see the same inputs as the snapshot.
+    pub fn runtime(&self) -> &'a Runtime<I> {
+        self.runtime
+    }
+
+    pub fn revision(&self) -> Revision {
+        self.runtime.rev()
+    }
+
+    pub fn get_input<T>(&self, key: &T::Key) -> Option<T::Value>
+    where
+        T: InputOf<I>,
+    {
+        self.runtime.get_input::<T>(key)
+    }
+
+    pub fn with_storage<T, F, R>(&self, f: F) -> R
+    where
+        T: InputOf<I>,
+        F: FnOnce(&InputStorage<T>) -> R,
+    {
+        self.runtime.with_storage::<T, _, _>(f)
+    }
+
+    pub fn with_storage_group<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&I) -> R,
+    {
+        self.runtime.with_storage_group(f)
+    }
+}
+
+pub struct ReadOnlyGuard<'a> {
+    _guard: parking_lot::RwLockReadGuard<'a, ()>,
+}
@@ -169,6 +169,7 @@ impl<P: Clone + Eq + Hash> RealSystem<P> {
         self.try_query::<_, R, Cycle>(param, f)
     }
 
+    #[allow(clippy::manual_inspect)]
     pub fn try_query<F, R, E>(&self, param: P, f: F) -> Result<Arc<R>, E>
     where
         F: FnOnce(&dyn Database, &P, &AnySystem<'_, P>) -> Result<R, E> + 'static,
@@ -211,6 +212,12 @@ impl<P: Clone + Eq + Hash> RealSystem<P> {
     }
 }
 
+impl<P: fmt::Debug> RealSystem<P> {
+    pub fn render_cycle_participants(&self) {
+        self.queries.render_cycle_participants();
+    }
+}
+
 impl<P> Default for RealSystem<P> {
     fn default() -> Self {
         Self::new(true)
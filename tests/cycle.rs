@@ -1,4 +1,12 @@
-use inqui::Cycle;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use inqui::{query::CyclePolicy, Cycle, QueryCache, QueryError, Runtime};
 
 mod common;
 
@@ -14,6 +22,7 @@ fn foo(_: &dyn Database, n: &u32, system: &AnySystem<'_, u32>) -> Result<u32, Cy
     }
 }
 
+#[allow(clippy::manual_is_multiple_of)]
 fn bar(_: &dyn Database, n: &u32, system: &AnySystem<'_, u32>) -> Result<u32, Cycle> {
     let n = *n;
 
@@ -34,6 +43,7 @@ fn cycle1() {
 
     let result = system.query_or_cycle(12, foo);
     assert!(result.is_err());
+    assert!(result.as_ref().unwrap_err().participants().is_empty());
 
     let cycle = system.debug_cycle(result.unwrap_err()).to_strings();
     assert_eq!(
@@ -47,3 +57,413 @@ fn cycle1() {
         ]
     );
 }
+
+#[test]
+fn cycle_participants() {
+    let system = RealSystem::default();
+    system.render_cycle_participants();
+
+    // Rendered without the cache.
+    let cycle = system.query_or_cycle(12, foo).unwrap_err();
+    assert_eq!(
+        cycle.participants(),
+        &[
+            "cycle::bar(2)",
+            "cycle::foo(2)",
+            "cycle::bar(1)",
+            "cycle::baz(1)",
+            "cycle::bar(2)"
+        ]
+    );
+}
+
+#[test]
+fn cycle_nodes() {
+    let system = RealSystem::default();
+
+    let cycle = system.query_or_cycle(12, foo).unwrap_err();
+    let ids = cycle.cycle().to_vec();
+    let nodes = system.debug_cycle(cycle).nodes();
+
+    assert_eq!(nodes.len(), 5);
+    assert_eq!(nodes[0].query_name, "cycle::bar");
+    assert_eq!(nodes[0].param_debug, "2");
+    assert_eq!(nodes[0].query_id, nodes[4].query_id);
+    assert_eq!(
+        nodes.iter().map(|node| node.query_id).collect::<Vec<_>>(),
+        ids
+    );
+}
+
+struct Repl;
+
+fn repl(
+    queries: &QueryCache<()>,
+    runtime: &Runtime<()>,
+    remaining: &AtomicU32,
+) -> Result<Arc<u32>, Cycle> {
+    queries.try_insert_with::<Repl, _, Cycle, _, _>(runtime, (), |_, _| {
+        if remaining.fetch_sub(1, Ordering::SeqCst) > 0 {
+            Ok(*repl(queries, runtime, remaining)? + 1)
+        } else {
+            Ok(0)
+        }
+    })
+}
+
+#[test]
+fn cycle_exempt() {
+    let mut runtime = Runtime::new();
+    let queries = QueryCache::new();
+
+    assert!(repl(&queries, &runtime, &AtomicU32::new(2)).is_err());
+
+    queries.set_cycle_exempt::<Repl>();
+    assert_eq!(*repl(&queries, &runtime, &AtomicU32::new(2)).unwrap(), 2);
+
+    runtime.set_max_query_depth(8);
+    let cycle = repl(&queries, &runtime, &AtomicU32::new(100)).unwrap_err();
+    assert_eq!(cycle.cycle().len(), 9);
+}
+
+struct Spawning;
+
+fn spawning(queries: &QueryCache<u32>, runtime: &Runtime<()>, n: u32) -> Result<Arc<u32>, Cycle> {
+    queries.try_insert_with::<Spawning, _, Cycle, _, _>(runtime, n, |n, _| {
+        if *n == 0 {
+            return Ok(0);
+        }
+
+        let child = runtime.with_inherited_stack();
+        // Spawns the same query for one, which is a cycle.
+        let next = if *n == 1 { 1 } else { *n - 2 };
+
+        std::thread::scope(|scope| {
+            scope
+                .spawn(move || spawning(queries, &child, next).map(|output| *output + 1))
+                .join()
+                .unwrap()
+        })
+    })
+}
+
+#[test]
+fn inherited_stack() {
+    let runtime = Runtime::new();
+    let queries = QueryCache::new();
+
+    assert_eq!(*spawning(&queries, &runtime, 2).unwrap(), 1);
+
+    let cycle = spawning(&queries, &runtime, 3).unwrap_err();
+    assert_eq!(cycle.cycle(), &[queries.id::<Spawning>(&1).unwrap(); 2]);
+}
+
+struct Interpreted;
+
+fn interpret(
+    queries: &QueryCache<u32>,
+    runtime: &Runtime<()>,
+    n: u32,
+    save: bool,
+) -> Result<Arc<u32>, Cycle> {
+    queries.try_insert_with::<Interpreted, _, Cycle, _, _>(runtime, n, |n, _| {
+        if *n == 0 {
+            return Ok(0);
+        }
+
+        // The foreign call evaluates the same expression in a fresh frame.
+        let snapshot = save.then(|| runtime.save_query_stack());
+        let inner = interpret(queries, runtime, 0, save).and_then(|_| {
+            queries
+                .try_insert_with::<Interpreted, _, Cycle, _, _>(runtime, *n, |_, _| Ok(1))
+                .map(|output| *output)
+        });
+
+        if let Some(snapshot) = snapshot {
+            runtime.restore_query_stack(snapshot);
+        }
+
+        Ok(inner? + 1)
+    })
+}
+
+#[test]
+fn saved_query_stack() {
+    let runtime = Runtime::new();
+    let queries = QueryCache::new();
+
+    assert!(interpret(&queries, &runtime, 1, false).is_err());
+    assert_eq!(*interpret(&queries, &runtime, 1, true).unwrap(), 2);
+}
+
+struct TypeCheck;
+
+fn type_check(
+    queries: &QueryCache<u64>,
+    runtime: &Runtime<()>,
+    file: u64,
+    path: &str,
+) -> Result<Arc<u32>, Cycle> {
+    queries.try_insert_with_label::<TypeCheck, _, Cycle, _, _>(runtime, file, path, |file, _| {
+        // Every file imports the first one.
+        if *file != 0 {
+            Ok(*type_check(queries, runtime, 0, "src/main.rs")? + 1)
+        } else {
+            Ok(0)
+        }
+    })
+}
+
+#[test]
+fn labels() {
+    let runtime = Runtime::new();
+    let queries = QueryCache::new();
+
+    assert_eq!(*type_check(&queries, &runtime, 1, "src/lib.rs").unwrap(), 1);
+    assert_eq!(
+        queries.label::<TypeCheck>(&0).as_deref(),
+        Some("src/main.rs")
+    );
+    assert_eq!(
+        queries.label::<TypeCheck>(&1).as_deref(),
+        Some("src/lib.rs")
+    );
+
+    struct Recursive;
+
+    let cycle = queries
+        .try_insert_with_label::<Recursive, u32, Cycle, _, _>(&runtime, 7, "main", |_, _| {
+            queries
+                .try_insert_with::<Recursive, u32, Cycle, _, _>(&runtime, 7, |_, _| Ok(0))
+                .map(|output| *output)
+        })
+        .unwrap_err();
+
+    let strings = queries.debug_cycle(cycle).to_strings();
+    assert_eq!(strings, &["cycle::labels::Recursive(main)"; 2]);
+}
+
+struct Reachable;
+
+// Nodes reachable from given node in a graph with cycles.
+fn reachable(
+    queries: &QueryCache<u32>,
+    runtime: &Runtime<()>,
+    edges: &[(u32, u32)],
+    node: u32,
+) -> Result<Arc<BTreeSet<u32>>, Cycle> {
+    queries.try_insert_fixpoint::<Reachable, _, Cycle, _, _>(runtime, node, |node, _| {
+        let mut reachable = BTreeSet::from([*node]);
+
+        for (_, to) in edges.iter().filter(|(from, _)| from == node) {
+            reachable.extend(reachable_from(queries, runtime, edges, *to)?.iter());
+        }
+
+        Ok(reachable)
+    })
+}
+
+fn reachable_from(
+    queries: &QueryCache<u32>,
+    runtime: &Runtime<()>,
+    edges: &[(u32, u32)],
+    node: u32,
+) -> Result<Arc<BTreeSet<u32>>, Cycle> {
+    match queries.cached::<Reachable, BTreeSet<u32>, _>(&node, runtime) {
+        Some(reachable) => Ok(reachable),
+        None => reachable(queries, runtime, edges, node),
+    }
+}
+
+#[test]
+fn cycle_policy() {
+    let runtime = Runtime::new();
+    let queries = QueryCache::new();
+    let edges = [(0, 1), (1, 2), (2, 0), (2, 3)];
+
+    assert!(reachable(&queries, &runtime, &edges, 0).is_err());
+
+    queries.set_cycle_policy::<Reachable, BTreeSet<u32>>(CyclePolicy::Fixpoint {
+        bottom: BTreeSet::new(),
+        join: |a, b| a.union(b).copied().collect(),
+    });
+
+    let all = BTreeSet::from([0, 1, 2, 3]);
+    assert_eq!(*reachable(&queries, &runtime, &edges, 0).unwrap(), all);
+    assert_eq!(*reachable_from(&queries, &runtime, &edges, 1).unwrap(), all);
+    assert_eq!(
+        *reachable_from(&queries, &runtime, &edges, 3).unwrap(),
+        BTreeSet::from([3])
+    );
+
+    let queries = QueryCache::new();
+    queries.set_cycle_policy::<Repl, u32>(CyclePolicy::Recover(|cycle| cycle.cycle().len() as u32));
+
+    // The recovery value replaces the re-entrant call.
+    assert_eq!(*repl(&queries, &runtime, &AtomicU32::new(1)).unwrap(), 3);
+}
+
+#[test]
+fn stack_observer() {
+    use inqui::runtime::StackEvent;
+    use std::sync::Mutex;
+
+    let mut runtime = Runtime::new();
+    let queries = QueryCache::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = events.clone();
+    runtime.set_stack_observer(move |event| recorded.lock().unwrap().push(event));
+
+    repl(&queries, &runtime.clone(), &AtomicU32::new(0)).unwrap();
+    queries.set_cycle_exempt::<Repl>();
+    repl(&queries, &runtime, &AtomicU32::new(1)).unwrap();
+
+    let id = queries.id::<Repl>(&()).unwrap();
+    let enter = |depth| StackEvent::Enter {
+        query_id: id,
+        depth,
+    };
+    let exit = |depth| StackEvent::Exit {
+        query_id: id,
+        depth,
+    };
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        &[enter(0), exit(0), enter(0), enter(1), exit(1), exit(0)]
+    );
+}
+
+struct Chain;
+
+// Each query calls the next one, the last one calls the first one.
+fn chain(queries: &QueryCache<u32>, runtime: &Runtime<()>, n: u32) -> Result<Arc<u32>, Cycle> {
+    queries.try_insert_with_recovery::<Chain, _, Cycle, _, _, _>(
+        runtime,
+        n,
+        |n, _| Ok(*chain(queries, runtime, (*n + 1) % 4)? + 1),
+        |cycle, n| *n * 100 + cycle.cycle().len() as u32,
+    )
+}
+
+#[test]
+fn cycle_recovery() {
+    let mut runtime = Runtime::new();
+    let queries = QueryCache::new();
+
+    // The first query is recovered when re-entered, then computed.
+    assert_eq!(*chain(&queries, &runtime, 0).unwrap(), 9);
+    assert_eq!(*queries.cached::<Chain, u32, _>(&3, &runtime).unwrap(), 6);
+
+    // Exceeding the maximum depth is not recovered.
+    let queries = QueryCache::new();
+    runtime.set_max_query_depth(2);
+    assert!(chain(&queries, &runtime, 0)
+        .unwrap_err()
+        .is_depth_exceeded());
+}
+
+struct Abandoned;
+
+#[derive(Debug)]
+enum Abandon {
+    Cycle,
+    GaveUp,
+}
+
+impl From<Cycle> for Abandon {
+    fn from(_: Cycle) -> Self {
+        Abandon::Cycle
+    }
+}
+
+// Fails after re-entering itself, so the recovered output stays cached.
+fn abandoned(queries: &QueryCache<()>, runtime: &Runtime<()>) -> Result<Arc<u32>, Abandon> {
+    queries.try_insert_with_recovery::<Abandoned, _, Abandon, _, _, _>(
+        runtime,
+        (),
+        |_, _| {
+            abandoned(queries, runtime)?;
+            Err(Abandon::GaveUp)
+        },
+        |_, _| 0,
+    )
+}
+
+#[test]
+fn recovered_invalidation() {
+    use inqui::{input::ExternalId, runtime::StaleDependency};
+    use std::sync::Mutex;
+
+    let mut runtime = Runtime::new();
+    let queries = QueryCache::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let logged = log.clone();
+    queries.set_invalidation_logger(move |invalidation| {
+        logged.lock().unwrap().push(invalidation.dependency)
+    });
+
+    assert!(matches!(
+        abandoned(&queries, &runtime),
+        Err(Abandon::GaveUp)
+    ));
+    assert_eq!(
+        *queries.cached::<Abandoned, u32, _>(&(), &runtime).unwrap(),
+        0
+    );
+
+    runtime.invalidate_external(ExternalId(0));
+    assert!(queries.cached::<Abandoned, u32, _>(&(), &runtime).is_none());
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![StaleDependency::Recovered {
+            changed_at: runtime.current_revision()
+        }]
+    );
+}
+
+struct Deep;
+
+fn deep(queries: &QueryCache<u32>, runtime: &Runtime<()>, n: u32) -> Result<Arc<u32>, QueryError> {
+    queries.try_insert_with::<Deep, _, QueryError, _, _>(runtime, n, |n, _| {
+        Ok(*deep(queries, runtime, *n + 1)? + 1)
+    })
+}
+
+#[test]
+#[should_panic(expected = "maximum query depth 8 exceeded, stack: [ ")]
+fn max_query_depth_panic() {
+    struct Nested;
+
+    fn nested(queries: &QueryCache<u32>, runtime: &Runtime<()>, n: u32) -> u32 {
+        *queries.insert_with::<Nested, _, _, _>(runtime, n, |n, _| nested(queries, runtime, *n + 1))
+    }
+
+    let mut runtime = Runtime::new();
+    let queries = QueryCache::new();
+    runtime.set_max_query_depth(8);
+
+    nested(&queries, &runtime, 0);
+}
+
+#[test]
+fn max_query_depth() {
+    let mut runtime = Runtime::new();
+    let queries = QueryCache::new();
+    let clone = runtime.clone();
+    runtime.set_max_query_depth(100);
+
+    match deep(&queries, &runtime, 0) {
+        Err(QueryError::DepthExceeded(cycle)) => assert_eq!(cycle.cycle().len(), 101),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // Shared by the clones and not recovered by the cycle policy.
+    queries.set_cycle_policy::<Deep, u32>(CyclePolicy::Recover(|_| 0));
+    assert!(matches!(
+        deep(&queries, &clone, 200),
+        Err(QueryError::DepthExceeded(_))
+    ));
+}
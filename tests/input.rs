@@ -0,0 +1,1125 @@
+use inqui::{Input, QueryCache, Runtime};
+
+#[inqui::database]
+pub trait Source {
+    #[input(ordered, eq)]
+    fn line(&self, number: u32) -> String;
+}
+
+fn runtime_with_lines(lines: &[&str]) -> Runtime<SourceStorage> {
+    let mut runtime = Runtime::new();
+
+    for (number, line) in lines.iter().enumerate() {
+        runtime.set_input::<LineInput>(number as u32, line.to_string());
+    }
+
+    runtime
+}
+
+#[test]
+fn ordered_range() {
+    let runtime = runtime_with_lines(&["a", "b", "c", "d"]);
+
+    let lines = runtime
+        .range_input::<LineInput, _>(1..3)
+        .collect::<Vec<_>>();
+
+    assert_eq!(lines, vec![(1, "b".to_string()), (2, "c".to_string())]);
+}
+
+#[test]
+fn ordered_range_dependencies() {
+    let mut runtime = runtime_with_lines(&["a", "b", "c", "d"]);
+    let queries = QueryCache::<()>::new();
+
+    struct Joined;
+
+    let joined = queries.insert_with::<Joined, _, _, _>(&runtime, (), |_, ctx| {
+        ctx.use_input_range::<LineInput, _>(1..3)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<String>()
+    });
+    assert_eq!(*joined, "bc");
+
+    runtime.set_input::<LineInput>(3, "e".to_string());
+    assert!(queries.cached::<Joined, String, _>(&(), &runtime).is_some());
+
+    runtime.set_input::<LineInput>(2, "x".to_string());
+    assert!(queries.cached::<Joined, String, _>(&(), &runtime).is_none());
+
+    let join = |runtime: &Runtime<SourceStorage>| {
+        queries.insert_with::<Joined, _, _, _>(runtime, (), |_, ctx| {
+            ctx.use_input_range::<LineInput, _>(1..3)
+                .into_iter()
+                .map(|(_, line)| line)
+                .collect::<String>()
+        })
+    };
+
+    runtime.remove_input::<LineInput>(&1);
+    assert_eq!(*join(&runtime), "x");
+
+    // A key inserted into the range.
+    runtime.set_input::<LineInput>(1, "y".to_string());
+    assert!(queries.cached::<Joined, String, _>(&(), &runtime).is_none());
+    assert_eq!(*join(&runtime), "yx");
+}
+
+#[test]
+fn manifest() {
+    use inqui::input::StorageGroup;
+
+    assert_eq!(SourceStorage::INPUT_MANIFEST, &[("line", 0)]);
+    assert!(SourceStorage::check_manifest(&[("line", 0)]).is_ok());
+
+    let mismatch = SourceStorage::check_manifest(&[("line", 1), ("column", 0)]).unwrap_err();
+    assert_eq!(mismatch.mismatched, vec!["line", "column"]);
+}
+
+#[test]
+fn external_dependency() {
+    use inqui::input::ExternalId;
+
+    const MTIME: ExternalId = ExternalId(1);
+
+    let mut runtime = runtime_with_lines(&["a"]);
+    let queries = QueryCache::<()>::new();
+
+    struct WithMtime;
+    struct WithoutMtime;
+
+    queries.insert_with::<WithMtime, _, _, _>(&runtime, (), |_, ctx| {
+        ctx.add_external_dependency(MTIME);
+        ctx.use_input::<LineInput>(&0).unwrap()
+    });
+    queries.insert_with::<WithoutMtime, _, _, _>(&runtime, (), |_, ctx| {
+        ctx.use_input::<LineInput>(&0).unwrap()
+    });
+
+    runtime.invalidate_external(ExternalId(2));
+    assert!(queries
+        .cached::<WithMtime, String, _>(&(), &runtime)
+        .is_some());
+
+    runtime.invalidate_external(MTIME);
+    assert!(queries
+        .cached::<WithMtime, String, _>(&(), &runtime)
+        .is_none());
+    assert!(queries
+        .cached::<WithoutMtime, String, _>(&(), &runtime)
+        .is_some());
+}
+
+#[test]
+fn reused_slot() {
+    let mut runtime = runtime_with_lines(&["a", "b"]);
+    let queries = QueryCache::<()>::new();
+
+    struct First;
+
+    queries.insert_with::<First, _, _, _>(&runtime, (), |_, ctx| {
+        ctx.use_input::<LineInput>(&0).unwrap()
+    });
+
+    runtime.remove_input::<LineInput>(&0);
+    // Takes over the slot of the removed key.
+    runtime.set_input::<LineInput>(2, "c".to_string());
+
+    assert!(queries.cached::<First, String, _>(&(), &runtime).is_none());
+
+    let mut storage = inqui::InputStorage::<LineInput>::new();
+    let removed = storage.set(0, "a".to_string());
+    storage.remove(&0);
+    let reused = storage.set(1, "b".to_string());
+
+    assert_ne!(removed, reused);
+}
+
+#[test]
+fn stable_key_index() {
+    let mut storage = inqui::InputStorage::<LineInput>::new();
+    let first = storage.set(0, "a".to_string());
+
+    for i in 0..1000 {
+        assert_eq!(storage.set(0, i.to_string()), first);
+    }
+
+    // Re-setting the key did not allocate any new index.
+    let mut fresh = inqui::InputStorage::<LineInput>::new();
+    fresh.set(0, "a".to_string());
+    assert_eq!(
+        storage.set(1, "b".to_string()),
+        fresh.set(1, "b".to_string())
+    );
+}
+
+#[test]
+fn iter_storage() {
+    let mut storage = inqui::InputStorage::<LineInput>::new();
+    assert!(storage.is_empty());
+
+    storage.set(0, "a".to_string());
+    storage.set(1, "b".to_string());
+    storage.set(2, "c".to_string());
+    storage.remove(&0);
+    // Reuses the key index of the removed key.
+    storage.set(5, "d".to_string());
+
+    assert_eq!(storage.len(), 3);
+    assert_eq!(
+        storage
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect::<Vec<_>>(),
+        vec![(1, "b"), (2, "c"), (5, "d")]
+    );
+}
+
+fn lowercase(path: &str) -> String {
+    path.to_lowercase()
+}
+
+#[inqui::database]
+pub trait Files {
+    #[input(normalize = lowercase)]
+    fn file(&self, path: String) -> String;
+}
+
+#[test]
+fn normalized_key() {
+    let mut runtime = Runtime::<FilesStorage>::new();
+
+    runtime.set_input::<FileInput>("Foo.rs".to_string(), "foo".to_string());
+    assert_eq!(
+        runtime.get_input::<FileInput>(&"foo.rs".to_string()),
+        Some("foo".to_string())
+    );
+
+    runtime.set_input::<FileInput>("FOO.RS".to_string(), "bar".to_string());
+    assert_eq!(
+        runtime.get_input::<FileInput>(&"Foo.rs".to_string()),
+        Some("bar".to_string())
+    );
+
+    runtime.remove_input::<FileInput>(&"foo.RS".to_string());
+    assert_eq!(runtime.get_input::<FileInput>(&"foo.rs".to_string()), None);
+}
+
+#[inqui::database]
+pub trait Workspace {
+    fn text(&self, path: String) -> String;
+    fn mtime(&self, path: String) -> u64;
+}
+
+#[test]
+fn transaction() {
+    let mut runtime = Runtime::<WorkspaceStorage>::new();
+    let queries = QueryCache::<String>::new();
+    let before = runtime.current_revision();
+
+    runtime.transaction(|tx| {
+        tx.set_text("a.rs".to_string(), "fn a() {}".to_string());
+        tx.set_mtime("a.rs".to_string(), 1);
+    });
+
+    let after = runtime.current_revision();
+    assert_eq!(after.as_raw(), before.as_raw() + 1);
+    assert_eq!(
+        runtime.get_input::<MtimeInput>(&"a.rs".to_string()),
+        Some(1)
+    );
+
+    struct Length;
+
+    queries.insert_with::<Length, _, _, _>(&runtime, "a.rs".to_string(), |path, ctx| {
+        ctx.text(path.clone()).len()
+    });
+
+    runtime.transaction(|tx| {
+        tx.set_mtime("a.rs".to_string(), 2);
+        tx.remove_mtime("b.rs".to_string());
+    });
+    assert!(queries
+        .cached::<Length, usize, _>(&"a.rs".to_string(), &runtime)
+        .is_some());
+
+    runtime.transaction(|tx| tx.set::<TextInput>("a.rs".to_string(), String::new()));
+    assert!(queries
+        .cached::<Length, usize, _>(&"a.rs".to_string(), &runtime)
+        .is_none());
+}
+
+#[test]
+fn transaction_panic() {
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    let mut runtime = Runtime::<WorkspaceStorage>::new();
+    runtime.set_input::<TextInput>("a.rs".to_string(), String::new());
+    let queries = Arc::new(QueryCache::<String>::new());
+    runtime.attach_cache(&queries);
+
+    struct Length;
+
+    queries.insert_with::<Length, _, _, _>(&runtime, "a.rs".to_string(), |path, ctx| {
+        ctx.text(path.clone()).len()
+    });
+    let invalidated = Arc::new(AtomicBool::new(false));
+    let handle = queries.stable_handle::<Length>(&"a.rs".to_string());
+    let subscription = queries.on_invalidate(handle, {
+        let invalidated = invalidated.clone();
+        move |_| invalidated.store(true, Ordering::SeqCst)
+    });
+    let before = runtime.current_revision();
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        runtime.transaction(|tx| {
+            tx.set_text("a.rs".to_string(), "fn a() {}".to_string());
+            panic!("interrupted");
+        })
+    }));
+
+    // The change made before the panic is committed.
+    assert!(result.is_err());
+    assert!(runtime.current_revision() > before);
+    assert!(invalidated.load(Ordering::SeqCst));
+    assert!(queries
+        .cached::<Length, usize, _>(&"a.rs".to_string(), &runtime)
+        .is_none());
+    drop(subscription);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    mtime: u64,
+}
+
+#[inqui::database]
+pub trait Project {
+    #[input(metadata = FileInfo)]
+    fn source(&self, path: String) -> String;
+}
+
+#[test]
+fn metadata() {
+    let mut runtime = Runtime::<ProjectStorage>::new();
+    let queries = QueryCache::<()>::new();
+    let path = "a.rs".to_string();
+
+    assert!(!runtime.set_input_metadata::<SourceInput>(&path, FileInfo { mtime: 1 }));
+
+    runtime.set_input::<SourceInput>(path.clone(), "fn a() {}".to_string());
+    assert!(runtime.set_input_metadata::<SourceInput>(&path, FileInfo { mtime: 1 }));
+
+    struct Mtime;
+
+    let mtime = queries.insert_with::<Mtime, _, _, _>(&runtime, (), |_, ctx| {
+        ctx.input_metadata::<SourceInput>(&"a.rs".to_string())
+            .unwrap()
+            .mtime
+    });
+    assert_eq!(*mtime, 1);
+
+    runtime.set_input_metadata::<SourceInput>(&path, FileInfo { mtime: 2 });
+    assert!(queries.cached::<Mtime, u64, _>(&(), &runtime).is_some());
+    assert_eq!(
+        runtime.get_input_metadata::<SourceInput>(&path),
+        Some(FileInfo { mtime: 2 })
+    );
+
+    runtime.remove_input::<SourceInput>(&path);
+    assert_eq!(runtime.get_input_metadata::<SourceInput>(&path), None);
+}
+
+#[inqui::database]
+pub trait Options {
+    #[input(default = 0)]
+    fn opt_level(&self) -> u8;
+    fn target(&self, name: String) -> Option<String>;
+}
+
+#[test]
+fn default_value() {
+    let mut runtime = Runtime::<OptionsStorage>::new();
+    let queries = QueryCache::<()>::new();
+
+    struct Opt;
+    struct Target;
+
+    let opt = queries.insert_with::<Opt, _, _, _>(&runtime, (), |_, ctx| ctx.opt_level());
+    assert_eq!(*opt, 0);
+    assert_eq!(runtime.get_input::<OptLevelInput>(&()), Some(0));
+
+    let target = queries
+        .insert_with::<Target, _, _, _>(&runtime, (), |_, ctx| ctx.target("wasm".to_string()));
+    assert_eq!(*target, None);
+
+    runtime.set_input::<TargetInput>("x86".to_string(), "x86_64".to_string());
+    assert!(queries.cached::<Opt, u8, _>(&(), &runtime).is_some());
+    // Any key could be the one the query used.
+    assert!(queries
+        .cached::<Target, Option<String>, _>(&(), &runtime)
+        .is_none());
+
+    runtime.set_input::<OptLevelInput>((), 2);
+    assert!(queries.cached::<Opt, u8, _>(&(), &runtime).is_none());
+}
+
+#[test]
+fn storage_group() {
+    let runtime = runtime_with_lines(&["a", "b", "c"]);
+
+    let lines = runtime.with_storage_group(|storage| {
+        LineInput::storage(storage)
+            .range(..)
+            .map(|(_, line, _)| line.clone())
+            .collect::<String>()
+    });
+
+    assert_eq!(lines, "abc");
+}
+
+#[test]
+fn compact() {
+    use std::sync::Arc;
+
+    let mut runtime = runtime_with_lines(&["a", "b", "c", "d"]);
+    let queries = Arc::new(QueryCache::<u32>::new());
+    runtime.attach_cache(&queries);
+
+    struct Line;
+
+    let line = |queries: &QueryCache<u32>, runtime: &Runtime<SourceStorage>, number| {
+        queries.insert_with::<Line, _, _, _>(runtime, number, |number, ctx| {
+            ctx.use_input::<LineInput>(number)
+        })
+    };
+
+    line(&queries, &runtime, 0);
+    line(&queries, &runtime, 3);
+
+    let stale: Vec<_> = (0..4)
+        .map(|number| runtime.input_key_index::<LineInput>(&number).unwrap())
+        .collect();
+    runtime.remove_input::<LineInput>(&0);
+    runtime.remove_input::<LineInput>(&1);
+    let before = runtime.input_key_index::<LineInput>(&3).unwrap();
+
+    runtime.compact();
+
+    assert_ne!(runtime.input_key_index::<LineInput>(&3), Some(before));
+    assert!(queries
+        .cached::<Line, Option<String>, _>(&0, &runtime)
+        .is_none());
+    assert!(queries
+        .cached::<Line, Option<String>, _>(&3, &runtime)
+        .is_some());
+    let index = runtime.input_key_index::<LineInput>(&3).unwrap();
+    assert_eq!(
+        queries.queries_depending_on(index),
+        vec![("input::compact::Line", queries.id::<Line>(&3).unwrap())]
+    );
+
+    // New keys do not take over the compacted ones.
+    runtime.set_input::<LineInput>(4, "e".to_string());
+    // Nor the indices from before the compaction.
+    for number in 2..5 {
+        let index = runtime.input_key_index::<LineInput>(&number).unwrap();
+        assert!(!stale.contains(&index));
+    }
+    assert!(queries
+        .cached::<Line, Option<String>, _>(&3, &runtime)
+        .is_some());
+
+    runtime.set_input::<LineInput>(3, "x".to_string());
+    assert!(queries
+        .cached::<Line, Option<String>, _>(&3, &runtime)
+        .is_none());
+}
+
+#[inqui::database]
+pub trait Tree {
+    #[input(group = "file")]
+    fn content(&self, path: String) -> String;
+    #[input(group = "file")]
+    fn hash(&self, path: String) -> u64;
+    fn permissions(&self, path: String) -> u32;
+}
+
+#[test]
+fn input_group() {
+    let mut runtime = Runtime::<TreeStorage>::new();
+    let queries = QueryCache::<String>::new();
+    let path = "a.rs".to_string();
+
+    runtime.set_input::<ContentInput>(path.clone(), "fn a() {}".to_string());
+    runtime.set_input::<HashInput>(path.clone(), 1);
+    runtime.set_input::<PermissionsInput>(path.clone(), 0o644);
+
+    struct Length;
+
+    queries.insert_with::<Length, _, _, _>(&runtime, path.clone(), |path, ctx| {
+        ctx.content(path.clone()).len()
+    });
+
+    assert_eq!(
+        runtime.input_key_index::<ContentInput>(&path),
+        runtime.input_key_index::<HashInput>(&path)
+    );
+
+    runtime.set_input::<PermissionsInput>(path.clone(), 0o755);
+    runtime.set_input::<HashInput>("b.rs".to_string(), 2);
+    assert!(queries
+        .cached::<Length, usize, _>(&path, &runtime)
+        .is_some());
+
+    // The query did not use the hash, but it is in the same group.
+    runtime.set_input::<HashInput>(path.clone(), 2);
+    assert!(queries
+        .cached::<Length, usize, _>(&path, &runtime)
+        .is_none());
+}
+
+#[test]
+fn input_snapshot() {
+    let mut runtime = runtime_with_lines(&["a", "b"]);
+    let snapshot = runtime.input_snapshot();
+
+    runtime.set_input::<LineInput>(0, "x".to_string());
+
+    let worker = std::thread::spawn({
+        let snapshot = snapshot.clone();
+        move || snapshot.get::<LineInput>(&0)
+    });
+
+    assert_eq!(worker.join().unwrap(), Some("a".to_string()));
+    assert_eq!(
+        snapshot.range::<LineInput, _>(1..).collect::<Vec<_>>(),
+        vec![(1, "b".to_string())]
+    );
+    assert!(snapshot.revision() < runtime.current_revision());
+}
+
+#[test]
+fn missing_dependency_policy() {
+    use inqui::runtime::MissingDependencyPolicy;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let mut runtime = runtime_with_lines(&["a", "b"]);
+    // Not attached, so its dependencies are not remapped by the compaction.
+    let queries = QueryCache::<()>::new();
+
+    struct Second;
+
+    queries.insert_with::<Second, _, _, _>(&runtime, (), |_, ctx| ctx.use_input::<LineInput>(&1));
+
+    runtime.remove_input::<LineInput>(&0);
+    runtime.compact();
+
+    assert!(queries
+        .cached::<Second, Option<String>, _>(&(), &runtime)
+        .is_none());
+
+    runtime.set_missing_dependency_policy(MissingDependencyPolicy::Error);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        queries.cached::<Second, Option<String>, _>(&(), &runtime)
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+fn compare_and_set() {
+    let mut runtime = runtime_with_lines(&["a"]);
+    let rev = runtime.current_revision();
+
+    assert!(!runtime.compare_and_set_input::<LineInput>(0, &"b".to_string(), "c".to_string()));
+    assert!(!runtime.compare_and_set_input::<LineInput>(1, &"a".to_string(), "c".to_string()));
+    assert_eq!(runtime.current_revision(), rev);
+
+    assert!(runtime.compare_and_set_input::<LineInput>(0, &"a".to_string(), "c".to_string()));
+    assert_eq!(runtime.get_input::<LineInput>(&0), Some("c".to_string()));
+    assert!(runtime.current_revision() > rev);
+    // Also for the inputs that do not compare the values when set.
+    let mut runtime = Runtime::<WorkspaceStorage>::new();
+    let path = "a.rs".to_string();
+    runtime.set_input::<TextInput>(path.clone(), String::new());
+    assert!(!runtime.compare_and_set_input::<TextInput>(
+        path.clone(),
+        &"x".to_string(),
+        "y".to_string()
+    ));
+    assert!(runtime.compare_and_set_input::<TextInput>(
+        path.clone(),
+        &String::new(),
+        "y".to_string()
+    ));
+    assert_eq!(runtime.get_input::<TextInput>(&path), Some("y".to_string()));
+}
+
+// Cannot be compared, so every set is a change.
+#[derive(Clone)]
+pub struct Callback(std::sync::Arc<dyn Fn() -> u32 + Send + Sync>);
+
+#[inqui::database]
+pub trait Callbacks {
+    fn callback(&self, key: u32) -> Callback;
+}
+
+#[test]
+fn incomparable_input() {
+    let mut runtime = Runtime::<CallbacksStorage>::new();
+    let queries = QueryCache::<u32>::new();
+    let callback = Callback(std::sync::Arc::new(|| 1));
+    runtime.set_input::<CallbackInput>(0, callback.clone());
+
+    struct Call;
+
+    queries.insert_with::<Call, _, _, _>(&runtime, 0, |n, ctx| {
+        (ctx.use_input::<CallbackInput>(n).unwrap().0)()
+    });
+
+    runtime.set_input::<CallbackInput>(0, callback);
+    assert_eq!(runtime.input_version::<CallbackInput>(&0), Some(2));
+    assert!(queries.cached::<Call, u32, _>(&0, &runtime).is_none());
+}
+
+#[test]
+fn diff() {
+    use inqui::input::InputChangeKind;
+
+    let mut runtime = runtime_with_lines(&["a", "b", "c"]);
+    let snapshot = runtime.input_snapshot();
+
+    runtime.set_input::<LineInput>(0, "x".to_string());
+    runtime.set_input::<LineInput>(1, "x".to_string());
+    runtime.set_input::<LineInput>(1, "b".to_string());
+    let removed = runtime.input_key_index::<LineInput>(&2).unwrap();
+    runtime.remove_input::<LineInput>(&2);
+    runtime.set_input::<LineInput>(3, "d".to_string());
+
+    let changes = runtime
+        .diff(&snapshot)
+        .into_iter()
+        .map(|change| ((change.input, change.key), change.kind))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        changes,
+        vec![
+            (
+                runtime.input_key_index::<LineInput>(&0).unwrap(),
+                InputChangeKind::Changed
+            ),
+            (removed, InputChangeKind::Removed),
+            (
+                runtime.input_key_index::<LineInput>(&3).unwrap(),
+                InputChangeKind::Added
+            ),
+        ]
+    );
+
+    assert_eq!(runtime.input_snapshot().diff(&snapshot).len(), 3);
+}
+
+#[test]
+fn update_input() {
+    let mut runtime = runtime_with_lines(&["a"]);
+    let queries = QueryCache::<u32>::new();
+
+    struct Line;
+
+    let line = |queries: &QueryCache<u32>, runtime: &Runtime<SourceStorage>| {
+        queries.insert_with::<Line, _, _, _>(runtime, 0, |n, ctx| ctx.use_input::<LineInput>(n))
+    };
+
+    line(&queries, &runtime);
+
+    assert!(runtime.update_input::<LineInput, _>(0, |line| line.push('b')));
+    assert!(!runtime.update_input::<LineInput, _>(1, |line| line.push('b')));
+    assert_eq!(runtime.get_input::<LineInput>(&1), None);
+    assert!(queries
+        .cached::<Line, Option<String>, _>(&0, &runtime)
+        .is_none());
+    assert_eq!(*line(&queries, &runtime), Some("ab".to_string()));
+
+    // Modifying to an equal value does not invalidate.
+    runtime.update_input::<LineInput, _>(0, |line| line.make_ascii_lowercase());
+    assert!(queries
+        .cached::<Line, Option<String>, _>(&0, &runtime)
+        .is_some());
+    assert_eq!(runtime.input_version::<LineInput>(&0), Some(2));
+
+    assert!(!runtime.set_input_with::<LineInput, _, _>(1, || "x".to_string(), |line| line.clear()));
+    assert_eq!(runtime.input_version::<LineInput>(&1), Some(1));
+    assert!(runtime.set_input_with::<LineInput, _, _>(
+        1,
+        || "x".to_string(),
+        |line| line.push('y')
+    ));
+    assert_eq!(runtime.get_input::<LineInput>(&1), Some("xy".to_string()));
+}
+
+static BLOB_COMPARISONS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Blob(Vec<u8>);
+
+impl PartialEq for Blob {
+    fn eq(&self, other: &Self) -> bool {
+        BLOB_COMPARISONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.0 == other.0
+    }
+}
+
+impl std::hash::Hash for Blob {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+#[inqui::database]
+pub trait Blobs {
+    #[input(eq, fingerprint)]
+    fn blob(&self, key: u32) -> Blob;
+}
+
+#[test]
+fn fingerprint() {
+    use std::sync::atomic::Ordering;
+
+    let mut runtime = Runtime::<BlobsStorage>::new();
+
+    runtime.set_input::<BlobInput>(0, Blob(vec![1; 1024]));
+    runtime.set_input::<BlobInput>(0, Blob(vec![2; 1024]));
+    assert_eq!(BLOB_COMPARISONS.load(Ordering::SeqCst), 0);
+    assert_eq!(runtime.input_version::<BlobInput>(&0), Some(2));
+
+    // Only equal fingerprints are compared in full.
+    runtime.set_input::<BlobInput>(0, Blob(vec![2; 1024]));
+    assert_eq!(BLOB_COMPARISONS.load(Ordering::SeqCst), 1);
+    assert_eq!(runtime.input_version::<BlobInput>(&0), Some(2));
+}
+
+#[test]
+fn revision_stats() {
+    let runtime = runtime_with_lines(&["a", "b", "c"]);
+    let stats = runtime.revision_stats();
+
+    assert_eq!(stats.revisions(), 3);
+    assert_eq!(stats.current, runtime.current_revision());
+
+    // The rate is sampled once the clock advances.
+    let stats = loop {
+        let stats = runtime.revision_stats();
+        if stats.change_rate.is_some() {
+            break stats;
+        }
+        std::hint::spin_loop();
+    };
+    assert!(stats.change_rate.unwrap() > 0.0);
+    assert!(!stats.elapsed.is_zero());
+}
+
+#[test]
+fn revision_stats_baseline() {
+    let mut runtime = Runtime::<WorkspaceStorage>::new();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+
+    // Sampling starts with the first revision.
+    assert_eq!(runtime.revision_stats().change_rate, None);
+
+    let mut source = Runtime::<WorkspaceStorage>::new();
+    for mtime in 0..4 {
+        source.set_input::<MtimeInput>("a.rs".to_string(), mtime);
+    }
+    let dump = source.dump_inputs(serde_json::value::Serializer).unwrap();
+
+    runtime.set_input::<MtimeInput>("b.rs".to_string(), 0);
+    while runtime.revision_stats().change_rate.is_none() {
+        std::hint::spin_loop();
+    }
+
+    runtime.load_inputs(dump).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1));
+
+    let stats = runtime.revision_stats();
+    assert_eq!(stats.revisions(), 0);
+    assert_eq!(stats.initial, runtime.current_revision());
+    assert_eq!(stats.change_rate, None);
+}
+
+#[derive(Default, Clone)]
+struct CompositeStorage {
+    source: SourceStorage,
+    options: OptionsStorage,
+}
+
+impl inqui::input::Contains<SourceStorage> for CompositeStorage {
+    fn part(&self) -> &SourceStorage {
+        &self.source
+    }
+
+    fn part_mut(&mut self) -> &mut SourceStorage {
+        &mut self.source
+    }
+}
+
+impl inqui::input::Contains<OptionsStorage> for CompositeStorage {
+    const INPUT_OFFSET: u16 = inqui::input::offset_after::<Self, SourceStorage>();
+
+    fn part(&self) -> &OptionsStorage {
+        &self.options
+    }
+
+    fn part_mut(&mut self) -> &mut OptionsStorage {
+        &mut self.options
+    }
+}
+
+const OPTIONS_OFFSET: u16 =
+    <CompositeStorage as inqui::input::Contains<OptionsStorage>>::INPUT_OFFSET;
+
+impl inqui::input::StorageGroup for CompositeStorage {
+    const INPUT_MANIFEST: &'static [(&'static str, u16)] = &[("line", 0), ("opt_level", 1)];
+    const MANIFEST_HASH: u64 =
+        SourceStorage::MANIFEST_HASH ^ OptionsStorage::MANIFEST_HASH.rotate_left(1);
+    const INDEX_COUNT: u16 = inqui::input::offset_after::<Self, OptionsStorage>();
+
+    fn approx_memory_bytes(&self) -> usize {
+        self.source.approx_memory_bytes() + self.options.approx_memory_bytes()
+    }
+
+    fn compact(&mut self) -> inqui::input::KeyRemap {
+        [
+            self.source.compact(),
+            self.options.compact().offset(OPTIONS_OFFSET),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn diff(&self, old: &Self) -> Vec<inqui::input::InputChange> {
+        let mut changes = self.source.diff(&old.source);
+        changes.extend(
+            self.options
+                .diff(&old.options)
+                .into_iter()
+                .map(|change| change.offset(OPTIONS_OFFSET)),
+        );
+        changes
+    }
+
+    fn dependencies(&self) -> Vec<(inqui::input::InputIndex, inqui::input::KeyIndex)> {
+        let mut dependencies = self.source.dependencies();
+        dependencies.extend(
+            self.options
+                .dependencies()
+                .into_iter()
+                .map(|(input, key)| (input.offset(OPTIONS_OFFSET), key)),
+        );
+        dependencies
+    }
+}
+
+#[test]
+fn composite_storage() {
+    let mut runtime = Runtime::<CompositeStorage>::new();
+    let queries = QueryCache::<u32>::new();
+
+    struct Compile;
+
+    runtime.set_input::<LineInput>(0, "main".to_string());
+    runtime.set_input::<OptLevelInput>((), 2);
+
+    let compile = |queries: &QueryCache<u32>, runtime: &Runtime<CompositeStorage>| {
+        queries.insert_with::<Compile, _, _, _>(runtime, 0, |n, ctx| {
+            format!("{} -O{}", ctx.line(*n), ctx.opt_level())
+        })
+    };
+
+    assert_eq!(*compile(&queries, &runtime), "main -O2");
+
+    runtime.set_input::<LineInput>(1, "unused".to_string());
+    assert!(queries.cached::<Compile, String, _>(&0, &runtime).is_some());
+
+    runtime.set_input::<OptLevelInput>((), 3);
+    assert!(queries.cached::<Compile, String, _>(&0, &runtime).is_none());
+    assert_eq!(*compile(&queries, &runtime), "main -O3");
+}
+
+#[test]
+fn composite_storage_group() {
+    use inqui::input::InputChangeKind;
+    use std::sync::Arc;
+
+    let mut runtime = Runtime::<CompositeStorage>::new();
+    let queries = Arc::new(QueryCache::<u32>::new());
+
+    struct OptLevel;
+
+    runtime.attach_cache(&queries);
+    runtime.set_input::<LineInput>(0, "main".to_string());
+    runtime.set_input::<LineInput>(1, "unused".to_string());
+    runtime.set_input::<OptLevelInput>((), 2);
+
+    let snapshot = runtime.input_snapshot();
+    runtime.set_input::<OptLevelInput>((), 3);
+
+    let opt_level = runtime.input_key_index::<OptLevelInput>(&()).unwrap();
+    let changes = runtime.diff(&snapshot);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].input, opt_level.0);
+    assert_eq!(changes[0].kind, InputChangeKind::Changed);
+
+    queries.insert_with::<OptLevel, _, _, _>(&runtime, 0, |_, ctx| ctx.opt_level());
+
+    // The dependency on the option survives the compaction of the offset part.
+    runtime.remove_input::<LineInput>(&0);
+    runtime.compact();
+    assert!(queries.cached::<OptLevel, u8, _>(&0, &runtime).is_some());
+}
+
+#[inqui::database]
+pub trait Flags {
+    #[input(storage = "vec")]
+    fn flag(&self, index: u16) -> bool;
+    #[input(storage = "btree")]
+    fn label(&self, name: String) -> String;
+    #[input(storage = "vec")]
+    fn offset(&self, index: u64) -> u64;
+}
+
+#[test]
+fn storage_backends() {
+    let mut runtime = Runtime::<FlagsStorage>::new();
+    let queries = QueryCache::<()>::new();
+
+    runtime.set_input::<FlagInput>(3, true);
+    runtime.set_input::<FlagInput>(1, false);
+    runtime.set_input::<FlagInput>(7, true);
+    runtime.set_input::<LabelInput>("b".to_string(), "B".to_string());
+    runtime.set_input::<LabelInput>("a".to_string(), "A".to_string());
+
+    let flags = runtime.range_input::<FlagInput, _>(2..).collect::<Vec<_>>();
+    assert_eq!(flags, vec![(3, true), (7, true)]);
+
+    let labels = runtime.range_input::<LabelInput, _>(..).collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        vec![
+            ("a".to_string(), "A".to_string()),
+            ("b".to_string(), "B".to_string())
+        ]
+    );
+
+    struct Enabled;
+
+    let enabled = |queries: &QueryCache<()>, runtime: &Runtime<FlagsStorage>| {
+        queries.insert_with::<Enabled, _, _, _>(runtime, (), |_, ctx| {
+            ctx.use_input_range::<FlagInput, _>(..)
+                .into_iter()
+                .filter(|(_, flag)| *flag)
+                .count()
+        })
+    };
+
+    assert_eq!(*enabled(&queries, &runtime), 2);
+
+    runtime.remove_input::<FlagInput>(&7);
+    assert_eq!(*enabled(&queries, &runtime), 1);
+
+    runtime.compact();
+    let flags = runtime.range_input::<FlagInput, _>(..).collect::<Vec<_>>();
+    assert_eq!(flags, vec![(1, false), (3, true)]);
+}
+
+#[test]
+fn sparse_vec_keys() {
+    let mut runtime = Runtime::<FlagsStorage>::new();
+
+    // Would not fit in memory if the keys were positions in a vector.
+    runtime.set_input::<OffsetInput>(1 << 40, 1);
+    runtime.set_input::<OffsetInput>(100, 2);
+    assert_eq!(runtime.get_input::<OffsetInput>(&(1 << 40)), Some(1));
+
+    // The dense keys reach the sparse key, which is moved to the vector.
+    for key in 0..=101 {
+        if key != 100 {
+            runtime.set_input::<OffsetInput>(key, key);
+        }
+    }
+
+    assert_eq!(runtime.get_input::<OffsetInput>(&100), Some(2));
+    let offsets = runtime
+        .range_input::<OffsetInput, _>(99..)
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, vec![(99, 99), (100, 2), (101, 101), (1 << 40, 1)]);
+
+    runtime.remove_input::<OffsetInput>(&(1 << 40));
+    runtime.remove_input::<OffsetInput>(&100);
+    assert_eq!(runtime.get_input::<OffsetInput>(&100), None);
+    assert_eq!(runtime.range_input::<OffsetInput, _>(..).count(), 101);
+
+    runtime.compact();
+    assert_eq!(runtime.get_input::<OffsetInput>(&101), Some(101));
+}
+
+mod settings {
+    #[inqui::database]
+    pub trait Settings {
+        /// How much is logged.
+        fn verbosity(&self) -> u8;
+    }
+}
+
+#[test]
+fn visibility() {
+    use settings::{Settings, SettingsStorage, VerbosityInput};
+
+    let mut runtime = Runtime::<SettingsStorage>::new();
+    let queries = QueryCache::<()>::new();
+    runtime.set_input::<VerbosityInput>((), 3);
+
+    struct Verbose;
+
+    let verbose =
+        queries.insert_with::<Verbose, _, _, _>(&runtime, (), |_, ctx| ctx.verbosity() > 2);
+    assert!(*verbose);
+}
+
+#[inqui::database(storage = LimitsStore)]
+trait Limits {
+    fn limit(&self, name: &'static str) -> u32;
+}
+
+#[test]
+fn storage_name() {
+    let mut runtime = Runtime::<LimitsStore>::new();
+    let queries = QueryCache::<()>::new();
+    runtime.set_input::<LimitInput>("depth", 8);
+
+    struct Depth;
+
+    let depth = queries.insert_with::<Depth, _, _, _>(&runtime, (), |_, ctx| ctx.limit("depth"));
+    assert_eq!(*depth, 8);
+}
+
+// The same inputs as `Workspace` in a different order.
+mod reordered {
+    #[inqui::database]
+    #[allow(dead_code)]
+    pub trait Workspace {
+        fn mtime(&self, path: String) -> u64;
+        fn text(&self, path: String) -> String;
+    }
+}
+
+#[test]
+fn persisted_manifest() {
+    let mut runtime = Runtime::<WorkspaceStorage>::new();
+    runtime.set_input::<TextInput>("a.rs".to_string(), "fn a() {}".to_string());
+
+    let dump = runtime.dump_inputs(serde_json::value::Serializer).unwrap();
+
+    // The inputs would deserialize, but at different indices.
+    let mut loaded = Runtime::<reordered::WorkspaceStorage>::new();
+    let error = loaded.load_inputs(dump).unwrap_err();
+    assert!(error.to_string().contains("manifest hash"));
+    assert_eq!(
+        loaded.get_input::<reordered::TextInput>(&"a.rs".to_string()),
+        None
+    );
+}
+
+#[test]
+fn persisted_inputs() {
+    let mut runtime = Runtime::<WorkspaceStorage>::new();
+    runtime.set_input::<TextInput>("a.rs".to_string(), "fn a() {}".to_string());
+    runtime.set_input::<TextInput>("b.rs".to_string(), "fn b() {}".to_string());
+    runtime.set_input::<MtimeInput>("a.rs".to_string(), 1);
+    runtime.remove_input::<TextInput>(&"a.rs".to_string());
+
+    let dump = runtime.dump_inputs(serde_json::value::Serializer).unwrap();
+
+    let mut loaded = Runtime::<WorkspaceStorage>::new();
+    let queries = QueryCache::<String>::new();
+
+    struct Length;
+
+    let length = |queries: &QueryCache<String>, runtime: &Runtime<WorkspaceStorage>| {
+        queries.insert_with::<Length, _, _, _>(runtime, "b.rs".to_string(), |path, ctx| {
+            ctx.text(path.clone()).len()
+        })
+    };
+
+    // Computed before loading, so that it gets invalidated.
+    loaded.set_input::<TextInput>("b.rs".to_string(), String::new());
+    assert_eq!(*length(&queries, &loaded), 0);
+
+    loaded.load_inputs(dump).unwrap();
+
+    assert_eq!(loaded.current_revision(), runtime.current_revision());
+    assert_eq!(
+        loaded.get_input::<TextInput>(&"b.rs".to_string()),
+        Some("fn b() {}".to_string())
+    );
+    assert_eq!(loaded.get_input::<TextInput>(&"a.rs".to_string()), None);
+    assert_eq!(loaded.get_input::<MtimeInput>(&"a.rs".to_string()), Some(1));
+    assert_eq!(
+        loaded.input_key_index::<TextInput>(&"b.rs".to_string()),
+        runtime.input_key_index::<TextInput>(&"b.rs".to_string())
+    );
+    assert!(queries
+        .cached::<Length, usize, _>(&"b.rs".to_string(), &loaded)
+        .is_none());
+    assert_eq!(*length(&queries, &loaded), 9);
+
+    // The slot of the removed key is reused as before.
+    runtime.set_input::<TextInput>("c.rs".to_string(), String::new());
+    loaded.set_input::<TextInput>("c.rs".to_string(), String::new());
+    assert_eq!(
+        loaded.input_key_index::<TextInput>(&"c.rs".to_string()),
+        runtime.input_key_index::<TextInput>(&"c.rs".to_string())
+    );
+}
+
+// Loads the inputs persisted at given revision.
+#[cfg(feature = "revision-u32")]
+fn load_at_revision(rev: u64) -> Result<Runtime<WorkspaceStorage>, serde_json::Error> {
+    let runtime = Runtime::<WorkspaceStorage>::new();
+    let mut dump = runtime.dump_inputs(serde_json::value::Serializer)?;
+    dump[1] = rev.into();
+
+    let mut loaded = Runtime::<WorkspaceStorage>::new();
+    loaded.load_inputs(dump)?;
+    Ok(loaded)
+}
+
+#[test]
+#[cfg(feature = "revision-u32")]
+fn revision_width() {
+    use inqui::revision::Revision;
+
+    assert_eq!(std::mem::size_of::<Revision>(), 4);
+    assert_eq!(std::mem::size_of::<Option<Revision>>(), 4);
+
+    let loaded = load_at_revision(u32::MAX.into()).unwrap();
+    assert_eq!(loaded.current_revision().as_raw(), u64::from(u32::MAX));
+
+    // Revisions persisted without the feature may not fit.
+    assert!(load_at_revision(u64::from(u32::MAX) + 1).is_err());
+}
+
+#[test]
+#[cfg(feature = "revision-u32")]
+#[should_panic(expected = "revision overflow")]
+fn revision_overflow() {
+    let mut runtime = load_at_revision(u32::MAX.into()).unwrap();
+    runtime.set_input::<TextInput>("a.rs".to_string(), String::new());
+}
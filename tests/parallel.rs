@@ -1,16 +1,29 @@
-use std::{sync::mpsc, thread, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Barrier, Condvar, Mutex,
+    },
+    thread,
+};
+
+use inqui::{
+    query::{HookPoint, QueryId},
+    Cycle, QueryCache, Runtime,
+};
 
 mod common;
 
-use common::{AnySystem, Database, RealSystem};
+use common::{AnySystem, Database, InputName, Log, RealSystem};
+
+fn longer(db: &dyn Database, _: &bool, _: &AnySystem<'_, bool>) -> i32 {
+    db.a() + db.b()
+}
 
-fn longer(db: &dyn Database, wait: &bool, _: &AnySystem<'_, bool>) -> i32 {
-    let a = db.a();
-    if *wait {
-        thread::sleep(Duration::from_millis(50));
+// Waits until the log is written after the first `since` entries.
+fn wait_for_log(system: &RealSystem<bool>, since: usize, log: Log) {
+    while !system.log_book()[since..].contains(&log) {
+        thread::yield_now();
     }
-    let b = db.b();
-    a + b
 }
 
 #[test]
@@ -24,9 +37,16 @@ fn consistency_with_locking() {
     let t1 = thread::spawn({
         let system = system.clone();
         move || {
-            let output = *system.query(true, move |db, param, system| {
-                sender.send(()).unwrap();
-                longer(db, param, system)
+            let output = *system.query(true, {
+                let system = system.clone();
+                move |db, _, _| {
+                    let a = db.a();
+                    let since = system.log_book().len();
+                    sender.send(()).unwrap();
+                    // The input is set only after the query finishes.
+                    wait_for_log(&system, since, Log::SetInputBefore(InputName::B, None));
+                    a + db.b()
+                }
             });
             assert_eq!(output, 8);
         }
@@ -51,9 +71,15 @@ fn inconsistency_without_locking() {
     let t1 = thread::spawn({
         let system = system.clone();
         move || {
-            let output = *system.query(true, move |db, param, system| {
-                sender.send(()).unwrap();
-                longer(db, param, system)
+            let output = *system.query(true, {
+                let system = system.clone();
+                move |db, _, _| {
+                    let a = db.a();
+                    let since = system.log_book().len();
+                    sender.send(()).unwrap();
+                    wait_for_log(&system, since, Log::SetInputAfter(InputName::B, None));
+                    a + db.b()
+                }
             });
             // `b` already changed to 10!
             assert_eq!(output, 13);
@@ -72,27 +98,31 @@ fn inconsistency_without_locking() {
 fn parallel_queries() {
     let mut system = RealSystem::new(true);
 
+    let barrier = Arc::new(Barrier::new(2));
+
     system.set_a(3);
     system.set_b(5);
 
-    let t1 = thread::spawn({
-        let system = system.clone();
-        move || {
-            let output = *system.query(true, longer);
-            assert_eq!(output, 8);
-        }
-    });
-
-    let t2 = thread::spawn({
-        let system = system.clone();
-        move || {
-            let output = *system.query(true, longer);
-            assert_eq!(output, 8);
-        }
-    });
+    // Both computations must start before either finishes, which would
+    // deadlock if they were serialized.
+    let threads = [true, false]
+        .into_iter()
+        .map(|param| {
+            let system = system.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let output = *system.query(param, move |db, param, system| {
+                    barrier.wait();
+                    longer(db, param, system)
+                });
+                assert_eq!(output, 8);
+            })
+        })
+        .collect::<Vec<_>>();
 
-    t1.join().unwrap();
-    t2.join().unwrap();
+    for thread in threads {
+        thread.join().unwrap();
+    }
 
     let log_book = system.log_book();
 
@@ -112,3 +142,384 @@ fn parallel_queries() {
     // Neither is finished before the other => they run in parallel.
     assert!(latest_start < earliest_done);
 }
+
+#[inqui::database]
+pub trait Numbers {
+    fn number(&self, key: u32) -> i32;
+}
+
+#[test]
+fn parallel_queries_with_hooks() {
+    let mut runtime = Runtime::<NumbersStorage>::new();
+    let queries = Arc::new(QueryCache::<u32>::new());
+    let barrier = Arc::new(Barrier::new(2));
+
+    runtime.set_input::<NumberInput>(0, 3);
+
+    // Both computations must start before either continues, which would
+    // deadlock if they were serialized.
+    queries.set_test_hook({
+        let barrier = barrier.clone();
+        move |point, _| {
+            if point == HookPoint::BeforeCompute {
+                barrier.wait();
+            }
+        }
+    });
+
+    struct Double;
+
+    let threads = (0..2)
+        .map(|key| {
+            let runtime = runtime.clone();
+            let queries = queries.clone();
+
+            thread::spawn(move || {
+                let _guard = runtime.lock_readonly();
+                *queries.insert_with::<Double, _, _, _>(&runtime, key, |_, ctx| {
+                    ctx.use_input::<NumberInput>(&0).unwrap() * 2
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 6);
+    }
+}
+
+#[test]
+fn lock_fairness() {
+    use inqui::runtime::LockFairness;
+
+    let runtime = Runtime::<NumbersStorage>::new();
+    let reader = runtime.lock_readonly();
+
+    let writer = thread::spawn({
+        let mut runtime = runtime.clone();
+        move || runtime.set_input::<NumberInput>(0, 1)
+    });
+
+    // Once the writer waits for the first reader, a second reader would
+    // block behind it.
+    while runtime.try_lock_readonly().is_some() {
+        thread::yield_now();
+    }
+
+    let second = thread::spawn({
+        let runtime = runtime.clone();
+        move || {
+            let _guard = runtime.lock_readonly();
+            runtime.get_input::<NumberInput>(&0)
+        }
+    });
+
+    drop(reader);
+    writer.join().unwrap();
+    assert_eq!(second.join().unwrap(), Some(1));
+
+    // The readers do not wait for the writer.
+    let runtime = Runtime::<NumbersStorage>::builder()
+        .lock_fairness(LockFairness::ReaderPreferring)
+        .build();
+    let reader = runtime.lock_readonly();
+
+    let writer = thread::spawn({
+        let mut runtime = runtime.clone();
+        move || runtime.set_input::<NumberInput>(0, 1)
+    });
+
+    let second = thread::spawn({
+        let runtime = runtime.clone();
+        move || {
+            let _guard = runtime.lock_readonly();
+            runtime.get_input::<NumberInput>(&0)
+        }
+    });
+
+    assert_eq!(second.join().unwrap(), None);
+    drop(reader);
+    writer.join().unwrap();
+}
+
+#[test]
+fn max_concurrency() {
+    let mut runtime = Runtime::<NumbersStorage>::new();
+    let queries = Arc::new(QueryCache::<u32>::builder().max_concurrency(2).build());
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let arrived = Arc::new((Mutex::new(0), Condvar::new()));
+
+    runtime.set_input::<NumberInput>(0, 3);
+
+    queries.set_test_hook({
+        let arrived = arrived.clone();
+        move |point, _| {
+            if point == HookPoint::BeforeAcquire {
+                *arrived.0.lock().unwrap() += 1;
+                arrived.1.notify_all();
+            }
+        }
+    });
+
+    struct Slow;
+    struct Nested;
+
+    let threads = (0..8)
+        .map(|key| {
+            let runtime = runtime.clone();
+            let queries = queries.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            let arrived = arrived.clone();
+
+            thread::spawn(move || {
+                let _guard = runtime.lock_readonly();
+                *queries.insert_with::<Slow, _, _, _>(&runtime, key, |key, ctx| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+
+                    // All threads compete for the permits meanwhile.
+                    let (count, all_arrived) = &*arrived;
+                    let mut count = count.lock().unwrap();
+                    while *count < 8 {
+                        count = all_arrived.wait(count).unwrap();
+                    }
+                    drop(count);
+
+                    // Would deadlock if the nested query waited for a permit.
+                    let nested =
+                        queries.insert_with::<Nested, _, _, _>(&runtime, *key, |_, ctx| {
+                            ctx.use_input::<NumberInput>(&0).unwrap()
+                        });
+
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    ctx.use_input::<NumberInput>(&0).unwrap() + *nested
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 6);
+    }
+
+    assert!(max_running.load(Ordering::SeqCst) <= 2);
+}
+
+#[test]
+fn max_concurrency_nested_flight() {
+    let mut runtime = Runtime::<NumbersStorage>::new();
+    let queries = Arc::new(QueryCache::<u32>::builder().max_concurrency(1).build());
+    let (computing, computing_signal) = mpsc::channel();
+    let (acquiring, acquiring_signal) = mpsc::channel();
+    let acquiring = Mutex::new(acquiring);
+
+    runtime.set_input::<NumberInput>(0, 3);
+
+    // The second thread is about to wait for the permit.
+    queries.set_test_hook(move |point, _| {
+        if point == HookPoint::BeforeAcquire && thread::current().name() == Some("second") {
+            acquiring.lock().unwrap().send(()).unwrap();
+        }
+    });
+
+    struct Outer;
+    struct Inner;
+
+    fn inner(queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>) -> i32 {
+        *queries.insert_with::<Inner, _, _, _>(runtime, 2, |_, ctx| {
+            ctx.use_input::<NumberInput>(&0).unwrap()
+        })
+    }
+
+    // Holds the permit while the second thread waits for it, then calls the
+    // same query as the second thread, which must not have registered its
+    // flight yet.
+    let first = thread::spawn({
+        let runtime = runtime.clone();
+        let queries = queries.clone();
+        move || {
+            let _guard = runtime.lock_readonly();
+            *queries.insert_with::<Outer, _, _, _>(&runtime, 1, |_, _| {
+                computing.send(()).unwrap();
+                acquiring_signal.recv().unwrap();
+                inner(&queries, &runtime) + 1
+            })
+        }
+    });
+
+    computing_signal.recv().unwrap();
+
+    let second = thread::Builder::new()
+        .name("second".to_string())
+        .spawn({
+            let runtime = runtime.clone();
+            let queries = queries.clone();
+            move || {
+                let _guard = runtime.lock_readonly();
+                inner(&queries, &runtime)
+            }
+        })
+        .unwrap();
+
+    assert_eq!(first.join().unwrap(), 4);
+    assert_eq!(second.join().unwrap(), 3);
+}
+
+#[derive(Debug)]
+struct Failed;
+
+impl From<inqui::Cycle> for Failed {
+    fn from(_: inqui::Cycle) -> Self {
+        Failed
+    }
+}
+
+#[test]
+fn single_flight() {
+    let mut runtime = Runtime::<NumbersStorage>::new();
+    let queries = Arc::new(QueryCache::<u32>::new());
+    let computed = Arc::new(AtomicUsize::new(0));
+    let (waiting, waiting_signal) = mpsc::channel();
+    let waiting = Mutex::new(waiting);
+    let waiting_signal = Arc::new(Mutex::new(waiting_signal));
+
+    runtime.set_input::<NumberInput>(0, 3);
+
+    queries.set_test_hook(move |point, _| {
+        if point == HookPoint::BeforeWait {
+            waiting.lock().unwrap().send(()).unwrap();
+        }
+    });
+
+    struct Slow;
+
+    let threads = (0..2)
+        .map(|_| {
+            let runtime = runtime.clone();
+            let queries = queries.clone();
+            let computed = computed.clone();
+            let waiting_signal = waiting_signal.clone();
+
+            thread::spawn(move || {
+                let _guard = runtime.lock_readonly();
+
+                // The first computation fails once the other thread waits for
+                // it, the waiting thread tries again.
+                queries
+                    .try_insert_with::<Slow, _, Failed, _, _>(&runtime, 0, |_, ctx| {
+                        let attempt = computed.fetch_add(1, Ordering::SeqCst);
+                        let number = ctx.use_input::<NumberInput>(&0).unwrap();
+                        if attempt == 0 {
+                            waiting_signal.lock().unwrap().recv().unwrap();
+                            Err(Failed)
+                        } else {
+                            Ok(number)
+                        }
+                    })
+                    .ok()
+                    .map(|output| *output)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut outputs = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect::<Vec<_>>();
+    outputs.sort();
+    assert_eq!(outputs, vec![None, Some(3)]);
+    assert_eq!(computed.load(Ordering::SeqCst), 2);
+
+    // Both threads reuse the output computed by one of them.
+    let threads = (1..3)
+        .map(|_| {
+            let runtime = runtime.clone();
+            let queries = queries.clone();
+            let computed = computed.clone();
+            let waiting_signal = waiting_signal.clone();
+
+            thread::spawn(move || {
+                let _guard = runtime.lock_readonly();
+
+                *queries.insert_with::<Slow, _, _, _>(&runtime, 1, |_, ctx| {
+                    waiting_signal.lock().unwrap().recv().unwrap();
+                    computed.fetch_add(1, Ordering::SeqCst);
+                    ctx.use_input::<NumberInput>(&0).unwrap()
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 3);
+    }
+    assert_eq!(computed.load(Ordering::SeqCst), 3);
+}
+
+struct PingPong {
+    queries: QueryCache<u32>,
+    barrier: Barrier,
+    entered: AtomicUsize,
+    cycles: Mutex<Vec<Vec<QueryId>>>,
+}
+
+impl PingPong {
+    // Query 1 calls query 2 and vice versa.
+    fn query(&self, runtime: &Runtime<NumbersStorage>, n: u32) -> Result<Arc<u32>, Cycle> {
+        self.queries
+            .try_insert_with::<PingPong, _, _, _, _>(runtime, n, |n, _| {
+                // Both queries are being computed before either calls the other.
+                if self.entered.fetch_add(1, Ordering::SeqCst) < 2 {
+                    self.barrier.wait();
+                }
+
+                let other = match self.query(runtime, 3 - n) {
+                    Ok(other) => *other,
+                    Err(cycle) => {
+                        self.cycles.lock().unwrap().push(cycle.cycle().to_vec());
+                        0
+                    }
+                };
+
+                Ok(n + other)
+            })
+    }
+}
+
+#[test]
+fn cross_thread_cycle() {
+    let runtime = Runtime::<NumbersStorage>::new();
+    let ping_pong = Arc::new(PingPong {
+        queries: QueryCache::new(),
+        barrier: Barrier::new(2),
+        entered: AtomicUsize::new(0),
+        cycles: Mutex::new(Vec::new()),
+    });
+
+    let threads = (1..3)
+        .map(|n| {
+            let runtime = runtime.clone();
+            let ping_pong = ping_pong.clone();
+
+            thread::spawn(move || {
+                let _guard = runtime.lock_readonly();
+                *ping_pong.query(&runtime, n).unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // The thread that would close the cycle by waiting gets the cycle error
+    // instead of a deadlock.
+    let outputs = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect::<Vec<_>>();
+    assert!(outputs == vec![1, 3] || outputs == vec![3, 2]);
+
+    let cycles = ping_pong.cycles.lock().unwrap();
+    assert_eq!(cycles.len(), 1);
+    assert_eq!(cycles[0].len(), 3);
+    assert_eq!(cycles[0].first(), cycles[0].last());
+}
@@ -0,0 +1,1700 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use inqui::{query::Overrides, runtime::YieldBudget, QueryCache, Runtime};
+
+#[inqui::database]
+pub trait Numbers {
+    #[input(eq)]
+    fn number(&self, key: u32) -> i32;
+}
+
+fn runtime_with_numbers(numbers: &[i32]) -> Runtime<NumbersStorage> {
+    let mut runtime = Runtime::new();
+
+    for (key, number) in numbers.iter().enumerate() {
+        runtime.set_input::<NumberInput>(key as u32, *number);
+    }
+
+    runtime
+}
+
+struct Sum;
+
+fn sum(queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>, n: u32) -> i32 {
+    *queries
+        .cached::<Sum, i32, _>(&n, runtime)
+        .unwrap_or_else(|| {
+            queries.insert_with::<Sum, _, _, _>(runtime, n, |n, ctx| {
+                (0..*n)
+                    .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                    .sum()
+            })
+        })
+}
+
+#[test]
+fn map_cached() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+    static MAPPED: AtomicU32 = AtomicU32::new(0);
+
+    struct Double;
+
+    let double = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.map_cached::<Sum, Double, i32, _, _, _>(&3, runtime, |sum| {
+            MAPPED.fetch_add(1, Ordering::SeqCst);
+            sum * 2
+        })
+    };
+
+    assert!(double(&queries, &runtime).is_none());
+
+    sum(&queries, &runtime, 3);
+    assert_eq!(*double(&queries, &runtime).unwrap(), 12);
+    assert_eq!(*double(&queries, &runtime).unwrap(), 12);
+    assert_eq!(MAPPED.load(Ordering::SeqCst), 1);
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert!(double(&queries, &runtime).is_none());
+
+    sum(&queries, &runtime, 3);
+    assert_eq!(*double(&queries, &runtime).unwrap(), 18);
+    assert_eq!(MAPPED.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn map_cached_markers() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Doubled;
+    struct Tripled;
+
+    // The same closure type for both.
+    let scale = |factor: i32| move |sum: &i32| sum * factor;
+
+    sum(&queries, &runtime, 3);
+    let doubled = queries.map_cached::<Sum, Doubled, i32, _, _, _>(&3, &runtime, scale(2));
+    let tripled = queries.map_cached::<Sum, Tripled, i32, _, _, _>(&3, &runtime, scale(3));
+    assert_eq!(*doubled.unwrap(), 12);
+    assert_eq!(*tripled.unwrap(), 18);
+
+    // The same marker with a different derived type.
+    let rendered =
+        queries.map_cached::<Sum, Doubled, i32, _, _, _>(&3, &runtime, |sum| (sum * 2).to_string());
+    assert_eq!(*rendered.unwrap(), "12");
+}
+
+#[test]
+fn approx_memory_bytes() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    assert!(runtime.approx_memory_bytes() > 0);
+    assert_eq!(queries.approx_memory_bytes(), 0);
+
+    sum(&queries, &runtime, 3);
+    let without_hook = queries.approx_memory_bytes();
+    assert!(without_hook > 0);
+
+    queries.set_heap_size::<Sum, i32>(|_| 1024);
+    assert_eq!(queries.approx_memory_bytes(), without_hook + 1024);
+}
+
+#[test]
+fn valid_at() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    assert_eq!(queries.valid_at::<Sum>(&3), None);
+
+    sum(&queries, &runtime, 3);
+    let computed_at = runtime.current_revision();
+    assert_eq!(queries.valid_at::<Sum>(&3), Some(computed_at));
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert!(runtime.current_revision() > computed_at);
+    assert_eq!(queries.valid_at::<Sum>(&3), Some(computed_at));
+
+    sum(&queries, &runtime, 3);
+    assert_eq!(
+        queries.valid_at::<Sum>(&3),
+        Some(runtime.current_revision())
+    );
+}
+
+#[test]
+fn cached_stale_ok() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    assert!(queries
+        .cached_stale_ok::<Sum, i32, _>(&3, &runtime)
+        .is_none());
+
+    sum(&queries, &runtime, 3);
+    let (output, stale) = queries
+        .cached_stale_ok::<Sum, i32, _>(&3, &runtime)
+        .unwrap();
+    assert_eq!((*output, stale), (6, false));
+
+    runtime.set_input::<NumberInput>(0, 4);
+    let (output, stale) = queries
+        .cached_stale_ok::<Sum, i32, _>(&3, &runtime)
+        .unwrap();
+    assert_eq!((*output, stale), (6, true));
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_none());
+}
+
+#[test]
+fn compute_isolated() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 3);
+
+    let mut overrides = Overrides::new();
+    overrides.set::<NumberInput>(1, 10);
+
+    let isolated = queries.compute_isolated::<Sum, _, _, _>(&runtime, &3, &overrides, |n, ctx| {
+        (0..*n)
+            .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+            .sum::<i32>()
+    });
+
+    assert_eq!(isolated.unwrap(), 14);
+    assert_eq!(*queries.cached::<Sum, i32, _>(&3, &runtime).unwrap(), 6);
+    assert_eq!(runtime.get_input::<NumberInput>(&1), Some(2));
+}
+
+#[test]
+fn compute_isolated_cycle() {
+    use inqui::QueryError;
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+    let overrides = Overrides::new();
+
+    struct Nested;
+
+    // Not cached before, so the cycle is detected only with an allocated id.
+    fn nested(
+        queries: &QueryCache<u32>,
+        runtime: &Runtime<NumbersStorage>,
+        overrides: &Overrides<NumbersStorage>,
+        n: u32,
+    ) -> Result<u32, QueryError> {
+        queries.compute_isolated::<Nested, _, _, _>(runtime, &n, overrides, |n, _| {
+            nested(queries, runtime, overrides, (*n + 1) % 2)
+        })?
+    }
+
+    assert!(matches!(
+        nested(&queries, &runtime, &overrides, 0),
+        Err(QueryError::Cycle(cycle)) if cycle.cycle().len() == 3
+    ));
+
+    struct Deep;
+
+    fn deep(
+        queries: &QueryCache<u32>,
+        runtime: &Runtime<NumbersStorage>,
+        overrides: &Overrides<NumbersStorage>,
+        n: u32,
+    ) -> Result<u32, QueryError> {
+        queries.compute_isolated::<Deep, _, _, _>(runtime, &n, overrides, |n, _| {
+            deep(queries, runtime, overrides, *n + 1)
+        })?
+    }
+
+    runtime.set_max_query_depth(8);
+    assert!(matches!(
+        deep(&queries, &runtime, &overrides, 0),
+        Err(QueryError::DepthExceeded(_))
+    ));
+}
+
+#[test]
+fn recompute_counts() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 3);
+    sum(&queries, &runtime, 3);
+    sum(&queries, &runtime, 2);
+    assert_eq!(queries.recompute_counts(), vec![("query::Sum", 2)]);
+
+    runtime.set_input::<NumberInput>(0, 4);
+    sum(&queries, &runtime, 3);
+    assert_eq!(queries.recompute_counts(), vec![("query::Sum", 3)]);
+}
+
+#[test]
+fn stable_handle() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+    let other = QueryCache::new();
+
+    let handle = queries.stable_handle::<Sum>(&3);
+    assert_eq!(handle, other.stable_handle::<Sum>(&3));
+    assert_ne!(handle, queries.stable_handle::<Sum>(&2));
+    assert_eq!(queries.resolve(handle), None);
+
+    sum(&queries, &runtime, 3);
+    assert_eq!(queries.resolve(handle), queries.id::<Sum>(&3));
+}
+
+#[test]
+fn param_normalizer() {
+    let runtime = runtime_with_numbers(&[1]);
+    let queries = QueryCache::<String>::new();
+
+    struct Length;
+
+    queries.set_param_normalizer::<Length>(|path| path.trim_end_matches('/').to_string());
+
+    let length =
+        queries.insert_with::<Length, _, _, _>(&runtime, "src/".to_string(), |path, _| path.len());
+    assert_eq!(*length, 3);
+
+    assert_eq!(
+        queries.id::<Length>(&"src".to_string()),
+        queries.id::<Length>(&"src//".to_string())
+    );
+    assert_eq!(
+        *queries
+            .cached::<Length, usize, _>(&"src".to_string(), &runtime)
+            .unwrap(),
+        3
+    );
+}
+
+#[test]
+fn should_yield() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::<u32>::new();
+
+    struct Chunk;
+
+    // Processes the numbers until the budget is exhausted.
+    let processed = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        *queries.insert_with::<Chunk, _, _, _>(runtime, 0, |_, ctx| {
+            (0..3u32)
+                .take_while(|key| {
+                    ctx.use_input::<NumberInput>(key);
+                    !ctx.should_yield()
+                })
+                .count()
+        })
+    };
+
+    assert_eq!(processed(&queries, &runtime), 3);
+
+    runtime.set_yield_budget(Some(YieldBudget::Ops(2)));
+    assert_eq!(processed(&queries, &runtime), 2);
+}
+
+#[test]
+fn evict_valid_before() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 2);
+    runtime.set_input::<NumberInput>(2, 4);
+    let reloaded = runtime.current_revision();
+    sum(&queries, &runtime, 3);
+
+    assert_eq!(queries.evict_valid_before(reloaded), 1);
+    assert!(queries.cached::<Sum, i32, _>(&2, &runtime).is_none());
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_some());
+    assert_eq!(queries.stats().evicted, 1);
+    assert!(queries.id::<Sum>(&2).is_none());
+
+    assert_eq!(sum(&queries, &runtime, 2), 3);
+}
+
+#[test]
+fn evict_valid_before_computing() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Outer;
+
+    let outer = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.insert_with::<Outer, _, _, _>(runtime, 3, |n, ctx| {
+            let first = ctx.use_input::<NumberInput>(&0).unwrap();
+            // The stale output of the query itself is not evicted.
+            queries.evict_valid_before(runtime.current_revision());
+            first + sum(queries, runtime, *n)
+        })
+    };
+
+    outer(&queries, &runtime);
+    let id = queries.id::<Outer>(&3);
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(*outer(&queries, &runtime), 13);
+
+    assert_eq!(queries.stats().evicted, 1);
+    assert_eq!(queries.id::<Outer>(&3), id);
+    assert!(queries.cached::<Outer, i32, _>(&3, &runtime).is_some());
+}
+
+#[test]
+fn eviction_policy() {
+    use inqui::query::EvictionPolicy;
+
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::builder()
+        .eviction(EvictionPolicy::Lru(2))
+        .build();
+
+    sum(&queries, &runtime, 1);
+    sum(&queries, &runtime, 2);
+    // Makes the first query recently used.
+    assert!(queries.cached::<Sum, i32, _>(&1, &runtime).is_some());
+    sum(&queries, &runtime, 3);
+
+    assert!(queries.cached::<Sum, i32, _>(&1, &runtime).is_some());
+    assert!(queries.cached::<Sum, i32, _>(&2, &runtime).is_none());
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_some());
+
+    let limit = queries.approx_memory_bytes();
+    let queries = QueryCache::builder()
+        .eviction(EvictionPolicy::Bytes(limit))
+        .build();
+
+    sum(&queries, &runtime, 1);
+    sum(&queries, &runtime, 2);
+    sum(&queries, &runtime, 3);
+    assert!(queries.approx_memory_bytes() <= limit);
+    assert!(queries.cached::<Sum, i32, _>(&1, &runtime).is_none());
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_some());
+}
+
+#[test]
+fn weak_eviction() {
+    use inqui::query::EvictionPolicy;
+
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::builder().eviction(EvictionPolicy::Weak).build();
+
+    let held = queries
+        .cached::<Sum, i32, _>(&1, &runtime)
+        .unwrap_or_else(|| queries.insert_with::<Sum, _, _, _>(&runtime, 1, |_, _| 1));
+    sum(&queries, &runtime, 2);
+    // Evicted when the next query is inserted.
+    sum(&queries, &runtime, 3);
+
+    assert!(queries.cached::<Sum, i32, _>(&1, &runtime).is_some());
+    assert!(queries.cached::<Sum, i32, _>(&2, &runtime).is_none());
+
+    drop(held);
+    sum(&queries, &runtime, 2);
+    assert!(queries.cached::<Sum, i32, _>(&1, &runtime).is_none());
+}
+
+#[test]
+fn param_hasher() {
+    use inqui::query::ParamHasher;
+    use std::collections::hash_map::RandomState;
+
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::builder()
+        .hasher(ParamHasher::Random(RandomState::new()))
+        .build();
+
+    assert_eq!(sum(&queries, &runtime, 3), 6);
+    assert_eq!(sum(&queries, &runtime, 2), 3);
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_some());
+    assert!(queries.id::<Sum>(&2).is_some());
+}
+
+#[test]
+fn cached_strict() {
+    use inqui::query::CacheMiss;
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    assert_eq!(
+        queries
+            .cached_strict::<Sum, i32, _>(&3, &runtime)
+            .unwrap_err(),
+        CacheMiss::Missing
+    );
+
+    sum(&queries, &runtime, 3);
+    assert_eq!(
+        *queries.cached_strict::<Sum, i32, _>(&3, &runtime).unwrap(),
+        6
+    );
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(
+        queries
+            .cached_strict::<Sum, i32, _>(&3, &runtime)
+            .unwrap_err(),
+        CacheMiss::Stale
+    );
+}
+
+#[test]
+fn equal_input() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 3);
+    assert_eq!(runtime.input_version::<NumberInput>(&0), Some(1));
+
+    let before = runtime.current_revision();
+    runtime.set_input::<NumberInput>(0, 1);
+    assert!(runtime.current_revision() > before);
+    assert_eq!(runtime.input_version::<NumberInput>(&0), Some(1));
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_some());
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(runtime.input_version::<NumberInput>(&0), Some(2));
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_none());
+}
+
+#[test]
+fn invalidation_logger() {
+    use inqui::{input::StorageGroup, runtime::StaleDependency};
+    use parking_lot::Mutex;
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let logged = log.clone();
+    queries.set_invalidation_logger(move |invalidation| logged.lock().push(invalidation.clone()));
+
+    sum(&queries, &runtime, 3);
+    let valid_at = runtime.current_revision();
+    runtime.set_input::<NumberInput>(1, 4);
+    sum(&queries, &runtime, 3);
+
+    let log = log.lock();
+    assert_eq!(log.len(), 1);
+    assert_eq!(log[0].query_name, "query::Sum");
+    assert_eq!(log[0].param_debug, "3");
+    assert_eq!(log[0].valid_at, valid_at);
+
+    match log[0].dependency {
+        StaleDependency::Input {
+            input, changed_at, ..
+        } => {
+            assert_eq!(NumbersStorage::input_name(input), Some("number"));
+            assert_eq!(changed_at, runtime.current_revision());
+        }
+        _ => panic!("expected input dependency"),
+    }
+}
+
+#[test]
+fn bound_query() {
+    let runtime = runtime_with_numbers(&[1, 2, 3, 4]);
+    let queries = QueryCache::<(u32, u32)>::new();
+
+    struct RangeSum;
+
+    let from_one = queries.bind::<RangeSum, _>(1);
+
+    for end in 2..4 {
+        from_one.insert_with::<_, i32, _, _>(&runtime, end, |(start, end), ctx| {
+            (*start..*end)
+                .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                .sum()
+        });
+    }
+
+    assert_eq!(*from_one.cached::<_, i32, _>(3, &runtime).unwrap(), 5);
+    assert_eq!(
+        *queries
+            .cached::<RangeSum, i32, _>(&(1, 2), &runtime)
+            .unwrap(),
+        2
+    );
+}
+
+#[test]
+fn mismatched_output_type() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 3);
+
+    assert!(queries.cached::<Sum, i64, _>(&3, &runtime).is_none());
+    assert!(queries
+        .cached_stale_ok::<Sum, String, _>(&3, &runtime)
+        .is_none());
+    assert_eq!(*queries.cached::<Sum, i32, _>(&3, &runtime).unwrap(), 6);
+}
+
+#[test]
+fn cached_downcast() {
+    use inqui::query::DowncastError;
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    assert_eq!(
+        queries.cached_downcast::<Sum, i64, _>(&3, &runtime),
+        Ok(None)
+    );
+
+    sum(&queries, &runtime, 3);
+
+    assert_eq!(
+        queries.cached_downcast::<Sum, i64, _>(&3, &runtime),
+        Err(DowncastError {
+            query_name: "query::Sum",
+            expected: "i64",
+        })
+    );
+    assert_eq!(
+        queries.cached_downcast::<Sum, i32, _>(&3, &runtime),
+        Ok(Some(Arc::new(6)))
+    );
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(
+        queries.cached_downcast::<Sum, i32, _>(&3, &runtime),
+        Ok(None)
+    );
+}
+
+#[test]
+fn rekey() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 3);
+    let id = queries.id::<Sum>(&3);
+
+    assert!(queries.rekey::<Sum>(&3, 4));
+    assert!(!queries.rekey::<Sum>(&3, 5));
+
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_none());
+    assert_eq!(*queries.cached::<Sum, i32, _>(&4, &runtime).unwrap(), 6);
+    assert_eq!(queries.id::<Sum>(&4), id);
+    assert_eq!(queries.resolve(queries.stable_handle::<Sum>(&4)), id);
+    assert_eq!(queries.recompute_counts(), vec![("query::Sum", 1)]);
+}
+
+#[test]
+fn rekey_deterministic_ids() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::builder().deterministic_ids(true).build();
+    let fresh = QueryCache::builder().deterministic_ids(true).build();
+
+    sum(&queries, &runtime, 2);
+    assert!(!queries.rekey::<Sum>(&2, 3));
+    assert_eq!(*queries.cached::<Sum, i32, _>(&2, &runtime).unwrap(), 3);
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_none());
+
+    // The id of the new param is still derived from it.
+    sum(&queries, &runtime, 3);
+    sum(&fresh, &runtime, 3);
+    assert_eq!(queries.id::<Sum>(&3), fresh.id::<Sum>(&3));
+}
+
+#[test]
+fn queries_depending_on() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 1);
+    sum(&queries, &runtime, 3);
+
+    let first = runtime.input_key_index::<NumberInput>(&0).unwrap();
+    let last = runtime.input_key_index::<NumberInput>(&2).unwrap();
+
+    let both = vec![
+        ("query::Sum", queries.id::<Sum>(&1).unwrap()),
+        ("query::Sum", queries.id::<Sum>(&3).unwrap()),
+    ];
+    assert_eq!(queries.queries_depending_on(first), both);
+    assert_eq!(queries.queries_depending_on(last), both[1..]);
+
+    runtime.set_input::<NumberInput>(0, 4);
+    queries.evict_valid_before(runtime.current_revision());
+    assert!(queries.queries_depending_on(first).is_empty());
+}
+
+#[test]
+fn soft_limit() {
+    use inqui::query::{SoftLimit, SoftLimitExceeded};
+    use parking_lot::Mutex;
+
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+    let exceeded = Arc::new(Mutex::new(Vec::new()));
+
+    queries.set_soft_limit(SoftLimit::Entries(100), {
+        let exceeded = exceeded.clone();
+        move |info| exceeded.lock().push(*info)
+    });
+
+    struct Constant;
+
+    let constant = |n: u32| {
+        queries.insert_with::<Constant, _, _, _>(&runtime, n, |n, _| *n);
+    };
+
+    for n in 0..200 {
+        constant(n % 4);
+    }
+    // Recomputing the same queries does not grow the cache.
+    assert!(exceeded.lock().is_empty());
+
+    for n in 0..200 {
+        constant(n);
+    }
+
+    assert_eq!(
+        exceeded.lock().first(),
+        Some(&SoftLimitExceeded {
+            limit: SoftLimit::Entries(100),
+            size: 121,
+            growth: 64,
+        })
+    );
+}
+
+#[test]
+fn insert_lazy() {
+    use inqui::lazy::Lazy;
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+    static EVALUATED: AtomicU32 = AtomicU32::new(0);
+
+    struct Squares;
+
+    let squares = queries.insert_lazy::<Squares, _, _, _, _>(&runtime, 3, |n, ctx| {
+        let numbers = (0..*n)
+            .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+            .collect::<Vec<_>>();
+
+        move || {
+            EVALUATED.fetch_add(1, Ordering::SeqCst);
+            numbers.iter().map(|number| number * number).sum::<i32>()
+        }
+    });
+
+    assert!(!squares.is_evaluated());
+    assert_eq!(EVALUATED.load(Ordering::SeqCst), 0);
+
+    let cached = queries
+        .cached::<Squares, Lazy<i32>, _>(&3, &runtime)
+        .unwrap();
+    assert_eq!(*cached.get(), 14);
+    assert_eq!(*squares.get(), 14);
+    assert_eq!(EVALUATED.load(Ordering::SeqCst), 1);
+
+    runtime.set_input::<NumberInput>(2, 4);
+    assert!(queries
+        .cached::<Squares, Lazy<i32>, _>(&3, &runtime)
+        .is_none());
+}
+
+#[test]
+#[should_panic(expected = "lazy value poisoned")]
+fn lazy_poisoned() {
+    use inqui::lazy::Lazy;
+
+    let lazy = Lazy::<i32>::new(|| panic!("thunk failed"));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *lazy.get()));
+    assert!(result.is_err());
+
+    lazy.get();
+}
+
+#[test]
+fn output_eq() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Parity;
+
+    // Only the parity of the sum matters.
+    queries.set_output_eq::<Parity, i32>(|a, b| a % 2 == b % 2);
+
+    let parity = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.insert_with::<Parity, _, _, _>(runtime, 3, |n, ctx| {
+            (0..*n)
+                .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                .sum::<i32>()
+        })
+    };
+
+    let first = parity(&queries, &runtime);
+    let computed_at = runtime.current_revision();
+
+    runtime.set_input::<NumberInput>(0, 3);
+    let second = parity(&queries, &runtime);
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(queries.changed_at::<Parity>(&3), Some(computed_at));
+    assert_eq!(
+        queries.valid_at::<Parity>(&3),
+        Some(runtime.current_revision())
+    );
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(*parity(&queries, &runtime), 9);
+    assert_eq!(
+        queries.changed_at::<Parity>(&3),
+        Some(runtime.current_revision())
+    );
+}
+
+#[test]
+fn secondary_store() {
+    use inqui::query::{EvictionPolicy, QueryId, SecondaryStore};
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[derive(Default, Clone)]
+    struct Disk(Arc<Mutex<HashMap<QueryId, Vec<u8>>>>);
+
+    impl SecondaryStore for Disk {
+        fn get(&self, id: QueryId) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().get(&id).cloned()
+        }
+
+        fn put(&self, id: QueryId, bytes: Vec<u8>) {
+            self.0.lock().unwrap().insert(id, bytes);
+        }
+    }
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::builder()
+        .eviction(EvictionPolicy::Lru(1))
+        .build();
+    let disk = Disk::default();
+
+    queries.set_secondary_store(disk.clone());
+    queries.set_secondary_codec::<Sum, i32>(
+        |sum| sum.to_le_bytes().to_vec(),
+        |bytes| Some(i32::from_le_bytes(bytes.try_into().ok()?)),
+    );
+
+    sum(&queries, &runtime, 2);
+    sum(&queries, &runtime, 3);
+    assert_eq!(disk.0.lock().unwrap().len(), 2);
+
+    // Restored from the store, evicting the other query from memory.
+    assert_eq!(
+        queries.cached::<Sum, i32, _>(&2, &runtime).as_deref(),
+        Some(&3)
+    );
+    assert_eq!(
+        queries.cached::<Sum, i32, _>(&3, &runtime).as_deref(),
+        Some(&6)
+    );
+
+    sum(&queries, &runtime, 2);
+    runtime.set_input::<NumberInput>(2, 4);
+    assert!(queries.cached::<Sum, i32, _>(&2, &runtime).is_some());
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_none());
+}
+
+#[test]
+fn warm_in_order() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    queries.set_recompute::<Sum, _>(|queries, runtime, n| {
+        sum(queries, runtime, *n);
+    });
+
+    for n in 1..=3 {
+        sum(&queries, &runtime, n);
+    }
+
+    runtime.set_input::<NumberInput>(1, 5);
+
+    let plan = (1..=3)
+        .map(|n| queries.stable_handle::<Sum>(&n))
+        .collect::<Vec<_>>();
+
+    // The first query does not depend on the changed input.
+    assert_eq!(queries.warm_in_order(&runtime, &plan), 2);
+    assert_eq!(
+        queries.cached::<Sum, i32, _>(&3, &runtime).as_deref(),
+        Some(&9)
+    );
+    assert_eq!(queries.warm_in_order(&runtime, &plan), 0);
+}
+
+#[test]
+fn on_invalidate() {
+    use std::sync::Mutex;
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = Arc::new(QueryCache::new());
+    let detached = QueryCache::new();
+    let invalidated = Arc::new(Mutex::new(Vec::new()));
+
+    runtime.attach_cache(&queries);
+
+    let handle = queries.stable_handle::<Sum>(&2);
+    let recorded = invalidated.clone();
+    let subscription = queries.on_invalidate(handle, move |id| recorded.lock().unwrap().push(id));
+    let recorded = invalidated.clone();
+    let _detached = detached.on_invalidate(handle, move |id| recorded.lock().unwrap().push(id));
+
+    sum(&queries, &runtime, 2);
+    sum(&queries, &runtime, 3);
+    sum(&detached, &runtime, 2);
+    let id = queries.id::<Sum>(&2).unwrap();
+    let detached_id = detached.id::<Sum>(&2).unwrap();
+
+    runtime.set_input::<NumberInput>(2, 4);
+    assert!(invalidated.lock().unwrap().is_empty());
+
+    runtime.set_input::<NumberInput>(1, 4);
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(*invalidated.lock().unwrap(), &[id]);
+
+    // Not attached to the runtime.
+    assert!(detached.cached::<Sum, i32, _>(&2, &runtime).is_none());
+    assert_eq!(*invalidated.lock().unwrap(), &[id, detached_id]);
+
+    sum(&queries, &runtime, 2);
+    drop(subscription);
+    runtime.set_input::<NumberInput>(0, 5);
+    assert_eq!(invalidated.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn on_invalidate_external() {
+    use inqui::input::ExternalId;
+    use std::sync::Mutex;
+
+    const CLOCK: ExternalId = ExternalId(1);
+
+    let mut runtime = runtime_with_numbers(&[1]);
+    let queries = Arc::new(QueryCache::<u32>::new());
+    let invalidated = Arc::new(Mutex::new(Vec::new()));
+
+    runtime.attach_cache(&queries);
+
+    struct Timed;
+
+    let handle = queries.stable_handle::<Timed>(&0);
+    let recorded = invalidated.clone();
+    let _subscription = queries.on_invalidate(handle, move |id| recorded.lock().unwrap().push(id));
+
+    queries.insert_with::<Timed, _, _, _>(&runtime, 0, |_, ctx| {
+        ctx.add_external_dependency(CLOCK);
+    });
+    let id = queries.id::<Timed>(&0).unwrap();
+
+    runtime.invalidate_external(ExternalId(2));
+    assert!(invalidated.lock().unwrap().is_empty());
+
+    runtime.invalidate_external(CLOCK);
+    assert_eq!(*invalidated.lock().unwrap(), &[id]);
+}
+
+#[test]
+fn iter_type() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    queries.set_recompute::<Sum, _>(|queries, runtime, n| {
+        sum(queries, runtime, *n);
+    });
+
+    for n in 1..=3 {
+        sum(&queries, &runtime, n);
+    }
+
+    runtime.set_input::<NumberInput>(1, 5);
+
+    let mut valid = queries
+        .iter_type::<Sum, i32, _>(&runtime, false)
+        .map(|(n, output)| (n, *output))
+        .collect::<Vec<_>>();
+    valid.sort();
+    assert_eq!(valid, [(1, 1)]);
+
+    let mut all = queries
+        .iter_type::<Sum, i32, _>(&runtime, true)
+        .map(|(n, output)| (n, *output))
+        .collect::<Vec<_>>();
+    all.sort();
+    assert_eq!(all, [(1, 1), (2, 6), (3, 9)]);
+}
+
+#[test]
+fn deterministic_ids() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let first = QueryCache::builder().deterministic_ids(true).build();
+    let second = QueryCache::builder().deterministic_ids(true).build();
+
+    sum(&first, &runtime, 3);
+    sum(&second, &runtime, 2);
+    sum(&second, &runtime, 3);
+
+    let id = first.id::<Sum>(&3).unwrap();
+    assert_eq!(second.id::<Sum>(&3), Some(id));
+
+    first.clear();
+    assert!(first.id::<Sum>(&3).is_none());
+    assert!(first.cached::<Sum, i32, _>(&3, &runtime).is_none());
+
+    assert_eq!(sum(&first, &runtime, 3), 6);
+    assert_eq!(first.id::<Sum>(&3), Some(id));
+}
+
+#[test]
+fn repeated_reads() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    struct Twice;
+
+    let twice = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.insert_with::<Twice, _, _, _>(runtime, 0, |n, ctx| {
+            let first = ctx.use_input::<NumberInput>(n).unwrap();
+            // Reads of other keys and missing keys are not mixed up.
+            assert_eq!(ctx.use_input::<NumberInput>(&1), Some(2));
+            assert_eq!(ctx.use_input::<NumberInput>(&2), None);
+            assert_eq!(ctx.use_input::<NumberInput>(&2), None);
+            first + ctx.use_input::<NumberInput>(n).unwrap()
+        })
+    };
+
+    assert_eq!(*twice(&queries, &runtime), 2);
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert!(queries.cached::<Twice, i32, _>(&0, &runtime).is_none());
+    assert_eq!(*twice(&queries, &runtime), 8);
+}
+
+static STORAGE_READS: AtomicU32 = AtomicU32::new(0);
+
+// Counts the lookups of the keys in the storage.
+fn counted(key: &u32) -> u32 {
+    STORAGE_READS.fetch_add(1, Ordering::SeqCst);
+    *key
+}
+
+#[inqui::database]
+pub trait Counted {
+    #[input(normalize = counted)]
+    fn counted(&self, key: u32) -> i32;
+}
+
+#[test]
+fn repeated_reads_storage() {
+    let mut runtime = Runtime::<CountedStorage>::new();
+    runtime.set_input::<CountedInput>(0, 1);
+    let queries = QueryCache::new();
+
+    struct Thrice;
+
+    STORAGE_READS.store(0, Ordering::SeqCst);
+    let thrice = queries.insert_with::<Thrice, _, _, _>(&runtime, 0, |n, ctx| {
+        (0..3)
+            .map(|_| ctx.use_input::<CountedInput>(n).unwrap())
+            .sum::<i32>()
+    });
+
+    assert_eq!(*thrice, 3);
+    assert_eq!(STORAGE_READS.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn cache_predicate() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    struct Positive;
+
+    // Negative sums are treated as transient.
+    queries.set_cache_predicate::<Positive, i32>(|sum| *sum > 0);
+
+    let positive = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>, n| {
+        queries.insert_with::<Positive, _, _, _>(runtime, n, |n, ctx| {
+            (0..*n)
+                .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                .sum::<i32>()
+        })
+    };
+
+    assert_eq!(*positive(&queries, &runtime, 2), 3);
+    assert!(queries.cached::<Positive, i32, _>(&2, &runtime).is_some());
+
+    runtime.set_input::<NumberInput>(0, -5);
+    assert_eq!(*positive(&queries, &runtime, 2), -3);
+    assert!(queries.cached::<Positive, i32, _>(&2, &runtime).is_none());
+}
+
+#[test]
+fn to_dot() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 1);
+    sum(&queries, &runtime, 2);
+    runtime.set_input::<NumberInput>(1, 3);
+
+    let dot = queries.to_dot_with_validity(&runtime);
+    assert!(dot.starts_with("digraph queries {\n"));
+    assert!(dot.contains("Sum(1)\", style=filled, fillcolor=palegreen]"));
+    assert!(dot.contains("Sum(2)\", style=filled, fillcolor=salmon]"));
+    assert!(dot.contains("[label=\"number #1\", shape=box]"));
+    assert_eq!(dot.matches(" -> ").count(), 3);
+
+    assert_eq!(queries.to_dot(), queries.to_dot());
+    assert!(!queries.to_dot().contains("fillcolor"));
+}
+
+#[test]
+fn to_dot_called_queries() {
+    let runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    struct Total;
+
+    queries.insert_with::<Total, _, _, _>(&runtime, 0, |_, _| sum(&queries, &runtime, 2));
+
+    let dot = queries.to_dot();
+    assert!(dot.contains("q0 [label=\"query::Sum(2)\"]"));
+    assert!(dot.contains("Total(0)\"]"));
+    assert!(dot.contains("q1 -> q0;"));
+    assert_eq!(dot.matches(" -> ").count(), 3);
+}
+
+#[inqui::param]
+#[derive(Debug)]
+enum Param {
+    Number(u32),
+    Range(u32, u32),
+    All,
+}
+
+#[test]
+fn param() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::<Param>::new();
+
+    struct Number;
+    struct RangeSum;
+
+    let number =
+        queries.insert_with::<Number, _, _, _>(&runtime, 1.into(), |param, ctx| match param {
+            Param::Number(key) => ctx.use_input::<NumberInput>(key).unwrap(),
+            _ => unreachable!(),
+        });
+    assert_eq!(*number, 2);
+
+    let range =
+        queries.insert_with::<RangeSum, _, _, _>(
+            &runtime,
+            (1, 3).into(),
+            |param, ctx| match param {
+                Param::Range(start, end) => (*start..*end)
+                    .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                    .sum::<i32>(),
+                _ => unreachable!(),
+            },
+        );
+    assert_eq!(*range, 5);
+
+    assert!(queries
+        .cached::<RangeSum, i32, _>(&Param::Range(1, 3), &runtime)
+        .is_some());
+    assert_ne!(Param::from(1), Param::All);
+}
+
+#[test]
+fn unchanged_since_last_run() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+    static COMPUTED: AtomicU32 = AtomicU32::new(0);
+
+    struct Cutoff;
+
+    let cutoff = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.insert_with::<Cutoff, _, _, _>(runtime, 0, |_, ctx| {
+            if ctx.unchanged_since_last_run() {
+                return *ctx.previous_output::<i32>().unwrap();
+            }
+
+            COMPUTED.fetch_add(1, Ordering::SeqCst);
+            ctx.use_input::<NumberInput>(&0).unwrap()
+        })
+    };
+
+    assert_eq!(*cutoff(&queries, &runtime), 1);
+    // Recomputing without changes reuses the previous output.
+    assert_eq!(*cutoff(&queries, &runtime), 1);
+    assert_eq!(COMPUTED.load(Ordering::SeqCst), 1);
+
+    // The dependencies are kept.
+    runtime.set_input::<NumberInput>(0, 3);
+    assert!(queries.cached::<Cutoff, i32, _>(&0, &runtime).is_none());
+    assert_eq!(*cutoff(&queries, &runtime), 3);
+    assert_eq!(COMPUTED.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn check_no_drift() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    let clean = |n: &u32, ctx: &inqui::QueryContext<'_, NumbersStorage>| {
+        (0..*n)
+            .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+            .sum::<i32>()
+    };
+
+    assert!(queries
+        .check_no_drift::<Sum, _, _, _>(&3, &runtime, clean)
+        .unwrap());
+
+    sum(&queries, &runtime, 3);
+    assert!(queries
+        .check_no_drift::<Sum, _, _, _>(&3, &runtime, clean)
+        .unwrap());
+
+    runtime.set_input::<NumberInput>(0, 4);
+    sum(&queries, &runtime, 3);
+    // A buggy computation that ignores the first number.
+    assert!(!queries
+        .check_no_drift::<Sum, _, _, _>(&3, &runtime, |n, ctx| {
+            (1..*n)
+                .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                .sum::<i32>()
+        })
+        .unwrap());
+
+    // A check that re-enters the checked query reports the cycle.
+    let nested = std::cell::RefCell::new(None);
+    let outer = queries.check_no_drift::<Sum, _, _, _>(&3, &runtime, |n, ctx| {
+        *nested.borrow_mut() = Some(queries.check_no_drift::<Sum, _, _, _>(n, &runtime, clean));
+        clean(n, ctx)
+    });
+    assert!(outer.unwrap());
+    assert!(matches!(
+        nested.into_inner(),
+        Some(Err(inqui::QueryError::Cycle(_)))
+    ));
+}
+
+#[test]
+fn rollback_dependencies() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Branchy;
+
+    queries.insert_with::<Branchy, _, _, _>(&runtime, 0, |_, ctx| {
+        // Speculative branches that are abandoned.
+        let checkpoint = ctx.checkpoint_dependencies();
+        ctx.use_input::<NumberInput>(&1);
+        ctx.rollback_dependencies(checkpoint);
+
+        let checkpoint = ctx.checkpoint_dependencies();
+        ctx.use_input::<NumberInput>(&2);
+        ctx.rollback_dependencies(checkpoint);
+
+        // Reading the input again records it again.
+        ctx.use_input::<NumberInput>(&2);
+        ctx.use_input::<NumberInput>(&0).unwrap()
+    });
+
+    let cached = |runtime: &Runtime<NumbersStorage>| {
+        queries.cached::<Branchy, i32, _>(&0, runtime).is_some()
+    };
+
+    assert!(cached(&runtime));
+    runtime.set_input::<NumberInput>(1, 5);
+    assert!(cached(&runtime));
+    runtime.set_input::<NumberInput>(2, 5);
+    assert!(!cached(&runtime));
+}
+
+#[test]
+fn rollback_query_dependencies() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Branchy;
+
+    queries.insert_with::<Branchy, _, _, _>(&runtime, 0, |_, ctx| {
+        // The called query stays cached, but is not a dependency.
+        let checkpoint = ctx.checkpoint_dependencies();
+        assert_eq!(sum(&queries, &runtime, 2), 3);
+        ctx.rollback_dependencies(checkpoint);
+
+        ctx.use_input::<NumberInput>(&2).unwrap()
+    });
+
+    let cached = |runtime: &Runtime<NumbersStorage>| {
+        queries.cached::<Branchy, i32, _>(&0, runtime).is_some()
+    };
+
+    assert!(cached(&runtime));
+    runtime.set_input::<NumberInput>(1, 5);
+    assert!(cached(&runtime));
+    assert_eq!(sum(&queries, &runtime, 2), 6);
+    runtime.set_input::<NumberInput>(2, 5);
+    assert!(!cached(&runtime));
+}
+
+#[test]
+fn volatile() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+    static EMITTED: AtomicU32 = AtomicU32::new(0);
+
+    struct Emit;
+
+    queries.set_volatile::<Emit>();
+
+    let emit = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        *queries
+            .cached::<Emit, i32, _>(&0, runtime)
+            .unwrap_or_else(|| {
+                queries.insert_with::<Emit, _, _, _>(runtime, 0, |_, ctx| {
+                    EMITTED.fetch_add(1, Ordering::SeqCst);
+                    ctx.use_input::<NumberInput>(&0).unwrap()
+                })
+            })
+    };
+
+    assert_eq!(emit(&queries, &runtime), 1);
+    assert_eq!(emit(&queries, &runtime), 1);
+    assert_eq!(EMITTED.load(Ordering::SeqCst), 2);
+
+    // The dependencies are still recorded.
+    let index = runtime.input_key_index::<NumberInput>(&0).unwrap();
+    assert_eq!(queries.queries_depending_on(index).len(), 1);
+
+    runtime.set_input::<NumberInput>(0, 3);
+    assert_eq!(emit(&queries, &runtime), 3);
+}
+
+#[test]
+fn configure() {
+    use inqui::query::QueryConfig;
+
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    struct Configured;
+
+    queries.configure::<Configured, i32>(
+        QueryConfig::new()
+            .output_eq(|a, b| a % 2 == b % 2)
+            .cache_predicate(|sum| *sum > 0),
+    );
+
+    let configured = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.insert_with::<Configured, _, _, _>(runtime, 2, |n, ctx| {
+            (0..*n)
+                .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                .sum::<i32>()
+        })
+    };
+
+    let first = configured(&queries, &runtime);
+    runtime.set_input::<NumberInput>(0, 3);
+    assert!(Arc::ptr_eq(&first, &configured(&queries, &runtime)));
+
+    runtime.set_input::<NumberInput>(0, -4);
+    assert_eq!(*configured(&queries, &runtime), -2);
+    assert!(queries.cached::<Configured, i32, _>(&2, &runtime).is_none());
+}
+
+#[test]
+fn on_dependency() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Observed;
+
+    let recorded = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+    let sum = queries.insert_with::<Observed, _, _, _>(&runtime, 3u32, |n, ctx| {
+        let recorded = recorded.clone();
+        ctx.on_dependency(move |_, _, name| recorded.lock().push(name));
+
+        // Repeated reads are not reported again.
+        (0..*n)
+            .chain(0..*n)
+            .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+            .sum::<i32>()
+    });
+
+    assert_eq!(*sum, 12);
+    assert_eq!(*recorded.lock(), vec!["number"; 3]);
+}
+
+#[test]
+fn unused_inputs() {
+    let runtime = runtime_with_numbers(&[1, 2, 3, 4]);
+    let queries = QueryCache::new();
+
+    struct Prefix;
+
+    assert_eq!(queries.unused_inputs(&runtime).len(), 4);
+
+    queries.insert_with::<Prefix, _, _, _>(&runtime, 2u32, |n, ctx| {
+        (0..*n)
+            .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+            .sum::<i32>()
+    });
+
+    let expected = [2, 3]
+        .iter()
+        .map(|key| runtime.input_key_index::<NumberInput>(key).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(queries.unused_inputs(&runtime), expected);
+}
+
+#[test]
+fn remove_input_result() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = Arc::new(QueryCache::new());
+    runtime.attach_cache(&queries);
+
+    struct Prefix;
+
+    for n in 1..=3u32 {
+        queries.insert_with::<Prefix, _, _, _>(&runtime, n, |n, ctx| {
+            (0..*n)
+                .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+                .sum::<i32>()
+        });
+    }
+
+    let removed = runtime.remove_input::<NumberInput>(&1);
+    assert_eq!(removed.previous, Some(2));
+    assert_eq!(removed.dependents_invalidated, 2);
+
+    assert!(queries.cached::<Prefix, i32, _>(&1, &runtime).is_some());
+    assert!(queries.cached::<Prefix, i32, _>(&2, &runtime).is_none());
+    assert!(queries.cached::<Prefix, i32, _>(&3, &runtime).is_none());
+
+    let removed = runtime.remove_input::<NumberInput>(&1);
+    assert_eq!(removed.previous, None);
+    assert_eq!(removed.dependents_invalidated, 0);
+}
+
+#[test]
+fn use_query() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    struct Number;
+    struct Total;
+
+    let number = queries.insert_with::<Number, _, _, _>(&runtime, 1u32, |key, ctx| {
+        ctx.use_input::<NumberInput>(key).unwrap()
+    });
+    let number_id = queries.id::<Number>(&1).unwrap();
+
+    // The output was obtained outside of the computation, so the query must be
+    // recorded explicitly.
+    queries.insert_with::<Total, _, _, _>(&runtime, 0, |_, ctx| {
+        ctx.use_query(number_id);
+        *number * 10
+    });
+
+    runtime.set_input::<NumberInput>(0, 3);
+    assert!(queries.cached::<Total, i32, _>(&0, &runtime).is_some());
+
+    runtime.set_input::<NumberInput>(1, 3);
+    assert!(queries.cached::<Total, i32, _>(&0, &runtime).is_none());
+}
+
+#[test]
+fn with_capacity() {
+    let runtime = runtime_with_numbers(&[1, 2, 3, 4]);
+    let queries = QueryCache::with_capacity(2);
+
+    for n in 1..=4 {
+        sum(&queries, &runtime, n);
+    }
+
+    let stats = queries.stats();
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.evicted, 2);
+
+    // The ids of the evicted queries are dropped too.
+    assert!(queries.id::<Sum>(&1).is_none());
+    assert!(queries.id::<Sum>(&4).is_some());
+
+    assert_eq!(sum(&queries, &runtime, 1), 1);
+    assert_eq!(queries.stats().evicted, 3);
+}
+
+#[test]
+fn stats() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    sum(&queries, &runtime, 2);
+    sum(&queries, &runtime, 2);
+    sum(&queries, &runtime, 3);
+    runtime.set_input::<NumberInput>(0, 4);
+    sum(&queries, &runtime, 2);
+
+    let stats = queries.stats();
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses, 3);
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.recomputations, 3);
+    assert_eq!(stats.evicted, 0);
+}
+
+#[test]
+fn backdating() {
+    let mut runtime = runtime_with_numbers(&[1]);
+    let queries = QueryCache::<u32>::new();
+
+    struct Parity;
+    struct Describe;
+
+    let parity = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries
+            .cached::<Parity, i32, _>(&0, runtime)
+            .unwrap_or_else(|| {
+                queries
+                    .try_insert_with_backdating::<Parity, _, inqui::Cycle, _, _>(
+                        runtime,
+                        0,
+                        |key, ctx| Ok(ctx.use_input::<NumberInput>(key).unwrap() % 2),
+                    )
+                    .unwrap()
+            })
+    };
+
+    // Depends on the input only through the parity.
+    queries.insert_with::<Describe, _, _, _>(&runtime, 0, |_, _| {
+        if *parity(&queries, &runtime) == 0 {
+            "even"
+        } else {
+            "odd"
+        }
+    });
+
+    runtime.set_input::<NumberInput>(0, 3);
+    assert!(queries.cached::<Describe, &str, _>(&0, &runtime).is_none());
+
+    // Once the parity is recomputed to the same output, it does not
+    // invalidate the description.
+    parity(&queries, &runtime);
+    assert!(queries.cached::<Describe, &str, _>(&0, &runtime).is_some());
+
+    runtime.set_input::<NumberInput>(0, 4);
+    parity(&queries, &runtime);
+    assert!(queries.cached::<Describe, &str, _>(&0, &runtime).is_none());
+}
+
+#[test]
+fn set_input_previous() {
+    let mut runtime = runtime_with_numbers(&[1]);
+
+    assert_eq!(runtime.set_input::<NumberInput>(0, 5), Some(1));
+    assert_eq!(runtime.set_input::<NumberInput>(0, 5), Some(5));
+    assert_eq!(runtime.set_input::<NumberInput>(1, 2), None);
+}
+
+#[test]
+fn set_input_if_changed() {
+    let mut runtime = runtime_with_numbers(&[1, 2]);
+    let queries = QueryCache::new();
+
+    assert_eq!(sum(&queries, &runtime, 2), 3);
+    let rev = runtime.current_revision();
+
+    assert!(!runtime.set_input_if_changed::<NumberInput>(0, 1));
+    assert_eq!(runtime.current_revision(), rev);
+    assert!(queries.cached::<Sum, i32, _>(&2, &runtime).is_some());
+
+    assert!(runtime.set_input_if_changed::<NumberInput>(0, 4));
+    assert!(runtime.current_revision() > rev);
+    assert_eq!(sum(&queries, &runtime, 2), 6);
+}
+
+#[test]
+fn transaction_many_inputs() {
+    let mut runtime = runtime_with_numbers(&[]);
+    let queries = QueryCache::new();
+    let before = runtime.current_revision();
+
+    runtime.transaction(|tx| {
+        for key in 0..10 {
+            tx.set::<NumberInput>(key, key as i32);
+        }
+    });
+
+    assert_eq!(runtime.current_revision().as_raw(), before.as_raw() + 1);
+    assert_eq!(sum(&queries, &runtime, 10), 45);
+}
+
+#[test]
+fn snapshot() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    let snapshot = runtime.snapshot();
+    let revision = snapshot.revision();
+    assert_eq!(snapshot.get_input::<NumberInput>(&1), Some(2));
+    assert_eq!(
+        snapshot.with_storage::<NumberInput, _, _>(|storage| storage.version(&1)),
+        Some(1)
+    );
+    assert_eq!(sum(&queries, snapshot.runtime(), 3), 6);
+    assert_eq!(snapshot.revision(), revision);
+    drop(snapshot);
+
+    runtime.set_input::<NumberInput>(0, 4);
+    assert_eq!(sum(&queries, &runtime, 3), 9);
+}
+
+struct PrefixSum;
+
+impl inqui::Query<NumbersStorage> for PrefixSum {
+    type Param = u32;
+    type Output = i32;
+
+    fn run(&self, n: &u32, ctx: &inqui::QueryContext<'_, NumbersStorage>) -> i32 {
+        (0..*n)
+            .map(|key| ctx.use_input::<NumberInput>(&key).unwrap())
+            .sum()
+    }
+}
+
+#[test]
+fn execute() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    assert_eq!(*queries.execute(&PrefixSum, &runtime, 3), 6);
+    assert_eq!(
+        *queries.cached::<PrefixSum, i32, _>(&3, &runtime).unwrap(),
+        6
+    );
+
+    runtime.set_input::<NumberInput>(2, 5);
+    assert_eq!(*queries.execute(&PrefixSum, &runtime, 3), 8);
+}
+
+#[test]
+fn invalidate() {
+    let runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    for n in 1..=3 {
+        sum(&queries, &runtime, n);
+    }
+
+    let id = queries.id::<Sum>(&3);
+    assert!(queries.invalidate::<Sum>(&3));
+    assert!(!queries.invalidate::<Sum>(&3));
+    assert_eq!(queries.id::<Sum>(&3), id);
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_none());
+    assert!(queries.cached::<Sum, i32, _>(&2, &runtime).is_some());
+
+    assert_eq!(sum(&queries, &runtime, 3), 6);
+    assert!(queries.cached::<Sum, i32, _>(&3, &runtime).is_some());
+
+    assert_eq!(queries.invalidate_all::<Sum>(), 3);
+    assert!(queries.cached::<Sum, i32, _>(&1, &runtime).is_none());
+}
+
+#[test]
+fn observer() {
+    use inqui::query::{CacheObserver, EvictionPolicy, QueryId};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<(&'static str, QueryId)>>);
+
+    impl CacheObserver for Events {
+        fn on_hit(&self, id: QueryId) {
+            self.0.lock().unwrap().push(("hit", id));
+        }
+
+        fn on_miss(&self, id: QueryId) {
+            self.0.lock().unwrap().push(("miss", id));
+        }
+
+        fn on_insert(&self, id: QueryId) {
+            self.0.lock().unwrap().push(("insert", id));
+        }
+
+        fn on_evict(&self, id: QueryId) {
+            self.0.lock().unwrap().push(("evict", id));
+        }
+    }
+
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::builder()
+        .eviction(EvictionPolicy::Lru(1))
+        .build();
+    let events = Arc::new(Events::default());
+
+    queries.set_observer(events.clone());
+
+    sum(&queries, &runtime, 1);
+    let first = queries.id::<Sum>(&1).unwrap();
+    sum(&queries, &runtime, 1);
+    sum(&queries, &runtime, 2);
+    let second = queries.id::<Sum>(&2).unwrap();
+
+    runtime.set_input::<NumberInput>(1, 5);
+    assert_eq!(sum(&queries, &runtime, 2), 6);
+
+    assert_eq!(
+        *events.0.lock().unwrap(),
+        vec![
+            ("insert", first),
+            ("hit", first),
+            ("insert", second),
+            ("evict", first),
+            ("miss", second),
+            ("insert", second),
+        ]
+    );
+}
+
+#[test]
+fn saved_query_stack_dependencies() {
+    let mut runtime = runtime_with_numbers(&[1, 2, 3]);
+    let queries = QueryCache::new();
+
+    struct Interpreted;
+
+    let interpret = |queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>| {
+        queries.insert_with::<Interpreted, _, _, _>(runtime, 0, |_, _| {
+            // The foreign call computes a query the interpreted one depends on.
+            let snapshot = runtime.save_query_stack();
+            let inner = sum(queries, runtime, 2);
+            runtime.restore_query_stack(snapshot);
+            inner * 10
+        })
+    };
+
+    assert_eq!(*interpret(&queries, &runtime), 30);
+    assert!(queries
+        .cached::<Interpreted, i32, _>(&0, &runtime)
+        .is_some());
+
+    runtime.set_input::<NumberInput>(1, 5);
+    assert!(queries
+        .cached::<Interpreted, i32, _>(&0, &runtime)
+        .is_none());
+    assert_eq!(*interpret(&queries, &runtime), 60);
+}
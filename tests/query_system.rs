@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[inqui::database(mock)]
+pub trait Text {
+    fn text(&self, key: ()) -> String;
+    fn suffix(&self, key: u32) -> Option<String>;
+}
+
+static RUNS: AtomicU32 = AtomicU32::new(0);
+
+inqui::query_system! {
+    #[database(Text)]
+    pub struct System;
+
+    #[query]
+    fn length(db: &dyn Text, key: ()) -> usize {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+        db.text(key).len()
+    }
+
+    #[query]
+    fn with_suffix(db: &dyn Text, key: (), suffix: u32) -> String {
+        format!("{}{}", db.text(key), db.suffix(suffix).unwrap_or_default())
+    }
+}
+
+#[test]
+fn generated_system() {
+    let mut system = System::new();
+
+    system.set_input::<TextInput>((), "hello".to_string());
+    system.set_input::<SuffixInput>(1, "!".to_string());
+
+    assert_eq!(*system.length(()), 5);
+    assert_eq!(*system.length(()), 5);
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+
+    assert_eq!(*system.with_suffix((), 1), "hello!");
+    assert_eq!(*system.with_suffix((), 2), "hello");
+
+    system.set_input::<TextInput>((), "hi".to_string());
+
+    assert_eq!(*system.length(()), 2);
+    assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+    assert_eq!(*system.with_suffix((), 1), "hi!");
+}
+
+#[test]
+fn query_registry() {
+    let names = System::query_registry()
+        .iter()
+        .map(|info| info.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["length", "with_suffix"]);
+
+    let with_suffix = System::query_registry()[1];
+    assert_eq!(with_suffix.params.len(), 2);
+    assert_eq!(with_suffix.params[1], "u32");
+    assert_eq!(with_suffix.output, "String");
+}
+
+#[test]
+fn mock_database() {
+    let mock = TextMock::new()
+        .with_text((), "hello".to_string())
+        .with_suffix(1, "?".to_string());
+
+    // Not calling `length`, which counts the runs of the system test.
+    assert_eq!(with_suffix(&mock, (), 1), "hello?");
+    assert_eq!(with_suffix(&mock, (), 2), "hello");
+}
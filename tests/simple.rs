@@ -1,6 +1,8 @@
+#![allow(clippy::iter_overeager_cloned)]
+
 mod common;
 
-use common::{AnySystem, Database, Log, Param, RealSystem};
+use common::{AnySystem, Database, Log, Param, RealSystem, System};
 
 fn sum_abc(db: &dyn Database, _: &(), _: &AnySystem<'_, ()>) -> i32 {
     db.a() + db.b() + db.c()
@@ -102,3 +104,42 @@ fn simple_parametrized_cache_invalidation() {
     assert!(log_book.iter().cloned().filter(Log::is_cache_hit).count() == 0);
     assert!(log_book.iter().cloned().filter(Log::is_query_start).count() == 2);
 }
+
+fn double_sum(_: &dyn Database, _: &(), system: &AnySystem<'_, ()>) -> i32 {
+    2 * *system.query((), sum_abc)
+}
+
+fn triple_double_sum(_: &dyn Database, _: &(), system: &AnySystem<'_, ()>) -> i32 {
+    3 * *system.query((), double_sum)
+}
+
+#[test]
+fn nested_cache_invalidation() {
+    let mut system = RealSystem::default();
+
+    system.set_a(1);
+    system.set_b(2);
+    system.set_c(3);
+
+    assert_eq!(*system.query((), triple_double_sum), 36);
+
+    // Not used by any of the queries.
+    system.set_parametrized(Param::Foo, 1);
+    assert_eq!(*system.query((), triple_double_sum), 36);
+
+    let log_book = system.log_book();
+    assert_eq!(
+        log_book.iter().filter(|log| log.is_query_start()).count(),
+        3
+    );
+
+    // Used only by the innermost query.
+    system.set_a(4);
+    assert_eq!(*system.query((), triple_double_sum), 54);
+
+    let log_book = system.log_book();
+    assert_eq!(
+        log_book.iter().filter(|log| log.is_query_start()).count(),
+        6
+    );
+}
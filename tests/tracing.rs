@@ -0,0 +1,132 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use inqui::{QueryCache, Runtime};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+#[inqui::database]
+pub trait Numbers {
+    fn number(&self, key: u32) -> i32;
+}
+
+// Records the query spans with their parents and the messages of the events.
+#[derive(Default)]
+struct Recorded {
+    next_id: AtomicU64,
+    spans: Mutex<Vec<(u64, Option<u64>, String)>>,
+    stack: Mutex<Vec<u64>>,
+    events: Mutex<Vec<String>>,
+}
+
+struct Recorder(Arc<Recorded>);
+
+#[derive(Default)]
+struct Fields {
+    query: String,
+    message: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "query" {
+            self.query = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        }
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+        let id = self.0.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let parent = self.0.stack.lock().unwrap().last().copied();
+
+        let mut fields = Fields::default();
+        span.record(&mut fields);
+        self.0
+            .spans
+            .lock()
+            .unwrap()
+            .push((id, parent, fields.query));
+
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.0.events.lock().unwrap().push(fields.message);
+    }
+
+    fn enter(&self, span: &span::Id) {
+        self.0.stack.lock().unwrap().push(span.into_u64());
+    }
+
+    fn exit(&self, _: &span::Id) {
+        self.0.stack.lock().unwrap().pop();
+    }
+}
+
+struct Sum;
+
+fn sum(queries: &QueryCache<u32>, runtime: &Runtime<NumbersStorage>, n: u32) -> i32 {
+    *queries
+        .cached::<Sum, i32, _>(&n, runtime)
+        .unwrap_or_else(|| {
+            queries.insert_with::<Sum, _, _, _>(runtime, n, |n, ctx| {
+                if *n == 0 {
+                    0
+                } else {
+                    ctx.use_input::<NumberInput>(&(*n - 1)).unwrap() + sum(queries, runtime, *n - 1)
+                }
+            })
+        })
+}
+
+#[test]
+fn query_spans() {
+    let recorded = Arc::new(Recorded::default());
+    let mut runtime = Runtime::<NumbersStorage>::new();
+    let queries = QueryCache::new();
+
+    tracing::subscriber::with_default(Recorder(recorded.clone()), || {
+        runtime.set_input::<NumberInput>(0, 1);
+        runtime.set_input::<NumberInput>(1, 2);
+
+        assert_eq!(sum(&queries, &runtime, 2), 3);
+        assert_eq!(sum(&queries, &runtime, 2), 3);
+
+        runtime.remove_input::<NumberInput>(&1);
+    });
+
+    // Each query is nested in the one that called it.
+    let spans = recorded.spans.lock().unwrap();
+    assert_eq!(spans.len(), 3);
+    assert_eq!(spans[0].1, None);
+    assert_eq!(spans[1].1, Some(spans[0].0));
+    assert_eq!(spans[2].1, Some(spans[1].0));
+    assert!(spans.iter().all(|(_, _, query)| query.ends_with("Sum")));
+
+    let events = recorded.events.lock().unwrap();
+    assert_eq!(
+        *events,
+        vec!["input set", "input set", "cache hit", "input removed"]
+    );
+}
@@ -0,0 +1,8 @@
+#[test]
+fn ui() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/database-generic.rs");
+    cases.compile_fail("tests/ui/database-lifetime.rs");
+    cases.compile_fail("tests/ui/database-unknown-option.rs");
+    cases.compile_fail("tests/ui/foreign-input.rs");
+}
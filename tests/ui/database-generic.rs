@@ -0,0 +1,24 @@
+use inqui::{QueryCache, Runtime};
+
+#[inqui::database]
+pub trait Store<T>
+where
+    T: Clone + PartialEq + Send + Sync + 'static,
+{
+    fn item(&self, key: u32) -> T;
+    fn fallback(&self) -> Option<T>;
+}
+
+struct First;
+
+fn main() {
+    let mut runtime = Runtime::<StoreStorage<String>>::new();
+    let queries = QueryCache::<u32>::new();
+
+    runtime.set_input::<ItemInput<String>>(1, "one".to_string());
+
+    let first = queries.insert_with::<First, _, _, _>(&runtime, 1, |key, ctx| {
+        ctx.fallback().unwrap_or_else(|| ctx.item(*key))
+    });
+    assert_eq!(*first, "one");
+}
@@ -0,0 +1,6 @@
+#[inqui::database]
+pub trait Borrowed<'a> {
+    fn name(&self) -> &'a str;
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[inqui::database(mock, storage = NamesStorage, cache)]
+pub trait Names {
+    fn name(&self, id: u32) -> String;
+}
+
+fn main() {}
@@ -0,0 +1,22 @@
+use inqui::{QueryCache, Runtime};
+
+#[inqui::database]
+pub trait Foo {
+    fn a(&self) -> u32;
+}
+
+#[inqui::database]
+pub trait Bar {
+    fn x(&self) -> u32;
+}
+
+struct Query;
+
+fn main() {
+    let runtime = Runtime::<BarStorage>::new();
+    let queries = QueryCache::<()>::new();
+
+    queries.insert_with::<Query, _, _, _>(&runtime, (), |_, ctx| {
+        ctx.use_input::<AInput>(&()).unwrap()
+    });
+}